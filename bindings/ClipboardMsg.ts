@@ -0,0 +1,3 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+export type ClipboardMsg = { "Text": { size: number, } } | { "Html": { html_size: number, text_size: number, } } | { "Image": { width: number, height: number, size: number, } } | { "Rejected": { reason: string, } };
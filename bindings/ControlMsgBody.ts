@@ -1,4 +1,18 @@
 // This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { CreateDisplayMsg } from "./CreateDisplayMsg";
+import type { DisplayIdMsg } from "./DisplayIdMsg";
+import type { FsChmodMsg } from "./FsChmodMsg";
+import type { FsPathMsg } from "./FsPathMsg";
+import type { FsRemoveMsg } from "./FsRemoveMsg";
+import type { FsRenameMsg } from "./FsRenameMsg";
+import type { GetRecordingMsg } from "./GetRecordingMsg";
+import type { KeyboardMsg } from "./KeyboardMsg";
+import type { KillSessionMsg } from "./KillSessionMsg";
+import type { RenameSessionMsg } from "./RenameSessionMsg";
+import type { ScreenDumpMsg } from "./ScreenDumpMsg";
 import type { StartVideoMsg } from "./StartVideoMsg";
+import type { UpdateDisplayMsg } from "./UpdateDisplayMsg";
+import type { UpdateVideoMsg } from "./UpdateVideoMsg";
+import type { VideoIdMsg } from "./VideoIdMsg";
 
-export type ControlMsgBody = "Empty" | { "StartVideo": StartVideoMsg };
+export type ControlMsgBody = "Empty" | { "StartVideo": StartVideoMsg } | { "StopVideo": VideoIdMsg } | { "PauseVideo": VideoIdMsg } | { "ResumeVideo": VideoIdMsg } | { "UpdateVideo": UpdateVideoMsg } | "ListRecordings" | { "GetRecording": GetRecordingMsg } | { "ScreenDump": ScreenDumpMsg } | "ListSessions" | { "RenameSession": RenameSessionMsg } | { "KillSession": KillSessionMsg } | { "ReadDir": FsPathMsg } | { "Stat": FsPathMsg } | { "MakeDir": FsPathMsg } | { "Rename": FsRenameMsg } | { "Remove": FsRemoveMsg } | { "Chmod": FsChmodMsg } | { "RealPath": FsPathMsg } | { "Keyboard": KeyboardMsg } | { "CreateDisplay": CreateDisplayMsg } | { "UpdateDisplay": UpdateDisplayMsg } | { "EnableDisplay": DisplayIdMsg } | { "DisableDisplay": DisplayIdMsg } | { "RemoveDisplay": DisplayIdMsg } | "ListDisplays";
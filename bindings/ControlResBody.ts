@@ -1,4 +1,13 @@
 // This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { DisplayEntry } from "./DisplayEntry";
 import type { ErrorMsg } from "./ErrorMsg";
+import type { FsEntry } from "./FsEntry";
+import type { FsPathMsg } from "./FsPathMsg";
+import type { KeyboardInfo } from "./KeyboardInfo";
+import type { RecordingChunk } from "./RecordingChunk";
+import type { RecordingInfo } from "./RecordingInfo";
+import type { ScreenDump } from "./ScreenDump";
+import type { SessionInfo } from "./SessionInfo";
+import type { VideoStream } from "./VideoStream";
 
-export type ControlResBody = "Empty" | { "Error": ErrorMsg };
+export type ControlResBody = "Empty" | { "Error": ErrorMsg } | { "Video": VideoStream } | { "Recordings": Array<RecordingInfo> } | { "Recording": RecordingChunk } | { "Screen": ScreenDump } | { "Sessions": Array<SessionInfo> } | { "DirEntries": Array<FsEntry> } | { "Stat": FsEntry } | { "Path": FsPathMsg } | { "Keyboard": KeyboardInfo } | { "Display": DisplayEntry } | { "Displays": Array<DisplayEntry> };
@@ -0,0 +1,4 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { Compression } from "./Compression";
+
+export type DataChannelAckMsg = { compression: Compression | null, };
@@ -1,3 +1,4 @@
 // This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { Compression } from "./Compression";
 
-export type DataChannelSettingsMsg = { variant: string, session_id: string | null, };
+export type DataChannelSettingsMsg = { variant: string, session_id: string | null, compression: Array<Compression> | null, target: string | null, bind: string | null, };
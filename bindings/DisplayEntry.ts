@@ -0,0 +1,4 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { DisplayModeEntry } from "./DisplayModeEntry";
+
+export type DisplayEntry = { id: number, name: string | null, enabled: boolean, modes: Array<DisplayModeEntry>, };
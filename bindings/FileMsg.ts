@@ -0,0 +1,3 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+export type FileMsg = { "Download": { path: string, offset: number, } } | { "Upload": { path: string, size: number, } } | { "Start": { size: number, offset: number, } } | { "Done": { sha256: string, } } | { "Progress": { bytes: number, } } | "Verified" | { "Error": { message: string, } };
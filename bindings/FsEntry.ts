@@ -0,0 +1,4 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { FsKind } from "./FsKind";
+
+export type FsEntry = { name: string, path: string, kind: FsKind, size: number, mode: number, modified: string, };
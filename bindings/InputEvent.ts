@@ -0,0 +1,4 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { MouseButton } from "./MouseButton";
+
+export type InputEvent = { "PointerMove": { x: number, y: number, } } | { "PointerMoveBy": { dx: number, dy: number, } } | { "Button": { button: MouseButton, down: boolean, } } | { "Wheel": { dx: number, dy: number, } } | { "Key": { scancode: number, down: boolean, } } | { "Text": { text: string, } };
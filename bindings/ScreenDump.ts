@@ -0,0 +1,3 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+export type ScreenDump = { rows: number, cols: number, cursor_row: number, cursor_col: number, alternate_screen: boolean, title: string, contents: string, };
@@ -0,0 +1,4 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { Participant } from "./Participant";
+
+export type SessionEvent = { "kind": "welcome", me: string, participants: Array<Participant>, } | { "kind": "joined" } & Participant | { "kind": "left" } & Participant | { "kind": "role" } & Participant;
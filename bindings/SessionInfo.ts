@@ -0,0 +1,3 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+export type SessionInfo = { id: string, name: string | null, variant: string, shell: string | null, created: string, clients: number, last_activity: string, rows: number | null, cols: number | null, };
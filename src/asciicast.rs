@@ -0,0 +1,194 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use portable_pty::PtySize;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use ts_rs::TS;
+
+const EXTENSION: &str = "cast";
+const MAX_CHUNK: u32 = 32 * 1024;
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize, TS)]
+pub struct RecordingInfo {
+    pub name: String,
+    #[ts(type = "number")]
+    pub size: u64,
+    pub modified: String,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize, TS)]
+pub struct GetRecordingMsg {
+    pub name: String,
+    #[ts(type = "number | null")]
+    pub offset: Option<u64>,
+    pub length: Option<u32>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize, TS)]
+pub struct RecordingChunk {
+    pub name: String,
+    #[ts(type = "number")]
+    pub offset: u64,
+    pub data: String,
+    pub eof: bool,
+}
+
+type Event = (f64, &'static str, String);
+
+struct Queue {
+    events: mpsc::Sender<Event>,
+    start: Instant,
+}
+
+// Writes a shell session in asciicast v2 format, one json event per line.
+// Events are queued without bound and written by a thread of their own, so
+// a slow disk never drops output or stalls the pty
+#[derive(Clone)]
+pub struct Recorder {
+    queue: Arc<Mutex<Queue>>,
+    capture_input: bool,
+}
+
+// Flushes whenever the queue runs empty, ends once every Recorder is gone
+fn write_events(mut file: BufWriter<File>, events: mpsc::Receiver<Event>) {
+    while let Ok(event) = events.recv() {
+        let res = std::iter::once(event)
+            .chain(events.try_iter())
+            .try_for_each(|(time, code, data)| writeln!(file, "{}", json!([time, code, data])))
+            .and_then(|_| file.flush());
+        if let Err(e) = res {
+            log::error!("Failed to write recording event: {}", e);
+        }
+    }
+}
+
+impl Recorder {
+    pub fn create(
+        dir: &Path,
+        session_id: &str,
+        size: PtySize,
+        capture_input: bool,
+    ) -> Result<Self> {
+        fs::create_dir_all(dir)?;
+        let now = Utc::now();
+        let path = dir.join(format!(
+            "{}-{}.{}",
+            session_id,
+            now.format("%Y%m%dT%H%M%S"),
+            EXTENSION
+        ));
+        let mut file = BufWriter::new(File::create(&path)?);
+
+        let header = json!({
+            "version": 2,
+            "width": size.cols,
+            "height": size.rows,
+            "timestamp": now.timestamp(),
+            "title": session_id,
+            "env": { "TERM": "xterm-256color" },
+        });
+        writeln!(file, "{}", header)?;
+        file.flush()?;
+
+        log::info!("Recording session {} to {}", session_id, path.display());
+        let (events, events_rx) = mpsc::channel();
+        thread::spawn(move || write_events(file, events_rx));
+        Ok(Self {
+            queue: Arc::new(Mutex::new(Queue {
+                events,
+                start: Instant::now(),
+            })),
+            capture_input,
+        })
+    }
+
+    // Timestamped under the lock so events land in the file in time order
+    fn event(&self, code: &'static str, data: &str) {
+        let queue = self.queue.lock().unwrap();
+        let time = queue.start.elapsed().as_secs_f64();
+        if queue.events.send((time, code, data.into())).is_err() {
+            log::error!("Recording writer has stopped");
+        }
+    }
+
+    pub fn output(&self, data: &str) {
+        self.event("o", data);
+    }
+
+    pub fn input(&self, data: &str) {
+        if self.capture_input {
+            self.event("i", data);
+        }
+    }
+
+    pub fn resize(&self, size: PtySize) {
+        self.event("r", &format!("{}x{}", size.cols, size.rows));
+    }
+}
+
+// Only plain file names are accepted so requests can't escape the recordings dir
+fn recording_path(dir: &Path, name: &str) -> Result<PathBuf> {
+    let path = Path::new(name);
+    if path.file_name().and_then(|n| n.to_str()) != Some(name)
+        || path.extension().and_then(|e| e.to_str()) != Some(EXTENSION)
+    {
+        return Err(anyhow!("Invalid recording name {}", name));
+    }
+    Ok(dir.join(path))
+}
+
+pub fn list_recordings(dir: &Path) -> Result<Vec<RecordingInfo>> {
+    if !dir.exists() {
+        return Ok(vec![]);
+    }
+    let mut recordings = vec![];
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if path.extension().and_then(|e| e.to_str()) != Some(EXTENSION) {
+            continue;
+        }
+        let meta = entry.metadata()?;
+        let modified: DateTime<Utc> = meta.modified()?.into();
+        recordings.push(RecordingInfo {
+            name: entry.file_name().to_string_lossy().to_string(),
+            size: meta.len(),
+            modified: modified.to_rfc3339(),
+        });
+    }
+    recordings.sort_by(|a, b| a.modified.cmp(&b.modified));
+    Ok(recordings)
+}
+
+pub fn read_recording(dir: &Path, msg: GetRecordingMsg) -> Result<RecordingChunk> {
+    let mut file = File::open(recording_path(dir, &msg.name)?)?;
+    let size = file.metadata()?.len();
+    let offset = msg.offset.unwrap_or(0).min(size);
+    let length = msg.length.unwrap_or(MAX_CHUNK).min(MAX_CHUNK);
+
+    file.seek(SeekFrom::Start(offset))?;
+    let mut buf = vec![];
+    file.take(length as u64).read_to_end(&mut buf)?;
+
+    // Don't split utf-8 sequences between chunks
+    let valid = match std::str::from_utf8(&buf) {
+        Ok(_) => buf.len(),
+        Err(e) => e.valid_up_to(),
+    };
+    buf.truncate(valid);
+    let next = offset + buf.len() as u64;
+
+    Ok(RecordingChunk {
+        name: msg.name,
+        offset,
+        data: String::from_utf8(buf)?,
+        eof: next >= size,
+    })
+}
@@ -0,0 +1,309 @@
+// Localhost benchmark of the port tunnel, two peer connections in one
+// process with the real client and the server's own channel handling
+// (sessions, direct output, compression) in between:
+//
+//   cargo run --release --bin port-bench -- --megabytes 512 --pings 2000 --compress zstd
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use clap::Parser;
+use compression::Compression;
+use env_logger::Env;
+use peer::DataChannelSettingsMsg;
+use policy::{ForwardPolicy, ForwardRule, Protocol};
+use port::{forward_stream, MAX_CHUNK};
+use settings::Settings;
+use signal::Signaling;
+use state::State;
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio::net::TcpListener;
+use tokio::sync::broadcast;
+use virtual_display::VirtualDisplayManager;
+use webrtc::api::setting_engine::SettingEngine;
+use webrtc::api::APIBuilder;
+use webrtc::data_channel::RTCDataChannel;
+use webrtc::peer_connection::configuration::RTCConfiguration;
+use webrtc::peer_connection::RTCPeerConnection;
+
+pub mod asciicast;
+pub mod clipboard;
+pub mod compression;
+pub mod control;
+pub mod display;
+pub mod file;
+pub mod fs;
+pub mod http;
+pub mod input;
+pub mod peer;
+pub mod policy;
+pub mod port;
+pub mod recording;
+pub mod screen;
+pub mod settings;
+pub mod sftp;
+pub mod sync;
+pub mod shell;
+pub mod signal;
+pub mod state;
+pub mod udp;
+pub mod utils;
+
+#[derive(Parser)]
+#[command(version, about = "Throughput and latency of the port tunnel on localhost", long_about = None)]
+struct Cli {
+    /// Megabytes pushed through the tunnel for the throughput test
+    #[arg(long, default_value_t = 256)]
+    megabytes: usize,
+
+    /// Round trips of single bytes for the latency test
+    #[arg(long, default_value_t = 1000)]
+    pings: usize,
+
+    /// Compression offered on the tunnel channels
+    #[arg(long)]
+    compress: Option<Compression>,
+}
+
+// Offer and answer are handed over in process
+struct NoSignaling;
+
+impl Signaling for NoSignaling {
+    fn send(&self, _msg: String) {}
+
+    async fn next(&self) -> Option<String> {
+        None
+    }
+}
+
+// The server state with a forward policy allowing the local test listeners
+async fn server_state() -> Result<Arc<State<NoSignaling>>> {
+    let settings = Settings {
+        forward: ForwardPolicy {
+            rules: vec![ForwardRule {
+                host: None,
+                cidr: Some("127.0.0.0/8".parse()?),
+                ports: None,
+                protocol: Protocol::Tcp,
+            }],
+            unix_sockets: vec![],
+        },
+        ..Default::default()
+    };
+    Ok(Arc::new(State {
+        api: APIBuilder::new().build(),
+        config: RTCConfiguration::default(),
+        session_map: Default::default(),
+        my_name: "port-bench".into(),
+        signaling: Arc::new(NoSignaling),
+        peer_map: Default::default(),
+        display_manager: Arc::new(VirtualDisplayManager::new().await?),
+        keyboards: Default::default(),
+        videos: Default::default(),
+        settings: Arc::new(settings),
+    }))
+}
+
+// Offer and answer are handed over directly, no signaling server involved
+async fn connected_pair(
+    state: Arc<State<NoSignaling>>,
+    done_rx: broadcast::Receiver<()>,
+) -> Result<(Arc<RTCPeerConnection>, Arc<RTCPeerConnection>)> {
+    let mut setting_engine = SettingEngine::default();
+    setting_engine.set_include_loopback_candidate(true);
+    let api = APIBuilder::new()
+        .with_setting_engine(setting_engine)
+        .build();
+
+    let a = Arc::new(api.new_peer_connection(RTCConfiguration::default()).await?);
+    let b = Arc::new(api.new_peer_connection(RTCConfiguration::default()).await?);
+    serve_channels(state, &b, done_rx);
+
+    // Like the client, a first channel gets sctp into the offer
+    let _ = a.create_data_channel("dummy", None).await?;
+
+    let offer = a.create_offer(None).await?;
+    let mut gathered = a.gathering_complete_promise().await;
+    a.set_local_description(offer).await?;
+    let _ = gathered.recv().await;
+    let offer = a.local_description().await.ok_or(anyhow!("no offer"))?;
+
+    b.set_remote_description(offer).await?;
+    let answer = b.create_answer(None).await?;
+    let mut gathered = b.gathering_complete_promise().await;
+    b.set_local_description(answer).await?;
+    let _ = gathered.recv().await;
+    let answer = b.local_description().await.ok_or(anyhow!("no answer"))?;
+    a.set_remote_description(answer).await?;
+
+    Ok((a, b))
+}
+
+// Server side, channels go through the same on_data_channel the server
+// registers for every peer
+fn serve_channels(
+    state: Arc<State<NoSignaling>>,
+    pc: &Arc<RTCPeerConnection>,
+    done_rx: broadcast::Receiver<()>,
+) {
+    let pc2 = pc.clone();
+    pc.on_data_channel(Box::new(move |d: Arc<RTCDataChannel>| {
+        let session_map = state.session_map.clone();
+        if let Err(e) =
+            state
+                .clone()
+                .on_data_channel(pc2.clone(), d, session_map, done_rx.resubscribe())
+        {
+            log::error!("Failed to handle data channel: {}", e);
+        }
+        Box::pin(async {})
+    }))
+}
+
+// A client side port channel to `target`, the returned end is what a
+// forwarded tcp connection would be
+fn tunnel(
+    pc: &Arc<RTCPeerConnection>,
+    target: SocketAddr,
+    compression: Option<Compression>,
+) -> DuplexStream {
+    let (app, stream) = io::duplex(4 * MAX_CHUNK);
+    let settings = DataChannelSettingsMsg {
+        variant: "port".into(),
+        target: Some(target.to_string()),
+        compression: compression.map(|compression| vec![compression]),
+        ..Default::default()
+    };
+    let pc = pc.clone();
+    tokio::spawn(async move {
+        if let Err(e) = forward_stream(pc, stream, settings).await {
+            log::error!("Tunnel failed: {}", e);
+        }
+    });
+    app
+}
+
+// Counts everything until EOF and answers with the count
+async fn sink() -> Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut buffer = vec![0u8; MAX_CHUNK];
+                let mut total = 0u64;
+                loop {
+                    match stream.read(&mut buffer).await {
+                        Ok(0) => break,
+                        Ok(n) => total += n as u64,
+                        Err(_) => return,
+                    }
+                }
+                let _ = stream.write_u64(total).await;
+                let _ = stream.shutdown().await;
+            });
+        }
+    });
+    Ok(addr)
+}
+
+async fn echo() -> Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = stream.into_split();
+                let _ = io::copy(&mut reader, &mut writer).await;
+            });
+        }
+    });
+    Ok(addr)
+}
+
+async fn throughput(
+    pc: &Arc<RTCPeerConnection>,
+    megabytes: usize,
+    compression: Option<Compression>,
+) -> Result<()> {
+    let (mut reader, mut writer) = io::split(tunnel(pc, sink().await?, compression));
+    let total = megabytes * 1024 * 1024;
+    let chunk = vec![0x5a; MAX_CHUNK];
+
+    let start = Instant::now();
+    let mut sent = 0;
+    while sent < total {
+        let n = (total - sent).min(chunk.len());
+        writer.write_all(&chunk[..n]).await?;
+        sent += n;
+    }
+    writer.shutdown().await?;
+    // Only arrives after the sink saw all of it and the EOF
+    let received = reader.read_u64().await?;
+    let elapsed = start.elapsed();
+
+    if received != total as u64 {
+        return Err(anyhow!("Sent {} bytes but {} arrived", total, received));
+    }
+    println!(
+        "throughput: {} MB in {:.2?}, {:.1} MB/s",
+        megabytes,
+        elapsed,
+        megabytes as f64 / elapsed.as_secs_f64()
+    );
+    Ok(())
+}
+
+async fn latency(
+    pc: &Arc<RTCPeerConnection>,
+    pings: usize,
+    compression: Option<Compression>,
+) -> Result<()> {
+    let mut stream = tunnel(pc, echo().await?, compression);
+    let mut byte = [0u8; 1];
+
+    // The first round trip also opens the channel, it's not counted
+    stream.write_all(b"x").await?;
+    stream.read_exact(&mut byte).await?;
+
+    let mut times: Vec<Duration> = Vec::with_capacity(pings);
+    for _ in 0..pings {
+        let start = Instant::now();
+        stream.write_all(b"x").await?;
+        stream.read_exact(&mut byte).await?;
+        times.push(start.elapsed());
+    }
+    if times.is_empty() {
+        return Ok(());
+    }
+    times.sort();
+
+    let avg = times.iter().sum::<Duration>() / times.len() as u32;
+    let percentile = |p: usize| times[(times.len() * p / 100).min(times.len() - 1)];
+    println!(
+        "latency: {} round trips, avg {:.2?}, p50 {:.2?}, p99 {:.2?}, max {:.2?}",
+        times.len(),
+        avg,
+        percentile(50),
+        percentile(99),
+        times[times.len() - 1]
+    );
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    env_logger::Builder::from_env(Env::default().default_filter_or("warn")).init();
+
+    let (done_tx, done_rx) = broadcast::channel::<()>(1);
+    let (a, b) = connected_pair(server_state().await?, done_rx).await?;
+    throughput(&a, cli.megabytes, cli.compress).await?;
+    latency(&a, cli.pings, cli.compress).await?;
+
+    let _ = done_tx.send(());
+    a.close().await?;
+    b.close().await?;
+    Ok(())
+}
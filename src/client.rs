@@ -1,30 +1,61 @@
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use anyhow::{Ok, Result};
+use anyhow::{anyhow, Ok, Result};
 
 use bytes::Bytes;
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use compression::Compression;
 use env_logger::Env;
-use peer::Peer;
+use forward::{
+    ClientConfig, DynamicForward, ForwardStats, HttpForward, LocalForward, RemoteForward,
+    UdpForward,
+};
+use futures_util::future::try_join_all;
+use peer::{DataChannelSettingsMsg, Peer};
+use policy::{Endpoint, ForwardPolicy, ForwardRule, Protocol, Target};
+use port::{forward_stream, Listener};
 use rand::distributions::{Alphanumeric, DistString};
+use settings::{ClipboardSettings, Settings};
 use signal::{Message, Signaling};
 use state::State;
 use tokio::{
-    io::{self, AsyncReadExt, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
-    sync::mpsc,
+    io,
+    net::UdpSocket,
+    sync::{broadcast, mpsc},
 };
+use utils::to_json;
 use webrtc::{
-    data_channel::data_channel_message::DataChannelMessage, peer_connection::RTCPeerConnection,
+    data_channel::{data_channel_message::DataChannelMessage, RTCDataChannel},
+    peer_connection::RTCPeerConnection,
 };
 
+pub mod asciicast;
+pub mod clipboard;
+pub mod compression;
+pub mod file;
+pub mod forward;
+pub mod fs;
+pub mod http;
+pub mod input;
 pub mod peer;
+pub mod policy;
 pub mod port;
 pub mod shell;
 pub mod signal;
+pub mod socks;
 pub mod recording;
+pub mod screen;
 pub mod control;
+pub mod display;
+pub mod settings;
+pub mod sftp;
+pub mod sync;
 pub mod state;
+pub mod udp;
 pub mod utils;
 
 #[derive(Parser)]
@@ -33,47 +64,603 @@ pub struct Cli {
     name: Option<String>,
     url: Option<String>,
 
+    /// Server to connect to
+    #[arg(short, long)]
+    server: Option<String>,
+
+    /// Local forward, [bind_addr:]port:host:hostport, either side can be unix:/path, can be repeated
+    #[arg(short = 'L', long = "local")]
+    local: Vec<LocalForward>,
+
+    /// Remote forward, the server listens on [bind_addr:]port and the client dials host:hostport, can be repeated
+    #[arg(short = 'R', long = "remote")]
+    remote: Vec<RemoteForward>,
+
+    /// Dynamic forward, a SOCKS5 and HTTP CONNECT proxy on [bind_addr:]port, can be repeated
+    #[arg(short = 'D', long = "dynamic")]
+    dynamic: Vec<DynamicForward>,
+
+    /// Udp forward, [bind_addr:]port:host:hostport, can be repeated
+    #[arg(short = 'U', long = "udp")]
+    udp: Vec<UdpForward>,
+
+    /// Http forward, a local web server on [bind_addr:]port proxying to the server-local app at host:hostport, can be repeated
+    #[arg(long)]
+    http: Vec<HttpForward>,
+
+    /// Path to a json config with `forwards`, `remote_forwards`, `dynamic_forwards`, `udp_forwards` and `http_forwards` sections
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+
+    /// Where the server should connect forwarded connections when no -L, -R, -D, -U or --http is given, host:port
+    #[arg(short, long)]
+    target: Option<String>,
+
+    /// Pump stdin/stdout through one port channel and exit, for ssh's ProxyCommand
+    #[arg(long, num_args = 1..=2, value_names = ["SERVER", "TARGET"])]
+    stdio: Option<Vec<String>>,
+
+    /// With --stdio, serve SFTP on stdin/stdout instead of a port channel, for `sftp -D` and `sshfs -o passive`
+    #[arg(long, requires = "stdio")]
+    sftp: bool,
+
+    /// Ask the server to compress forwarded connections and file transfers
+    #[arg(long, value_enum)]
+    compress: Option<Compression>,
+
     /// Turn debugging information on
     #[arg(short, long, action = clap::ArgAction::Count)]
     debug: u8,
+
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
+#[derive(Subcommand)]
+enum Command {
+    /// Copy a file to or from a server, server:/path names the remote side.
+    /// Running the same copy again resumes an interrupted one
+    Cp { source: String, destination: String },
+    /// Make server:/path match a local directory, only changed blocks of
+    /// changed files are sent
+    Sync {
+        source: String,
+        destination: String,
+        /// Also remove what the local directory doesn't have
+        #[arg(long)]
+        delete: bool,
+        /// Print what would change without changing anything
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+    },
+    /// Share this machine's clipboard with the server's desktop until interrupted
+    Clipboard,
+}
+
+const DEFAULT_LISTEN: &str = "127.0.0.1:2222";
+
 pub async fn start_client(cli: Cli) -> Result<()> {
-    let cli = Arc::new(cli);
+    let config = ClientConfig::load(cli.config.as_deref())?;
+    let server = cli
+        .server
+        .clone()
+        .or(config.server)
+        .unwrap_or("server1".into());
+    let url = cli.url.clone().or(config.url);
+    let compression = cli.compress.or(config.compress);
+
+    if let Some(stdio) = &cli.stdio {
+        let settings = if cli.sftp {
+            DataChannelSettingsMsg {
+                variant: "sftp".into(),
+                compression: compression.map(|compression| vec![compression]),
+                ..Default::default()
+            }
+        } else {
+            let target = stdio
+                .get(1)
+                .or(cli.target.as_ref())
+                .cloned()
+                .unwrap_or_else(|| Target::default().to_string());
+            DataChannelSettingsMsg {
+                variant: "port".into(),
+                compression: compression.map(|compression| vec![compression]),
+                target: Some(target),
+                ..Default::default()
+            }
+        };
+        return run_stdio(cli.name.clone(), url, stdio[0].clone(), settings).await;
+    }
+
+    if let Some(Command::Cp {
+        source,
+        destination,
+    }) = &cli.command
+    {
+        return run_cp(cli.name.clone(), url, source, destination, compression).await;
+    }
+
+    if let Some(Command::Sync {
+        source,
+        destination,
+        delete,
+        dry_run,
+    }) = &cli.command
+    {
+        return run_sync(
+            cli.name.clone(),
+            url,
+            source,
+            destination,
+            *delete,
+            *dry_run,
+            compression,
+        )
+        .await;
+    }
+
+    if let Some(Command::Clipboard) = &cli.command {
+        return run_clipboard(cli.name.clone(), url, server, compression).await;
+    }
 
-    let peer_connection = connect_to_peer(cli).await?;
-    // Define the port to listen on
-    let addr = "127.0.0.1:2222"; // Adjust as needed
+    let mut forwards = config.forwards;
+    forwards.extend(cli.local.iter().cloned());
+    let mut remote_forwards = config.remote_forwards;
+    remote_forwards.extend(cli.remote.iter().cloned());
+    let mut dynamic_forwards = config.dynamic_forwards;
+    dynamic_forwards.extend(cli.dynamic.iter().cloned());
+    let mut udp_forwards = config.udp_forwards;
+    udp_forwards.extend(cli.udp.iter().cloned());
+    let mut http_forwards = config.http_forwards;
+    http_forwards.extend(cli.http.iter().cloned());
+    if forwards.is_empty()
+        && remote_forwards.is_empty()
+        && dynamic_forwards.is_empty()
+        && udp_forwards.is_empty()
+        && http_forwards.is_empty()
+    {
+        forwards.push(LocalForward {
+            bind: DEFAULT_LISTEN.into(),
+            target: cli
+                .target
+                .clone()
+                .unwrap_or_else(|| Target::default().to_string()),
+        });
+    }
 
-    let listener = TcpListener::bind(addr).await?;
+    let settings = client_settings(&remote_forwards)?;
+    let idle_timeout = Duration::from_secs(settings.udp.idle_timeout_secs);
+
+    // All forwards share one peer connection
+    let (peer_connection, mut done_rx) =
+        connect_to_peer(cli.name.clone(), url, server, settings).await?;
+
+    // Listen channels have to stay open for as long as the server should listen
+    let mut listen_channels = vec![];
+    for forward in remote_forwards {
+        listen_channels.push(request_listen(&peer_connection, forward).await?);
+    }
+
+    if forwards.is_empty()
+        && dynamic_forwards.is_empty()
+        && udp_forwards.is_empty()
+        && http_forwards.is_empty()
+    {
+        let _ = done_rx.recv().await;
+    } else {
+        let local = try_join_all(
+            forwards
+                .into_iter()
+                .map(|forward| run_forward(peer_connection.clone(), forward, "port", compression)),
+        );
+        let dynamic = try_join_all(
+            dynamic_forwards
+                .into_iter()
+                .map(|forward| run_dynamic(peer_connection.clone(), forward, compression)),
+        );
+        let udp = try_join_all(
+            udp_forwards
+                .into_iter()
+                .map(|forward| run_udp(peer_connection.clone(), forward, idle_timeout)),
+        );
+        let http = try_join_all(
+            http_forwards
+                .into_iter()
+                .map(|forward| run_forward(peer_connection.clone(), forward, "http", compression)),
+        );
+        tokio::try_join!(local, dynamic, udp, http)?;
+    }
 
-    log::info!("TCP server listening on {}", addr);
+    log::info!("Shutting down TCP server");
+    Ok(())
+}
+
+// Port channels opened by the server may only dial the -R targets
+fn client_settings(remote_forwards: &[RemoteForward]) -> Result<Settings> {
+    let mut forward_policy = ForwardPolicy {
+        rules: vec![],
+        unix_sockets: vec![],
+    };
+    for forward in remote_forwards {
+        match forward.target.parse::<Endpoint>()? {
+            Endpoint::Tcp(target) => forward_policy.rules.push(ForwardRule {
+                host: Some(target.host),
+                cidr: None,
+                ports: Some(target.port.to_string()),
+                protocol: Protocol::Tcp,
+            }),
+            Endpoint::Unix(path) => forward_policy.unix_sockets.push(path),
+        }
+    }
+    Ok(Settings {
+        forward: forward_policy,
+        ..Default::default()
+    })
+}
+
+// ProxyCommand mode, a single port or sftp channel over stdin and stdout
+async fn run_stdio(
+    name: Option<String>,
+    url: Option<String>,
+    server: String,
+    settings: DataChannelSettingsMsg,
+) -> Result<()> {
+    let (peer_connection, mut done_rx) =
+        connect_to_peer(name, url, server.clone(), client_settings(&[])?).await?;
 
-    while let io::Result::Ok((tcp_stream, _)) = listener.accept().await {
+    let stdio = io::join(io::stdin(), io::stdout());
+    tokio::select! {
+        res = forward_stream(peer_connection.clone(), stdio, settings) => res?,
+        _ = done_rx.recv() => return Err(anyhow!("Connection to {} failed", server)),
+    }
+
+    peer_connection.close().await?;
+    Ok(())
+}
+
+// `server:/path`, a spec without a server part before the colon is a local
+// path, and so is a Windows drive like `C:\dir` or `C:/dir`
+fn remote_path(spec: &str) -> Option<(&str, &str)> {
+    spec.split_once(':').filter(|(server, path)| {
+        let drive = server.len() == 1
+            && server.bytes().all(|b| b.is_ascii_alphabetic())
+            && path.starts_with(['/', '\\']);
+        !server.is_empty() && !server.contains(['/', '\\']) && !drive
+    })
+}
+
+async fn run_cp(
+    name: Option<String>,
+    url: Option<String>,
+    source: &str,
+    destination: &str,
+    compression: Option<Compression>,
+) -> Result<()> {
+    let (server, remote, download) = match (remote_path(source), remote_path(destination)) {
+        (Some((server, remote)), None) => (server, remote, true),
+        (None, Some((server, remote))) => (server, remote, false),
+        _ => return Err(anyhow!("Exactly one side of cp has to be server:/path")),
+    };
+    let (peer_connection, mut done_rx) =
+        connect_to_peer(name, url, server.into(), client_settings(&[])?).await?;
+
+    let copy = async {
+        if download {
+            // Copying into a directory keeps the remote file name
+            let mut local = PathBuf::from(destination);
+            if local.is_dir() {
+                let file_name = Path::new(remote)
+                    .file_name()
+                    .ok_or(anyhow!("{} has no file name", remote))?;
+                local.push(file_name);
+            }
+            file::download(&peer_connection, remote, &local, compression).await
+        } else {
+            let mut remote = remote.to_owned();
+            if remote.ends_with('/') {
+                let file_name = Path::new(source)
+                    .file_name()
+                    .ok_or(anyhow!("{} has no file name", source))?;
+                remote.push_str(&file_name.to_string_lossy());
+            }
+            file::upload(&peer_connection, Path::new(source), &remote, compression).await
+        }
+    };
+    tokio::select! {
+        res = copy => res?,
+        _ = done_rx.recv() => return Err(anyhow!("Connection to {} failed", server)),
+    }
+
+    peer_connection.close().await?;
+    Ok(())
+}
+
+async fn run_sync(
+    name: Option<String>,
+    url: Option<String>,
+    source: &str,
+    destination: &str,
+    delete: bool,
+    dry_run: bool,
+    compression: Option<Compression>,
+) -> Result<()> {
+    let (None, Some((server, remote))) = (remote_path(source), remote_path(destination)) else {
+        return Err(anyhow!("sync pushes a local directory to server:/path"));
+    };
+    let local = Path::new(source);
+    if !local.is_dir() {
+        return Err(anyhow!("{} is not a directory", source));
+    }
+    let (peer_connection, mut done_rx) =
+        connect_to_peer(name, url, server.into(), client_settings(&[])?).await?;
+
+    tokio::select! {
+        res = sync::sync(&peer_connection, local, remote, delete, dry_run, compression) => res?,
+        _ = done_rx.recv() => return Err(anyhow!("Connection to {} failed", server)),
+    }
+
+    peer_connection.close().await?;
+    Ok(())
+}
+
+async fn run_clipboard(
+    name: Option<String>,
+    url: Option<String>,
+    server: String,
+    compression: Option<Compression>,
+) -> Result<()> {
+    let (peer_connection, mut done_rx) =
+        connect_to_peer(name, url, server.clone(), client_settings(&[])?).await?;
+
+    let settings = ClipboardSettings::default();
+    tokio::select! {
+        res = clipboard::share(&peer_connection, &settings, compression) => res?,
+        _ = done_rx.recv() => return Err(anyhow!("Connection to {} failed", server)),
+    }
+
+    peer_connection.close().await?;
+    Ok(())
+}
+
+// Every accepted connection gets its own `variant` channel, "port" or "http"
+async fn run_forward(
+    peer_connection: Arc<RTCPeerConnection>,
+    forward: LocalForward,
+    variant: &'static str,
+    compression: Option<Compression>,
+) -> Result<()> {
+    let listener = Listener::bind(&forward.bind).await?;
+
+    log::info!("[{}] listening", forward);
+
+    let forward = Arc::new(forward);
+    let stats = Arc::new(ForwardStats::default());
+    while let io::Result::Ok(stream) = listener.accept().await {
         let peer_connection2 = peer_connection.clone();
+        let forward = forward.clone();
+        let stats = stats.clone();
         tokio::spawn(async move {
-            if let Err(e) = handle_client(peer_connection2, tcp_stream).await {
-                log::error!("Error while handle_client {}", e.to_string());
+            stats.opened(&forward);
+            let settings = DataChannelSettingsMsg {
+                variant: variant.into(),
+                compression: compression.map(|compression| vec![compression]),
+                target: Some(forward.target.clone()),
+                ..Default::default()
+            };
+            if let Err(e) = forward_stream(peer_connection2, stream, settings).await {
+                log::error!("[{}] Error while handle_client {}", forward, e.to_string());
             }
+            stats.closed(&forward);
         });
     }
 
-    log::info!("Shutting down TCP server");
     Ok(())
 }
 
-async fn connect_to_peer(cli: Arc<Cli>) -> Result<Arc<RTCPeerConnection>> {
+async fn run_dynamic(
+    peer_connection: Arc<RTCPeerConnection>,
+    forward: DynamicForward,
+    compression: Option<Compression>,
+) -> Result<()> {
+    let listener = Listener::bind(&forward.bind).await?;
+
+    log::info!("[{}] listening", forward);
+
+    let forward = Arc::new(forward);
+    let stats = Arc::new(ForwardStats::default());
+    while let io::Result::Ok(mut stream) = listener.accept().await {
+        let peer_connection2 = peer_connection.clone();
+        let forward = forward.clone();
+        let stats = stats.clone();
+        tokio::spawn(async move {
+            // Every proxied connection gets its own port channel to the requested destination
+            let target = match socks::handshake(&mut stream).await {
+                Result::Ok(target) => target,
+                Err(e) => {
+                    log::error!("[{}] Proxy handshake failed {}", forward, e.to_string());
+                    return;
+                }
+            };
+            log::info!("[{}] proxying to {}", forward, target);
+            stats.opened(&forward);
+            let settings = DataChannelSettingsMsg {
+                variant: "port".into(),
+                compression: compression.map(|compression| vec![compression]),
+                target: Some(target.to_string()),
+                ..Default::default()
+            };
+            if let Err(e) = forward_stream(peer_connection2, stream, settings).await {
+                log::error!("[{}] Error while handle_client {}", forward, e.to_string());
+            }
+            stats.closed(&forward);
+        });
+    }
+
+    Ok(())
+}
+
+async fn run_udp(
+    peer_connection: Arc<RTCPeerConnection>,
+    forward: UdpForward,
+    idle_timeout: Duration,
+) -> Result<()> {
+    let socket = Arc::new(UdpSocket::bind(&forward.bind).await?);
+
+    log::info!("[{}] listening udp", forward);
+
+    let forward = Arc::new(forward);
+    let stats = Arc::new(ForwardStats::default());
+    // Every source address is its own flow with its own channel
+    let mut flows: HashMap<SocketAddr, mpsc::Sender<Bytes>> = HashMap::new();
+    let mut buffer = vec![0u8; udp::MAX_DATAGRAM];
+    loop {
+        let (n, source) = socket.recv_from(&mut buffer).await?;
+        let data = Bytes::copy_from_slice(&buffer[..n]);
+
+        let flow = match flows.get(&source) {
+            Some(flow) if !flow.is_closed() => flow.clone(),
+            _ => {
+                flows.retain(|_, flow| !flow.is_closed());
+                let (flow_tx, flow_rx) = mpsc::channel::<Bytes>(100);
+                let peer_connection2 = peer_connection.clone();
+                let socket = socket.clone();
+                let forward = forward.clone();
+                let stats = stats.clone();
+                tokio::spawn(async move {
+                    stats.opened(&forward);
+                    if let Err(e) = udp_flow(
+                        peer_connection2,
+                        socket,
+                        source,
+                        &forward.target,
+                        flow_rx,
+                        idle_timeout,
+                    )
+                    .await
+                    {
+                        log::error!("[{}] Error while udp_flow {}", forward, e.to_string());
+                    }
+                    stats.closed(&forward);
+                });
+                flows.insert(source, flow_tx.clone());
+                flow_tx
+            }
+        };
+        // Like any udp hop, datagrams are dropped when the flow can't keep up
+        if flow.try_send(data).is_err() {
+            log::debug!("[{}] dropped datagram from {}", forward, source);
+        }
+    }
+}
+
+async fn udp_flow(
+    peer_connection: Arc<RTCPeerConnection>,
+    socket: Arc<UdpSocket>,
+    source: SocketAddr,
+    target: &str,
+    mut flow_rx: mpsc::Receiver<Bytes>,
+    idle_timeout: Duration,
+) -> Result<()> {
+    let label = to_json(DataChannelSettingsMsg {
+        variant: "udp".into(),
+        target: Some(target.into()),
+        ..Default::default()
+    })?;
+    let d = peer_connection
+        .create_data_channel(&label, Some(udp::channel_init()))
+        .await?;
+
+    let last_seen = Arc::new(Mutex::new(Instant::now()));
+    let (open_tx, mut open_rx) = mpsc::channel::<()>(1);
+    let (closed_tx, mut closed_rx) = mpsc::channel::<()>(1);
+
+    d.on_open(Box::new(move || {
+        Box::pin(async move {
+            let _ = open_tx.send(()).await;
+        })
+    }));
+    d.on_close(Box::new(move || {
+        let _ = closed_tx.try_send(());
+        Box::pin(async {})
+    }));
+    let last_seen2 = last_seen.clone();
+    d.on_message(Box::new(move |msg: DataChannelMessage| {
+        *last_seen2.lock().unwrap() = Instant::now();
+        let socket = socket.clone();
+        Box::pin(async move {
+            if let Err(e) = socket.send_to(&msg.data, source).await {
+                log::error!("UDP send error: {}", e);
+            }
+        })
+    }));
+
+    tokio::select! {
+        _ = open_rx.recv() => (),
+        _ = closed_rx.recv() => return Ok(()),
+    }
+
+    loop {
+        let deadline = *last_seen.lock().unwrap() + idle_timeout;
+        tokio::select! {
+            data = flow_rx.recv() => {
+                let Some(data) = data else {
+                    break;
+                };
+                *last_seen.lock().unwrap() = Instant::now();
+                if let Err(e) = d.send(&data).await {
+                    log::error!("Failed to send datagram: {}", e);
+                    break;
+                }
+            }
+            _ = tokio::time::sleep_until(deadline.into()) => {
+                if last_seen.lock().unwrap().elapsed() >= idle_timeout {
+                    log::info!("UDP flow from {} idle", source);
+                    break;
+                }
+            }
+            _ = closed_rx.recv() => break,
+        }
+    }
+
+    d.close().await?;
+    Ok(())
+}
+
+// Asks the server to listen on `forward.bind` and send connections back to us
+async fn request_listen(
+    peer_connection: &Arc<RTCPeerConnection>,
+    forward: RemoteForward,
+) -> Result<Arc<RTCDataChannel>> {
+    let label = to_json(DataChannelSettingsMsg {
+        variant: "listen".into(),
+        target: Some(forward.target.clone()),
+        bind: Some(forward.bind.clone()),
+        ..Default::default()
+    })?;
+    let d = peer_connection.create_data_channel(&label, None).await?;
+
+    log::info!("[{}] requested remote listener", forward);
+    d.on_close(Box::new(move || {
+        log::error!("[{}] remote listener closed", forward);
+        Box::pin(async {})
+    }));
+    Ok(d)
+}
+
+async fn connect_to_peer(
+    name: Option<String>,
+    url: Option<String>,
+    target: String,
+    settings: Settings,
+) -> Result<(Arc<RTCPeerConnection>, broadcast::Receiver<()>)> {
     log::info!("new connection");
-    let name = Alphanumeric.sample_string(&mut rand::thread_rng(), 16);
-    log::info!("Staring with {}", &name);
+    let random_name = Alphanumeric.sample_string(&mut rand::thread_rng(), 16);
+    log::info!("Staring with {}", &random_name);
 
-    let name = cli.name.clone().unwrap_or(name.clone());
-    let url = cli
-        .url
-        .clone()
-        .unwrap_or("wss://websh.amogos.pro/signaling".into());
+    let name = name.unwrap_or(random_name);
+    let url = url.unwrap_or("wss://websh.amogos.pro/signaling".into());
 
-    let state = State::new(name.clone(), "client".to_string(), url).await?;
+    let state = State::new(name.clone(), "client".to_string(), url, settings).await?;
     let state = Arc::new(state);
 
     let state_clone = state.clone();
@@ -81,9 +668,30 @@ async fn connect_to_peer(cli: Arc<Cli>) -> Result<Arc<RTCPeerConnection>> {
         state_clone.signal_loop().await;
         Box::pin(async move {})
     });
-    let target: String = "server1".into();
 
-    let (peer_connection, _done_rx) = state.create_peer_connection(target.clone()).await?;
+    let (peer_connection, done_rx) = state.create_peer_connection(target.clone()).await?;
+
+    // The server only opens port channels back to us (-R), refuse anything else
+    let state_clone = state.clone();
+    let pc = peer_connection.clone();
+    let peer_done_rx = done_rx.resubscribe();
+    peer_connection.on_data_channel(Box::new(move |d: Arc<RTCDataChannel>| {
+        let msg = serde_json::from_str::<DataChannelSettingsMsg>(d.label()).ok();
+        if msg.is_some_and(|msg| msg.variant == "port") {
+            let session_map = state_clone.session_map.clone();
+            if let Err(e) = state_clone.clone().on_data_channel(
+                pc.clone(),
+                d,
+                session_map,
+                peer_done_rx.resubscribe(),
+            ) {
+                log::error!("Failed to handle data channel: {}", e.to_string())
+            }
+        } else {
+            log::error!("Refusing data channel {}", d.label());
+        }
+        Box::pin(async {})
+    }));
 
     {
         let user_name = target.clone();
@@ -149,93 +757,48 @@ async fn connect_to_peer(cli: Arc<Cli>) -> Result<Arc<RTCPeerConnection>> {
     // create dummy data channel to force on_negotiation_needed
     let _ = peer_connection.create_data_channel("dummy", None).await?;
 
-    Ok(peer_connection)
-}
-
-async fn handle_client(
-    peer_connection: Arc<RTCPeerConnection>,
-    tcp_stream: TcpStream,
-) -> Result<()> {
-    let d = peer_connection.create_data_channel("port", None).await?;
-
-    let (to_pty_tx, mut to_pty_rx) = mpsc::channel::<Bytes>(100);
-
-    let (done_tx, mut done_rx) = mpsc::channel::<()>(1);
-
-    d.on_message(Box::new(move |msg: DataChannelMessage| {
-        let ptx_clone = to_pty_tx.clone();
-
-        Box::pin(async move {
-            // Send the message to the PTY task asynchronously
-            if let Err(e) = ptx_clone.send(msg.data).await {
-                log::error!("Failed to send message to PTY: {}", e);
-            }
-        })
-    }));
-
-    let d2 = d.clone();
-
-    d.on_open(Box::new(move || {
-        let d_clone = Arc::clone(&d2); // Clone the Arc to use in the async block
-        Box::pin(async move {
-            let (mut tcp_reader, mut tcp_writer) = tcp_stream.into_split();
-
-            let tcp_to_ws = async {
-                let mut buffer = [0u8; 1024];
-                loop {
-                    match tcp_reader.read(&mut buffer).await {
-                        Result::Ok(0) => break,
-                        Result::Ok(n) => {
-                            if let Err(e) =
-                                d_clone.send(&Bytes::copy_from_slice(&buffer[..n])).await
-                            {
-                                log::error!("WebSocket send error: {}", e);
-                                break;
-                            }
-                        }
-                        Err(e) => {
-                            log::error!("TCP read error: {}", e);
-                            break;
-                        }
-                    }
-                }
-                log::info!("TCP client disconnected");
-            };
-
-            let ws_to_tcp = async {
-                while let Some(msg) = to_pty_rx.recv().await {
-                    if let Err(e) = tcp_writer.write_all(&msg).await {
-                        log::error!("TCP write error: {}", e);
-                        break;
-                    }
-                }
-                tcp_writer.shutdown().await.ok();
-                log::info!("WebSocket connection closed");
-            };
-
-            tokio::select! {
-                _ = tcp_to_ws => (),
-                _ = ws_to_tcp => (),
-            }
-            done_tx.send(()).await.unwrap();
-        })
-    }));
-
-    done_rx.recv().await;
-
-    log::info!("connection end");
-
-    Ok(())
+    Ok((peer_connection, done_rx))
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli = Cli::parse();
-    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+    // ssh shows our stderr, keep it quiet when used as ProxyCommand. cp
+    // draws its progress there
+    let stdio = cli.stdio.is_some();
+    let default_filter = if stdio || cli.command.is_some() {
+        "warn"
+    } else {
+        "info"
+    };
+    env_logger::Builder::from_env(Env::default().default_filter_or(default_filter)).init();
 
-    if let Err(e) = start_client(cli).await {
+    let res = start_client(cli).await;
+    if let Err(e) = &res {
         log::error!("Error while handling {}", e.to_string())
     }
 
+    // A stdin read still blocked in the background would keep the runtime alive
+    if stdio {
+        std::process::exit(if res.is_ok() { 0 } else { 1 });
+    }
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remote_paths_need_a_server() {
+        assert_eq!(remote_path("box:/etc/hosts"), Some(("box", "/etc/hosts")));
+        assert_eq!(remote_path("b:notes.txt"), Some(("b", "notes.txt")));
+        assert_eq!(remote_path("box:notes.txt"), Some(("box", "notes.txt")));
+        assert_eq!(remote_path("notes.txt"), None);
+        assert_eq!(remote_path(":/etc/hosts"), None);
+        assert_eq!(remote_path("./box:/etc/hosts"), None);
+        assert_eq!(remote_path("C:\\Users\\me"), None);
+        assert_eq!(remote_path("c:/Users/me"), None);
+    }
+}
@@ -0,0 +1,618 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io::Cursor;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use arboard::Clipboard;
+use bytes::Bytes;
+use image::{ImageFormat, ImageReader, Limits, RgbaImage};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, mpsc, oneshot};
+use ts_rs::TS;
+use webrtc::peer_connection::RTCPeerConnection;
+
+use crate::compression::Compression;
+use crate::file::FileChannel;
+use crate::port::{is_eof, MAX_CHUNK};
+use crate::settings::ClipboardSettings;
+
+// Headers of a clipboard channel. Each is json followed by its payload as
+// raw chunks, either side sends one whenever its clipboard changed
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub enum ClipboardMsg {
+    // `size` bytes of utf-8
+    Text { size: u32 },
+    // The html, then the plain text version for apps that can't take html
+    Html { html_size: u32, text_size: u32 },
+    // `size` bytes of png
+    Image { width: u32, height: u32, size: u32 },
+    // The other side dropped an update, it was over a limit
+    Rejected { reason: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Content {
+    Text(String),
+    Html {
+        html: String,
+        text: String,
+    },
+    // 4 bytes a pixel
+    Image {
+        width: u32,
+        height: u32,
+        rgba: Vec<u8>,
+    },
+}
+
+impl Content {
+    fn digest(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn check(&self, settings: &ClipboardSettings) -> Result<()> {
+        let (size, max) = match self {
+            Content::Text(text) => (text.len(), settings.max_text_bytes),
+            Content::Html { html, text } => (html.len() + text.len(), settings.max_text_bytes),
+            Content::Image { rgba, .. } => (rgba.len(), settings.max_image_bytes),
+        };
+        if size > max as usize {
+            return Err(anyhow!(
+                "{} bytes of clipboard is over the {} limit",
+                size,
+                max
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn available<T>(res: Result<T, arboard::Error>) -> Result<Option<T>> {
+    match res {
+        Ok(value) => Ok(Some(value)),
+        Err(arboard::Error::ContentNotAvailable) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Where the contents live, the system clipboard outside of tests. Its calls
+// may block, they only run on the backend's thread
+trait Store {
+    fn read(&mut self) -> Result<Option<Content>>;
+    fn write(&mut self, content: Content) -> Result<()>;
+}
+
+impl Store for Clipboard {
+    // Richest format first, html usually comes with a text version as well
+    fn read(&mut self) -> Result<Option<Content>> {
+        if let Some(html) = available(self.get().html())? {
+            let text = available(self.get_text())?.unwrap_or_default();
+            return Ok(Some(Content::Html { html, text }));
+        }
+        if let Some(image) = available(self.get_image())? {
+            return Ok(Some(Content::Image {
+                width: image.width as u32,
+                height: image.height as u32,
+                rgba: image.bytes.into_owned(),
+            }));
+        }
+        Ok(available(self.get_text())?.map(Content::Text))
+    }
+
+    fn write(&mut self, content: Content) -> Result<()> {
+        match content {
+            Content::Text(text) => self.set_text(text)?,
+            Content::Html { html, text } => self.set_html(html, Some(text))?,
+            Content::Image {
+                width,
+                height,
+                rgba,
+            } => self.set_image(arboard::ImageData {
+                width: width as usize,
+                height: height as usize,
+                bytes: rgba.into(),
+            })?,
+        }
+        Ok(())
+    }
+}
+
+fn system() -> Result<Clipboard> {
+    Ok(Clipboard::new()?)
+}
+
+enum Request {
+    Read(oneshot::Sender<Result<Option<Content>>>),
+    Write(Content, oneshot::Sender<Result<()>>),
+}
+
+// The clipboard, owned by a thread of its own. On X11 whatever we copied
+// is only served while the `Clipboard` lives, and its calls block. arboard
+// talks plain X11 on Linux, so this runs the same under Xvfb with DISPLAY
+// pointed at it
+struct Backend {
+    requests: mpsc::UnboundedSender<Request>,
+}
+
+impl Backend {
+    async fn start<S: Store>(open: impl FnOnce() -> Result<S> + Send + 'static) -> Result<Self> {
+        let (requests, mut requests_rx) = mpsc::unbounded_channel();
+        let (ready_tx, ready_rx) = oneshot::channel();
+        std::thread::spawn(move || {
+            let mut clipboard = match open() {
+                Ok(clipboard) => clipboard,
+                Err(e) => {
+                    let _ = ready_tx.send(Err(e));
+                    return;
+                }
+            };
+            let _ = ready_tx.send(Ok(()));
+            while let Some(request) = requests_rx.blocking_recv() {
+                match request {
+                    Request::Read(reply) => {
+                        let _ = reply.send(clipboard.read());
+                    }
+                    Request::Write(content, reply) => {
+                        let _ = reply.send(clipboard.write(content));
+                    }
+                }
+            }
+        });
+        ready_rx.await??;
+        Ok(Self { requests })
+    }
+
+    async fn read(&self) -> Result<Option<Content>> {
+        let (reply, reply_rx) = oneshot::channel();
+        self.requests.send(Request::Read(reply))?;
+        reply_rx.await?
+    }
+
+    async fn write(&self, content: Content) -> Result<()> {
+        let (reply, reply_rx) = oneshot::channel();
+        self.requests.send(Request::Write(content, reply))?;
+        reply_rx.await?
+    }
+}
+
+fn encode_png(width: u32, height: u32, rgba: Vec<u8>) -> Result<Vec<u8>> {
+    let image = RgbaImage::from_raw(width, height, rgba)
+        .ok_or(anyhow!("Clipboard image doesn't match its size"))?;
+    let mut png = Cursor::new(vec![]);
+    image.write_to(&mut png, ImageFormat::Png)?;
+    Ok(png.into_inner())
+}
+
+// The limit applies to the decoded image so a small png can't unpack to gigabytes
+fn decode_png(png: &[u8], max_bytes: u32) -> Result<Content> {
+    let mut reader = ImageReader::with_format(Cursor::new(png), ImageFormat::Png);
+    let mut limits = Limits::default();
+    limits.max_alloc = Some(max_bytes as u64);
+    reader.limits(limits);
+    let image = reader.decode()?.to_rgba8();
+    Ok(Content::Image {
+        width: image.width(),
+        height: image.height(),
+        rgba: image.into_raw(),
+    })
+}
+
+async fn send_msg(tx: &mpsc::Sender<Bytes>, msg: &ClipboardMsg) -> Result<()> {
+    tx.send(serde_json::to_vec(msg)?.into()).await?;
+    Ok(())
+}
+
+async fn send_content(tx: &mpsc::Sender<Bytes>, content: Content) -> Result<()> {
+    let (msg, payload) = match content {
+        Content::Text(text) => (
+            ClipboardMsg::Text {
+                size: text.len() as u32,
+            },
+            text.into_bytes(),
+        ),
+        Content::Html { html, text } => (
+            ClipboardMsg::Html {
+                html_size: html.len() as u32,
+                text_size: text.len() as u32,
+            },
+            [html, text].concat().into_bytes(),
+        ),
+        Content::Image {
+            width,
+            height,
+            rgba,
+        } => {
+            let png =
+                tokio::task::spawn_blocking(move || encode_png(width, height, rgba)).await??;
+            let msg = ClipboardMsg::Image {
+                width,
+                height,
+                size: png.len() as u32,
+            };
+            (msg, png)
+        }
+    };
+    send_msg(tx, &msg).await?;
+    let mut payload = Bytes::from(payload);
+    while !payload.is_empty() {
+        tx.send(payload.split_to(payload.len().min(MAX_CHUNK)))
+            .await?;
+    }
+    Ok(())
+}
+
+// Collects `size` bytes of payload, None if the channel ended
+async fn recv_payload(rx: &mut mpsc::Receiver<Bytes>, size: usize) -> Result<Option<Vec<u8>>> {
+    let mut payload = Vec::with_capacity(size.min(MAX_CHUNK));
+    while payload.len() < size {
+        let Some(data) = rx.recv().await else {
+            return Ok(None);
+        };
+        if is_eof(&data) {
+            return Ok(None);
+        }
+        payload.extend_from_slice(&data);
+    }
+    if payload.len() > size {
+        return Err(anyhow!("Clipboard payload is longer than announced"));
+    }
+    Ok(Some(payload))
+}
+
+// Reads past a payload we won't take without keeping any of it, false if
+// the channel ended
+async fn skip_payload(rx: &mut mpsc::Receiver<Bytes>, size: usize) -> Result<bool> {
+    let mut left = size;
+    while left > 0 {
+        let Some(data) = rx.recv().await.filter(|data| !is_eof(data)) else {
+            return Ok(false);
+        };
+        left = left
+            .checked_sub(data.len())
+            .ok_or(anyhow!("Clipboard payload is longer than announced"))?;
+    }
+    Ok(true)
+}
+
+// Checks the announced sizes against our limits before anything is read,
+// for images both the png and what it decodes to
+fn check_msg(msg: &ClipboardMsg, settings: &ClipboardSettings) -> Result<()> {
+    let (size, max) = match *msg {
+        ClipboardMsg::Text { size } => (size as u64, settings.max_text_bytes),
+        ClipboardMsg::Html {
+            html_size,
+            text_size,
+        } => (html_size as u64 + text_size as u64, settings.max_text_bytes),
+        ClipboardMsg::Image {
+            width,
+            height,
+            size,
+        } => {
+            let decoded = width as u64 * height as u64 * 4;
+            (decoded.max(size as u64), settings.max_image_bytes)
+        }
+        ClipboardMsg::Rejected { .. } => return Ok(()),
+    };
+    if size > max as u64 {
+        return Err(anyhow!(
+            "{} bytes of clipboard is over the {} limit",
+            size,
+            max
+        ));
+    }
+    Ok(())
+}
+
+fn payload_size(msg: &ClipboardMsg) -> usize {
+    match *msg {
+        ClipboardMsg::Text { size } | ClipboardMsg::Image { size, .. } => size as usize,
+        ClipboardMsg::Html {
+            html_size,
+            text_size,
+        } => html_size as usize + text_size as usize,
+        ClipboardMsg::Rejected { .. } => 0,
+    }
+}
+
+async fn parse_content(
+    msg: ClipboardMsg,
+    payload: Vec<u8>,
+    settings: &ClipboardSettings,
+) -> Result<Content> {
+    match msg {
+        ClipboardMsg::Text { .. } => Ok(Content::Text(String::from_utf8(payload)?)),
+        ClipboardMsg::Html { html_size, .. } => {
+            let mut html = String::from_utf8(payload)?;
+            if !html.is_char_boundary(html_size as usize) {
+                return Err(anyhow!("Clipboard html size is off"));
+            }
+            let text = html.split_off(html_size as usize);
+            Ok(Content::Html { html, text })
+        }
+        ClipboardMsg::Image { .. } => {
+            let max = settings.max_image_bytes;
+            tokio::task::spawn_blocking(move || decode_png(&payload, max)).await?
+        }
+        ClipboardMsg::Rejected { .. } => Err(anyhow!("Rejected carries no content")),
+    }
+}
+
+// Keeps the local clipboard and the one across the channel the same until
+// the channel ends. What either side has when it starts is not sent, only
+// later changes, so connecting doesn't clobber anything
+async fn sync_clipboard<S: Store>(
+    tx: &mpsc::Sender<Bytes>,
+    rx: &mut mpsc::Receiver<Bytes>,
+    settings: &ClipboardSettings,
+    open: impl FnOnce() -> Result<S> + Send + 'static,
+) -> Result<()> {
+    let clipboard = Backend::start(open).await?;
+    let mut last = clipboard.read().await.ok().flatten().map(|c| c.digest());
+    let mut poll = tokio::time::interval(Duration::from_millis(settings.poll_interval_ms));
+    poll.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            _ = poll.tick() => {
+                let content = match clipboard.read().await {
+                    Ok(Some(content)) => content,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        log::debug!("Failed to read clipboard: {}", e);
+                        continue;
+                    }
+                };
+                let digest = content.digest();
+                if last == Some(digest) {
+                    continue;
+                }
+                last = Some(digest);
+                if let Err(e) = content.check(settings) {
+                    log::warn!("Not sending clipboard: {}", e);
+                    continue;
+                }
+                send_content(tx, content).await?;
+            }
+            data = rx.recv() => {
+                let Some(data) = data.filter(|data| !is_eof(data)) else {
+                    return Ok(());
+                };
+                // A bad header from a newer or broken peer costs one update, not the sync
+                let msg: ClipboardMsg = match serde_json::from_slice(&data) {
+                    Ok(msg) => msg,
+                    Err(e) => {
+                        log::warn!("Dropping clipboard message: {}", e);
+                        let reason = format!("Unreadable clipboard message: {}", e);
+                        send_msg(tx, &ClipboardMsg::Rejected { reason }).await?;
+                        continue;
+                    }
+                };
+                if let ClipboardMsg::Rejected { reason } = &msg {
+                    log::warn!("Clipboard update rejected: {}", reason);
+                    continue;
+                }
+                if let Err(e) = check_msg(&msg, settings) {
+                    log::warn!("Dropping clipboard update: {}", e);
+                    let reason = e.to_string();
+                    send_msg(tx, &ClipboardMsg::Rejected { reason }).await?;
+                    if !skip_payload(rx, payload_size(&msg)).await? {
+                        return Ok(());
+                    }
+                    continue;
+                }
+                let Some(payload) = recv_payload(rx, payload_size(&msg)).await? else {
+                    return Ok(());
+                };
+                let content = parse_content(msg, payload, settings).await?;
+                clipboard.write(content).await?;
+                // Read back what the system made of it, otherwise a format
+                // conversion would look like a local change and bounce back
+                last = clipboard.read().await.ok().flatten().map(|c| c.digest());
+            }
+        }
+    }
+}
+
+pub async fn handle_clipboard(
+    tx: mpsc::Sender<Bytes>,       // From server to the client
+    mut rx: mpsc::Receiver<Bytes>, // From clients to server
+    mut done_rx: broadcast::Receiver<()>,
+    settings: ClipboardSettings,
+) {
+    if !settings.enabled {
+        log::error!("Clipboard channels are disabled");
+        return;
+    }
+    tokio::select! {
+        res = sync_clipboard(&tx, &mut rx, &settings, system) => {
+            if let Err(e) = res {
+                log::error!("Clipboard sync failed: {}", e);
+            }
+        }
+        _ = done_rx.recv() => (),
+    }
+
+    log::info!("handle_clipboard exiting");
+}
+
+// Client end, shares this machine's clipboard with the server's desktop
+pub async fn share(
+    peer_connection: &Arc<RTCPeerConnection>,
+    settings: &ClipboardSettings,
+    compression: Option<Compression>,
+) -> Result<()> {
+    let FileChannel { d, tx, mut rx } =
+        FileChannel::open(peer_connection, "clipboard", compression).await?;
+    let res = sync_clipboard(&tx, &mut rx, settings, system).await;
+    d.close().await?;
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use tokio::task::JoinHandle;
+    use tokio::time::timeout;
+
+    use super::*;
+
+    const WAIT: Duration = Duration::from_secs(2);
+
+    #[derive(Clone, Default)]
+    struct MockClipboard(Arc<Mutex<Option<Content>>>);
+
+    impl Store for MockClipboard {
+        fn read(&mut self) -> Result<Option<Content>> {
+            Ok(self.0.lock().unwrap().clone())
+        }
+
+        fn write(&mut self, content: Content) -> Result<()> {
+            *self.0.lock().unwrap() = Some(content);
+            Ok(())
+        }
+    }
+
+    // One side of a clipboard channel, the test plays the other
+    struct Peer {
+        tx: mpsc::Sender<Bytes>,
+        rx: mpsc::Receiver<Bytes>,
+        clipboard: MockClipboard,
+        task: JoinHandle<Result<()>>,
+    }
+
+    impl Peer {
+        fn start(settings: ClipboardSettings, content: Option<Content>) -> Self {
+            let (tx, mut sync_rx) = mpsc::channel(16);
+            let (sync_tx, rx) = mpsc::channel(16);
+            let clipboard = MockClipboard(Arc::new(Mutex::new(content)));
+            let store = clipboard.clone();
+            let task = tokio::spawn(async move {
+                sync_clipboard(&sync_tx, &mut sync_rx, &settings, move || Ok(store)).await
+            });
+            Self {
+                tx,
+                rx,
+                clipboard,
+                task,
+            }
+        }
+
+        async fn send(&self, msg: &ClipboardMsg, payload: &[&[u8]]) {
+            send_msg(&self.tx, msg).await.unwrap();
+            for chunk in payload {
+                self.tx.send(Bytes::copy_from_slice(chunk)).await.unwrap();
+            }
+        }
+
+        async fn recv(&mut self) -> Bytes {
+            timeout(WAIT, self.rx.recv()).await.unwrap().unwrap()
+        }
+
+        async fn recv_msg(&mut self) -> ClipboardMsg {
+            serde_json::from_slice(&self.recv().await).unwrap()
+        }
+
+        async fn wait_for(&self, content: &Content) {
+            timeout(WAIT, async {
+                while self.clipboard.0.lock().unwrap().as_ref() != Some(content) {
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                }
+            })
+            .await
+            .unwrap();
+        }
+    }
+
+    fn settings() -> ClipboardSettings {
+        ClipboardSettings {
+            max_text_bytes: 16,
+            poll_interval_ms: 10,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn local_changes_are_sent_but_not_the_initial_content() {
+        let initial = Content::Text("before".into());
+        let mut peer = Peer::start(settings(), Some(initial));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(peer.rx.try_recv().is_err());
+
+        *peer.clipboard.0.lock().unwrap() = Some(Content::Text("copied".into()));
+        assert!(matches!(
+            peer.recv_msg().await,
+            ClipboardMsg::Text { size: 6 }
+        ));
+        assert_eq!(&peer.recv().await[..], b"copied");
+    }
+
+    #[tokio::test]
+    async fn remote_update_is_written_and_not_echoed() {
+        let mut peer = Peer::start(settings(), None);
+        let html = ClipboardMsg::Html {
+            html_size: 9,
+            text_size: 2,
+        };
+        peer.send(&html, &[b"<b>", b"hi</b>hi"]).await;
+        let expected = Content::Html {
+            html: "<b>hi</b>".into(),
+            text: "hi".into(),
+        };
+        peer.wait_for(&expected).await;
+        assert!(timeout(Duration::from_millis(100), peer.rx.recv())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn oversized_update_is_rejected_and_skipped() {
+        let mut peer = Peer::start(settings(), None);
+        peer.send(&ClipboardMsg::Text { size: 20 }, &[&[b'x'; 12], &[b'x'; 8]])
+            .await;
+        assert!(matches!(
+            peer.recv_msg().await,
+            ClipboardMsg::Rejected { .. }
+        ));
+        // The skipped payload must not be taken for the next header
+        peer.send(&ClipboardMsg::Text { size: 2 }, &[b"ok"]).await;
+        peer.wait_for(&Content::Text("ok".into())).await;
+    }
+
+    #[tokio::test]
+    async fn huge_png_is_rejected_before_its_payload() {
+        let mut peer = Peer::start(settings(), None);
+        let image = ClipboardMsg::Image {
+            width: 1,
+            height: 1,
+            size: u32::MAX,
+        };
+        peer.send(&image, &[]).await;
+        assert!(matches!(
+            peer.recv_msg().await,
+            ClipboardMsg::Rejected { .. }
+        ));
+        drop(peer.tx);
+        timeout(WAIT, peer.task).await.unwrap().unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn unreadable_message_is_rejected_and_sync_goes_on() {
+        let mut peer = Peer::start(settings(), None);
+        peer.tx
+            .send(Bytes::from_static(b"{\"type\":"))
+            .await
+            .unwrap();
+        assert!(matches!(
+            peer.recv_msg().await,
+            ClipboardMsg::Rejected { .. }
+        ));
+        peer.send(&ClipboardMsg::Text { size: 2 }, &[b"ok"]).await;
+        peer.wait_for(&Content::Text("ok".into())).await;
+    }
+}
@@ -0,0 +1,149 @@
+use std::io::{self, Write};
+
+use anyhow::Result;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use crate::port::MAX_CHUNK;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, TS, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+#[ts(export)]
+pub enum Compression {
+    Zstd,
+    Deflate,
+}
+
+const ZSTD_LEVEL: i32 = 3;
+// Every message was at most a chunk before compression, a frame unpacking
+// to much more than that is a bomb
+const MAX_DECOMPRESSED: usize = 4 * MAX_CHUNK;
+
+// Picks the first offered algorithm, or nothing if the policy forbids it
+pub fn negotiate(offer: &[Compression], allowed: bool) -> Option<Compression> {
+    if !allowed {
+        return None;
+    }
+    offer.first().copied()
+}
+
+// Streaming compressor, the dictionary is kept between messages of one channel.
+// Every message is flushed so the other side can decode it on its own.
+pub enum Compressor {
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+    Deflate(flate2::write::DeflateEncoder<Vec<u8>>),
+}
+
+impl Compressor {
+    pub fn new(compression: Compression) -> Result<Self> {
+        Ok(match compression {
+            Compression::Zstd => {
+                Self::Zstd(zstd::stream::write::Encoder::new(Vec::new(), ZSTD_LEVEL)?)
+            }
+            Compression::Deflate => Self::Deflate(flate2::write::DeflateEncoder::new(
+                Vec::new(),
+                flate2::Compression::fast(),
+            )),
+        })
+    }
+
+    pub fn compress(&mut self, data: &[u8]) -> Result<Bytes> {
+        let out = match self {
+            Self::Zstd(encoder) => {
+                encoder.write_all(data)?;
+                encoder.flush()?;
+                std::mem::take(encoder.get_mut())
+            }
+            Self::Deflate(encoder) => {
+                encoder.write_all(data)?;
+                encoder.flush()?;
+                std::mem::take(encoder.get_mut())
+            }
+        };
+        Ok(out.into())
+    }
+}
+
+// Output of one message, writes past the limit fail instead of growing it
+#[derive(Default)]
+pub struct Bounded(Vec<u8>);
+
+impl Write for Bounded {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.0.len() + buf.len() > MAX_DECOMPRESSED {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Message decompresses to over {} bytes", MAX_DECOMPRESSED),
+            ));
+        }
+        self.0.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+pub enum Decompressor {
+    Zstd(zstd::stream::write::Decoder<'static, Bounded>),
+    Deflate(flate2::write::DeflateDecoder<Bounded>),
+}
+
+impl Decompressor {
+    pub fn new(compression: Compression) -> Result<Self> {
+        Ok(match compression {
+            Compression::Zstd => Self::Zstd(zstd::stream::write::Decoder::new(Bounded::default())?),
+            Compression::Deflate => {
+                Self::Deflate(flate2::write::DeflateDecoder::new(Bounded::default()))
+            }
+        })
+    }
+
+    pub fn decompress(&mut self, data: &[u8]) -> Result<Bytes> {
+        let out = match self {
+            Self::Zstd(decoder) => {
+                decoder.write_all(data)?;
+                decoder.flush()?;
+                std::mem::take(&mut decoder.get_mut().0)
+            }
+            Self::Deflate(decoder) => {
+                decoder.write_all(data)?;
+                decoder.flush()?;
+                std::mem::take(&mut decoder.get_mut().0)
+            }
+        };
+        Ok(out.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOTH: [Compression; 2] = [Compression::Zstd, Compression::Deflate];
+
+    #[test]
+    fn messages_round_trip_on_one_stream() {
+        for compression in BOTH {
+            let mut compressor = Compressor::new(compression).unwrap();
+            let mut decompressor = Decompressor::new(compression).unwrap();
+            for message in [&b"hello"[..], &[0x5a; MAX_CHUNK], b"", b"hello again"] {
+                let compressed = compressor.compress(message).unwrap();
+                assert_eq!(&decompressor.decompress(&compressed).unwrap()[..], message);
+            }
+        }
+    }
+
+    #[test]
+    fn bombs_are_rejected() {
+        for compression in BOTH {
+            let mut compressor = Compressor::new(compression).unwrap();
+            let bomb = compressor.compress(&vec![0; MAX_DECOMPRESSED + 1]).unwrap();
+            assert!(bomb.len() < MAX_CHUNK);
+            let mut decompressor = Decompressor::new(compression).unwrap();
+            assert!(decompressor.decompress(&bomb).is_err());
+        }
+    }
+}
@@ -9,12 +9,27 @@ use tokio::net::TcpStream;
 use tokio::sync::mpsc::Receiver;
 use virtual_display::VirtualDisplayManager;
 
-use crate::recording::add_video;
+use crate::asciicast::{
+    list_recordings, read_recording, GetRecordingMsg, RecordingChunk, RecordingInfo,
+};
+use crate::display::{
+    create_display, list_displays, monitor_handle, remove_display, set_display_enabled,
+    update_display, CreateDisplayMsg, DisplayEntry, DisplayIdMsg, UpdateDisplayMsg,
+};
+use crate::fs::{
+    chmod, make_dir, read_dir, real_path, remove, rename, run, stat, FsChmodMsg, FsEntry,
+    FsPathMsg, FsRemoveMsg, FsRenameMsg,
+};
+use crate::input::{negotiate, session_mode, KeyboardInfo, KeyboardMsg};
+use crate::peer::peer_key;
+use crate::recording::{add_video, pause_video, shows_display, stop_video, update_video};
+use crate::screen::{dump, ScreenDump, ScreenDumpMsg};
+use crate::shell::{KillSessionMsg, RenameSessionMsg, Session, SessionInfo};
 use crate::signal::Signaling;
 use crate::state::State;
 use crate::utils::to_json;
 use anyhow::{anyhow, Result};
-use tokio::sync::{broadcast, mpsc};
+use tokio::sync::{broadcast, mpsc, oneshot};
 use ts_rs::TS;
 use webrtc::peer_connection::RTCPeerConnection;
 
@@ -31,6 +46,30 @@ pub enum ControlMsgBody {
     #[default]
     Empty,
     StartVideo(StartVideoMsg),
+    StopVideo(VideoIdMsg),
+    PauseVideo(VideoIdMsg),
+    ResumeVideo(VideoIdMsg),
+    UpdateVideo(UpdateVideoMsg),
+    ListRecordings,
+    GetRecording(GetRecordingMsg),
+    ScreenDump(ScreenDumpMsg),
+    ListSessions,
+    RenameSession(RenameSessionMsg),
+    KillSession(KillSessionMsg),
+    ReadDir(FsPathMsg),
+    Stat(FsPathMsg),
+    MakeDir(FsPathMsg),
+    Rename(FsRenameMsg),
+    Remove(FsRemoveMsg),
+    Chmod(FsChmodMsg),
+    RealPath(FsPathMsg),
+    Keyboard(KeyboardMsg),
+    CreateDisplay(CreateDisplayMsg),
+    UpdateDisplay(UpdateDisplayMsg),
+    EnableDisplay(DisplayIdMsg),
+    DisableDisplay(DisplayIdMsg),
+    RemoveDisplay(DisplayIdMsg),
+    ListDisplays,
 }
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize, TS)]
@@ -44,6 +83,17 @@ pub enum ControlResBody {
     #[default]
     Empty,
     Error(ErrorMsg),
+    Video(VideoStream),
+    Recordings(Vec<RecordingInfo>),
+    Recording(RecordingChunk),
+    Screen(ScreenDump),
+    Sessions(Vec<SessionInfo>),
+    DirEntries(Vec<FsEntry>),
+    Stat(FsEntry),
+    Path(FsPathMsg),
+    Keyboard(KeyboardInfo),
+    Display(DisplayEntry),
+    Displays(Vec<DisplayEntry>),
 }
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize, TS)]
@@ -55,10 +105,34 @@ pub struct ControlResMsg {
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize, TS)]
 pub struct StartVideoMsg {
-    display_id: u32,
-    width: Option<u32>,
-    height: Option<u32>,
-    refresh_rate: Option<u32>,
+    pub display_id: u32,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub refresh_rate: Option<u32>,
+}
+
+// Returned from StartVideo, the id is what the other video messages take.
+// Every display streamed to a peer gets its own track
+#[derive(Debug, Default, Clone, Serialize, Deserialize, TS)]
+pub struct VideoStream {
+    pub id: u32,
+    pub display_id: u32,
+    pub track_id: String,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize, TS)]
+pub struct VideoIdMsg {
+    pub id: u32,
+}
+
+// Unset fields are left alone, bitrate is in kbit/s
+#[derive(Debug, Default, Clone, Serialize, Deserialize, TS)]
+pub struct UpdateVideoMsg {
+    pub id: u32,
+    pub bitrate: Option<u32>,
+    pub fps: Option<u32>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
 }
 
 fn parse_msg(json: Bytes) -> Result<ControlMsg> {
@@ -70,42 +144,172 @@ fn parse_msg(json: Bytes) -> Result<ControlMsg> {
 
 impl<T> State<T>
 where
-    T: Signaling + std::marker::Sync + 'static,
+    T: Signaling + std::marker::Send + std::marker::Sync + 'static,
 {
+    fn find_session(&self, session_id: &str) -> Result<Session> {
+        let map = self.session_map.lock().unwrap();
+        map.get(session_id)
+            .cloned()
+            .ok_or(anyhow!("Session {} not found", session_id))
+    }
+
+    // Only the session's owner may change it
+    fn find_owned_session(&self, pc: &Arc<RTCPeerConnection>, session_id: &str) -> Result<Session> {
+        let session = self.find_session(session_id)?;
+        if !session.owned_by(peer_key(pc)) {
+            return Err(anyhow!("Not the owner of session {}", session_id));
+        }
+        Ok(session)
+    }
+
+    // Reading a session's contents needs a participant in it
+    fn find_joined_session(
+        &self,
+        pc: &Arc<RTCPeerConnection>,
+        session_id: &str,
+    ) -> Result<Session> {
+        let session = self.find_session(session_id)?;
+        if !session.joined_by(peer_key(pc)) {
+            return Err(anyhow!("Not joined to session {}", session_id));
+        }
+        Ok(session)
+    }
+
     async fn control_inner_loop(
-        manager: Arc<VirtualDisplayManager>,
+        self: Arc<Self>,
         pc: Arc<RTCPeerConnection>,
         msg: ControlMsg,
-        mut done_rx: broadcast::Receiver<()>,
+        done_rx: broadcast::Receiver<()>,
     ) -> Result<ControlResBody> {
+        let manager = self.display_manager.clone();
         match msg.body {
             ControlMsgBody::Empty => todo!(),
-            ControlMsgBody::StartVideo(start_video_msg) => {
+            ControlMsgBody::StartVideo(mut start_video_msg) => {
                 dbg!(&"sus");
-                let id = start_video_msg.display_id;
-                manager
+                // An unknown id creates a new display, from here on it's the real one
+                let id = manager
                     .update_display(
-                        id,
+                        start_video_msg.display_id,
                         start_video_msg.width,
                         start_video_msg.height,
                         start_video_msg.refresh_rate,
                     )
                     .await?;
-                let p: Arc<RTCPeerConnection> = pc.clone();
-                let mut done_rx_clone = done_rx.resubscribe();
+                start_video_msg.display_id = id;
+                let monitor = monitor_handle(&manager, id).await?;
+                // The display goes with the stream, whether StopVideo or the
+                // session ending stopped it. Another stream on it keeps it
+                let (stopped_tx, stopped_rx) = oneshot::channel::<()>();
+                let videos = self.videos.clone();
                 tokio::spawn(async move {
-                    if let Err(e) = done_rx_clone.recv().await.map_err(|e| anyhow!(e)).and_then(|_| {
-                        tokio::spawn(async move {
-                            if let Err(e) = manager.remove_display(id).await {
-                                log::error!("Failed to remove display: {}", e);
-                            }
-                        });
-                        Ok(())
-                    }) {
-                        log::error!("Some error idc 1: {}", e);
+                    let _ = stopped_rx.await;
+                    if shows_display(&videos, id) {
+                        return;
+                    }
+                    if let Err(e) = manager.remove_display(id).await {
+                        log::error!("Failed to remove display: {}", e);
                     }
                 });
-                add_video(p, start_video_msg, done_rx).await?;
+                let videos = self.videos.clone();
+                let stream =
+                    add_video(pc, start_video_msg, monitor, videos, done_rx, stopped_tx).await?;
+                return Ok(ControlResBody::Video(stream));
+            }
+            ControlMsgBody::StopVideo(video_id_msg) => {
+                stop_video(&self.videos, &pc, video_id_msg.id).await?;
+            }
+            ControlMsgBody::PauseVideo(video_id_msg) => {
+                pause_video(&self.videos, &pc, video_id_msg.id, true)?;
+            }
+            ControlMsgBody::ResumeVideo(video_id_msg) => {
+                pause_video(&self.videos, &pc, video_id_msg.id, false)?;
+            }
+            ControlMsgBody::UpdateVideo(update_video_msg) => {
+                update_video(&self.videos, &pc, update_video_msg)?;
+            }
+            ControlMsgBody::ListRecordings => {
+                let recordings = list_recordings(&self.settings.recording.dir)?;
+                return Ok(ControlResBody::Recordings(recordings));
+            }
+            ControlMsgBody::GetRecording(get_recording_msg) => {
+                let chunk = read_recording(&self.settings.recording.dir, get_recording_msg)?;
+                return Ok(ControlResBody::Recording(chunk));
+            }
+            ControlMsgBody::ScreenDump(screen_dump_msg) => {
+                let session = self.find_joined_session(&pc, &screen_dump_msg.session_id)?;
+                let screen = session.screen.ok_or(anyhow!("Session has no screen"))?;
+                return Ok(ControlResBody::Screen(dump(&screen)));
+            }
+            ControlMsgBody::ListSessions => {
+                // Only the caller's own sessions, ids of others are enough to join them
+                let peer = peer_key(&pc);
+                let map = self.session_map.lock().unwrap();
+                let mut sessions: Vec<SessionInfo> = map
+                    .iter()
+                    .filter(|(_, session)| session.joined_by(peer))
+                    .map(|(id, session)| session.info(id))
+                    .collect();
+                sessions.sort_by(|a, b| a.created.cmp(&b.created));
+                return Ok(ControlResBody::Sessions(sessions));
+            }
+            ControlMsgBody::RenameSession(rename_session_msg) => {
+                self.find_owned_session(&pc, &rename_session_msg.session_id)?
+                    .rename(rename_session_msg.name);
+            }
+            ControlMsgBody::KillSession(kill_session_msg) => {
+                self.find_owned_session(&pc, &kill_session_msg.session_id)?
+                    .kill();
+            }
+            ControlMsgBody::ReadDir(fs_path_msg) => {
+                let entries = run(&self.settings.files, fs_path_msg, read_dir).await?;
+                return Ok(ControlResBody::DirEntries(entries));
+            }
+            ControlMsgBody::Stat(fs_path_msg) => {
+                let entry = run(&self.settings.files, fs_path_msg, stat).await?;
+                return Ok(ControlResBody::Stat(entry));
+            }
+            ControlMsgBody::MakeDir(fs_path_msg) => {
+                run(&self.settings.files, fs_path_msg, make_dir).await?;
+            }
+            ControlMsgBody::Rename(fs_rename_msg) => {
+                run(&self.settings.files, fs_rename_msg, rename).await?;
+            }
+            ControlMsgBody::Remove(fs_remove_msg) => {
+                run(&self.settings.files, fs_remove_msg, remove).await?;
+            }
+            ControlMsgBody::Chmod(fs_chmod_msg) => {
+                run(&self.settings.files, fs_chmod_msg, chmod).await?;
+            }
+            ControlMsgBody::RealPath(fs_path_msg) => {
+                let path = run(&self.settings.files, fs_path_msg, real_path).await?;
+                return Ok(ControlResBody::Path(path));
+            }
+            ControlMsgBody::Keyboard(keyboard_msg) => {
+                let mode = session_mode(&self.keyboards, &pc);
+                return Ok(ControlResBody::Keyboard(negotiate(&mode, keyboard_msg)));
+            }
+            ControlMsgBody::CreateDisplay(create_display_msg) => {
+                let display = create_display(&manager, create_display_msg).await?;
+                return Ok(ControlResBody::Display(display));
+            }
+            ControlMsgBody::UpdateDisplay(update_display_msg) => {
+                let display = update_display(&manager, update_display_msg).await?;
+                return Ok(ControlResBody::Display(display));
+            }
+            ControlMsgBody::EnableDisplay(display_id_msg) => {
+                let display = set_display_enabled(&manager, display_id_msg, true).await?;
+                return Ok(ControlResBody::Display(display));
+            }
+            ControlMsgBody::DisableDisplay(display_id_msg) => {
+                let display = set_display_enabled(&manager, display_id_msg, false).await?;
+                return Ok(ControlResBody::Display(display));
+            }
+            ControlMsgBody::RemoveDisplay(display_id_msg) => {
+                remove_display(&manager, display_id_msg).await?;
+            }
+            ControlMsgBody::ListDisplays => {
+                let displays = list_displays(&manager).await?;
+                return Ok(ControlResBody::Displays(displays));
             }
         }
 
@@ -119,13 +323,10 @@ where
         mut rx: mpsc::Receiver<Bytes>, // From clients to server
         mut done_rx: broadcast::Receiver<()>,
     ) {
-        let manager = self.display_manager.clone();
-
         let done_rx_copy = done_rx.resubscribe();
 
         tokio::spawn(async move {
             while let Some(json) = rx.recv().await {
-                let manager = manager.clone();
                 let done_rx = done_rx_copy.resubscribe();
                 let parse_res = parse_msg(json);
 
@@ -138,8 +339,10 @@ where
                 };
                 let id = msg.id;
                 dbg!("ok nig");
-                let res =
-                    Self::control_inner_loop(manager, pc.clone(), msg, done_rx.resubscribe()).await;
+                let res = self
+                    .clone()
+                    .control_inner_loop(pc.clone(), msg, done_rx.resubscribe())
+                    .await;
 
                 let res = match res {
                     Ok(res) => res,
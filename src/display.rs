@@ -0,0 +1,264 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+use virtual_display::{DisplayCommand, DisplayInfo, VirtualDisplayManager};
+
+// Unset sizes default to 1920x1080 at 60Hz
+#[derive(Debug, Default, Clone, Serialize, Deserialize, TS)]
+pub struct CreateDisplayMsg {
+    pub name: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub refresh_rate: Option<u32>,
+}
+
+// Unset fields keep their current value, an unknown id creates a new display
+#[derive(Debug, Default, Clone, Serialize, Deserialize, TS)]
+pub struct UpdateDisplayMsg {
+    pub id: u32,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub refresh_rate: Option<u32>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize, TS)]
+pub struct DisplayIdMsg {
+    pub id: u32,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize, TS)]
+pub struct DisplayModeEntry {
+    pub width: u32,
+    pub height: u32,
+    pub refresh_rates: Vec<u32>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize, TS)]
+pub struct DisplayEntry {
+    pub id: u32,
+    pub name: Option<String>,
+    pub enabled: bool,
+    pub modes: Vec<DisplayModeEntry>,
+}
+
+impl From<DisplayInfo> for DisplayEntry {
+    fn from(info: DisplayInfo) -> Self {
+        Self {
+            id: info.id,
+            name: info.name,
+            enabled: info.enabled,
+            modes: info
+                .modes
+                .into_iter()
+                .map(|mode| DisplayModeEntry {
+                    width: mode.width,
+                    height: mode.height,
+                    refresh_rates: mode.refresh_rates,
+                })
+                .collect(),
+        }
+    }
+}
+
+// For the commands that touch exactly one display
+async fn one(manager: &VirtualDisplayManager, command: DisplayCommand) -> Result<DisplayEntry> {
+    manager
+        .handle_command(command)
+        .await?
+        .into_iter()
+        .next()
+        .map(DisplayEntry::from)
+        .ok_or(anyhow!("Display is gone"))
+}
+
+pub async fn create_display(
+    manager: &VirtualDisplayManager,
+    msg: CreateDisplayMsg,
+) -> Result<DisplayEntry> {
+    let command = DisplayCommand::Create {
+        name: msg.name,
+        width: msg.width,
+        height: msg.height,
+        refresh_rate: msg.refresh_rate,
+    };
+    one(manager, command).await
+}
+
+pub async fn update_display(
+    manager: &VirtualDisplayManager,
+    msg: UpdateDisplayMsg,
+) -> Result<DisplayEntry> {
+    let command = DisplayCommand::Update {
+        id: msg.id,
+        width: msg.width,
+        height: msg.height,
+        refresh_rate: msg.refresh_rate,
+    };
+    one(manager, command).await
+}
+
+pub async fn set_display_enabled(
+    manager: &VirtualDisplayManager,
+    msg: DisplayIdMsg,
+    enabled: bool,
+) -> Result<DisplayEntry> {
+    let command = if enabled {
+        DisplayCommand::Enable { id: msg.id }
+    } else {
+        DisplayCommand::Disable { id: msg.id }
+    };
+    one(manager, command).await
+}
+
+pub async fn remove_display(manager: &VirtualDisplayManager, msg: DisplayIdMsg) -> Result<()> {
+    manager
+        .handle_command(DisplayCommand::Remove { id: msg.id })
+        .await?;
+    Ok(())
+}
+
+pub async fn list_displays(manager: &VirtualDisplayManager) -> Result<Vec<DisplayEntry>> {
+    let displays = manager.handle_command(DisplayCommand::List).await?;
+    Ok(displays.into_iter().map(DisplayEntry::from).collect())
+}
+
+// The HMONITOR d3d11screencapturesrc captures the display through. Windows
+// takes a moment to attach a display that was just created or enabled
+pub async fn monitor_handle(manager: &VirtualDisplayManager, id: u32) -> Result<u64> {
+    if !manager.display(id).await?.enabled {
+        return Err(anyhow!("Display {} is not enabled", id));
+    }
+    for _ in 0..20 {
+        if let Some(handle) = attached_monitor(id)? {
+            return Ok(handle);
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    Err(anyhow!("Display {} is not attached", id))
+}
+
+#[cfg(windows)]
+mod monitors {
+    use anyhow::{anyhow, Result};
+    use windows::Win32::Devices::Display::{
+        DisplayConfigGetDeviceInfo, GetDisplayConfigBufferSizes, QueryDisplayConfig,
+        DISPLAYCONFIG_DEVICE_INFO_GET_SOURCE_NAME, DISPLAYCONFIG_DEVICE_INFO_GET_TARGET_NAME,
+        DISPLAYCONFIG_DEVICE_INFO_HEADER, DISPLAYCONFIG_MODE_INFO, DISPLAYCONFIG_PATH_INFO,
+        DISPLAYCONFIG_SOURCE_DEVICE_NAME, DISPLAYCONFIG_TARGET_DEVICE_NAME, QDC_ONLY_ACTIVE_PATHS,
+    };
+    use windows::Win32::Foundation::{BOOL, LPARAM, RECT};
+    use windows::Win32::Graphics::Gdi::{
+        EnumDisplayMonitors, GetMonitorInfoW, HDC, HMONITOR, MONITORINFOEXW,
+    };
+
+    fn wide(name: &[u16]) -> String {
+        let len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+        String::from_utf16_lossy(&name[..len])
+    }
+
+    // GDI names like \\.\DISPLAY3 of the active outputs on connector `id`.
+    // The virtual display driver attaches every display with its id as the
+    // connector index
+    fn device_names(id: u32) -> Result<Vec<String>> {
+        let (mut paths_len, mut modes_len) = (0, 0);
+        unsafe {
+            GetDisplayConfigBufferSizes(QDC_ONLY_ACTIVE_PATHS, &mut paths_len, &mut modes_len)
+        }
+        .ok()?;
+        let mut paths = vec![DISPLAYCONFIG_PATH_INFO::default(); paths_len as usize];
+        let mut modes = vec![DISPLAYCONFIG_MODE_INFO::default(); modes_len as usize];
+        unsafe {
+            QueryDisplayConfig(
+                QDC_ONLY_ACTIVE_PATHS,
+                &mut paths_len,
+                paths.as_mut_ptr(),
+                &mut modes_len,
+                modes.as_mut_ptr(),
+                None,
+            )
+        }
+        .ok()?;
+        paths.truncate(paths_len as usize);
+
+        let mut names = vec![];
+        for path in &paths {
+            let mut target = DISPLAYCONFIG_TARGET_DEVICE_NAME {
+                header: DISPLAYCONFIG_DEVICE_INFO_HEADER {
+                    r#type: DISPLAYCONFIG_DEVICE_INFO_GET_TARGET_NAME,
+                    size: std::mem::size_of::<DISPLAYCONFIG_TARGET_DEVICE_NAME>() as u32,
+                    adapterId: path.targetInfo.adapterId,
+                    id: path.targetInfo.id,
+                },
+                ..Default::default()
+            };
+            if unsafe { DisplayConfigGetDeviceInfo(&mut target.header) } != 0
+                || target.connectorInstance != id
+            {
+                continue;
+            }
+            let mut source = DISPLAYCONFIG_SOURCE_DEVICE_NAME {
+                header: DISPLAYCONFIG_DEVICE_INFO_HEADER {
+                    r#type: DISPLAYCONFIG_DEVICE_INFO_GET_SOURCE_NAME,
+                    size: std::mem::size_of::<DISPLAYCONFIG_SOURCE_DEVICE_NAME>() as u32,
+                    adapterId: path.sourceInfo.adapterId,
+                    id: path.sourceInfo.id,
+                },
+                ..Default::default()
+            };
+            if unsafe { DisplayConfigGetDeviceInfo(&mut source.header) } == 0 {
+                names.push(wide(&source.viewGdiDeviceName));
+            }
+        }
+        Ok(names)
+    }
+
+    unsafe extern "system" fn collect(
+        monitor: HMONITOR,
+        _: HDC,
+        _: *mut RECT,
+        data: LPARAM,
+    ) -> BOOL {
+        let monitors = &mut *(data.0 as *mut Vec<(HMONITOR, String)>);
+        let mut info = MONITORINFOEXW::default();
+        info.monitorInfo.cbSize = std::mem::size_of::<MONITORINFOEXW>() as u32;
+        if GetMonitorInfoW(monitor, &mut info.monitorInfo).as_bool() {
+            monitors.push((monitor, wide(&info.szDevice)));
+        }
+        true.into()
+    }
+
+    // None while the display isn't attached yet, two outputs on the
+    // connector would only be a guess between them
+    pub fn attached_monitor(id: u32) -> Result<Option<u64>> {
+        let names = device_names(id)?;
+        let name = match names.as_slice() {
+            [] => return Ok(None),
+            [name] => name,
+            _ => return Err(anyhow!("More than one output on connector {}", id)),
+        };
+        let mut monitors: Vec<(HMONITOR, String)> = vec![];
+        unsafe {
+            EnumDisplayMonitors(
+                None,
+                None,
+                Some(collect),
+                LPARAM(&mut monitors as *mut _ as isize),
+            )
+        }
+        .ok()?;
+        Ok(monitors
+            .into_iter()
+            .find(|(_, device)| device == name)
+            .map(|(monitor, _)| monitor.0 as u64))
+    }
+}
+
+#[cfg(windows)]
+use monitors::attached_monitor;
+
+#[cfg(not(windows))]
+fn attached_monitor(_: u32) -> Result<Option<u64>> {
+    Err(anyhow!("Screen capture needs Windows"))
+}
@@ -0,0 +1,509 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use bytes::{Bytes, BytesMut};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, SeekFrom};
+use tokio::sync::{broadcast, mpsc, oneshot};
+use ts_rs::TS;
+use webrtc::data_channel::data_channel_message::DataChannelMessage;
+use webrtc::data_channel::RTCDataChannel;
+use webrtc::peer_connection::RTCPeerConnection;
+
+use crate::compression::{Compression, Compressor, Decompressor};
+use crate::peer::DataChannelSettingsMsg;
+use crate::policy::FilePolicy;
+use crate::port::{decode_message, is_eof, read_chunk, PendingAck, MAX_CHUNK};
+use crate::utils::to_json;
+
+// Incoming files are written here first and renamed once the hash matched,
+// the next transfer of the same file continues where this one stopped
+const PART_SUFFIX: &str = ".part";
+
+// The receiving server reports an upload's progress after this many bytes
+const PROGRESS_INTERVAL: u64 = 4 * 1024 * 1024;
+
+// Control messages of a file channel. They are json, the file itself goes
+// as raw chunks between `Start` and an empty EOF message:
+//
+//   download: Download -> Start, chunks, EOF, Done
+//   upload:   Upload -> Start, chunks, EOF, Done -> Progress..., Verified
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub enum FileMsg {
+    // `offset` is how much of the file the client already has
+    Download {
+        path: String,
+        #[ts(type = "number")]
+        offset: u64,
+    },
+    Upload {
+        path: String,
+        #[ts(type = "number")]
+        size: u64,
+    },
+    // Chunks start at `offset`, everything before it is kept from earlier attempts
+    Start {
+        #[ts(type = "number")]
+        size: u64,
+        #[ts(type = "number")]
+        offset: u64,
+    },
+    // Hex SHA-256 of the whole file, sent after EOF
+    Done {
+        sha256: String,
+    },
+    Progress {
+        #[ts(type = "number")]
+        bytes: u64,
+    },
+    Verified,
+    Error {
+        message: String,
+    },
+}
+
+fn encode(msg: &FileMsg) -> Bytes {
+    serde_json::to_vec(msg).unwrap().into()
+}
+
+async fn recv_msg(rx: &mut mpsc::Receiver<Bytes>) -> Result<FileMsg> {
+    let data = rx.recv().await.ok_or(anyhow!("File channel closed"))?;
+    match serde_json::from_slice(&data)? {
+        FileMsg::Error { message } => Err(anyhow!(message)),
+        msg => Ok(msg),
+    }
+}
+
+async fn recv_hash(rx: &mut mpsc::Receiver<Bytes>) -> Result<String> {
+    match recv_msg(rx).await? {
+        FileMsg::Done { sha256 } => Ok(sha256),
+        msg => Err(anyhow!("Expected the file hash, got {:?}", msg)),
+    }
+}
+
+fn part_path(path: &Path) -> PathBuf {
+    let mut part = path.as_os_str().to_owned();
+    part.push(PART_SUFFIX);
+    part.into()
+}
+
+// Hashes the first `len` bytes and leaves the file positioned right after them
+async fn hash_prefix(file: &mut File, len: u64) -> Result<Sha256> {
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; MAX_CHUNK];
+    let mut left = len;
+    file.seek(SeekFrom::Start(0)).await?;
+    while left > 0 {
+        let n = file
+            .read(&mut buffer[..left.min(MAX_CHUNK as u64) as usize])
+            .await?;
+        if n == 0 {
+            return Err(anyhow!("File shrank while hashing"));
+        }
+        hasher.update(&buffer[..n]);
+        left -= n as u64;
+    }
+    Ok(hasher)
+}
+
+// Sends the file from `offset` on, then EOF and the hash of all of it
+async fn send_file(tx: &mpsc::Sender<Bytes>, file: &mut File, offset: u64) -> Result<()> {
+    let mut hasher = hash_prefix(file, offset).await?;
+    let mut buffer = BytesMut::with_capacity(MAX_CHUNK);
+    while read_chunk(file, &mut buffer, MAX_CHUNK).await? > 0 {
+        let chunk = buffer.split().freeze();
+        hasher.update(&chunk);
+        tx.send(chunk).await?;
+    }
+    tx.send(Bytes::new()).await?;
+    let sha256 = format!("{:x}", hasher.finalize());
+    tx.send(encode(&FileMsg::Done { sha256 })).await?;
+    Ok(())
+}
+
+// Appends chunks until EOF, `hasher` already covers the `received` bytes
+// the file held before. Returns the hash of the whole file
+async fn receive_file(
+    rx: &mut mpsc::Receiver<Bytes>,
+    file: &mut File,
+    mut hasher: Sha256,
+    mut received: u64,
+    mut progress: impl FnMut(u64),
+) -> Result<String> {
+    loop {
+        let data = rx.recv().await.ok_or(anyhow!("File channel closed"))?;
+        if is_eof(&data) {
+            break;
+        }
+        file.write_all(&data).await?;
+        hasher.update(&data);
+        received += data.len() as u64;
+        progress(received);
+    }
+    file.flush().await?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+// One download or upload, the channel is closed once it's done
+pub async fn handle_file(
+    tx: mpsc::Sender<Bytes>,       // From server to the client
+    mut rx: mpsc::Receiver<Bytes>, // From clients to server
+    mut done_rx: broadcast::Receiver<()>,
+    policy: FilePolicy,
+) {
+    tokio::select! {
+        res = serve_file(&tx, &mut rx, &policy) => {
+            if let Err(e) = res {
+                log::error!("File transfer failed: {}", e);
+            }
+        }
+        _ = done_rx.recv() => (),
+    }
+
+    log::info!("handle_file exiting");
+}
+
+async fn serve_file(
+    tx: &mpsc::Sender<Bytes>,
+    rx: &mut mpsc::Receiver<Bytes>,
+    policy: &FilePolicy,
+) -> Result<()> {
+    match recv_msg(rx).await? {
+        FileMsg::Download { path, offset } => serve_download(tx, path, offset, policy).await,
+        FileMsg::Upload { path, size } => {
+            // Only control messages flow back during an upload, a failure
+            // can be reported at any point
+            let res = serve_upload(tx, rx, path, size, policy).await;
+            if let Err(e) = &res {
+                let message = e.to_string();
+                let _ = tx.send(encode(&FileMsg::Error { message })).await;
+            }
+            res
+        }
+        msg => Err(anyhow!("Unexpected file message {:?}", msg)),
+    }
+}
+
+async fn open_download(path: &str, policy: &FilePolicy) -> Result<(PathBuf, File, u64)> {
+    let path = policy.check(Path::new(path))?;
+    let file = File::open(&path).await?;
+    let size = file.metadata().await?.len();
+    Ok((path, file, size))
+}
+
+async fn serve_download(
+    tx: &mpsc::Sender<Bytes>,
+    path: String,
+    offset: u64,
+    policy: &FilePolicy,
+) -> Result<()> {
+    let (path, mut file, size) = match open_download(&path, policy).await {
+        Ok(opened) => opened,
+        Err(e) => {
+            let message = e.to_string();
+            tx.send(encode(&FileMsg::Error { message })).await?;
+            return Err(e);
+        }
+    };
+    // A partial copy longer than the file is from some other file, start over
+    let offset = if offset > size { 0 } else { offset };
+
+    log::info!(
+        "Sending {} from {} of {} bytes",
+        path.display(),
+        offset,
+        size
+    );
+    tx.send(encode(&FileMsg::Start { size, offset })).await?;
+    send_file(tx, &mut file, offset).await
+}
+
+async fn serve_upload(
+    tx: &mpsc::Sender<Bytes>,
+    rx: &mut mpsc::Receiver<Bytes>,
+    path: String,
+    size: u64,
+    policy: &FilePolicy,
+) -> Result<()> {
+    let path = policy.check(Path::new(&path))?;
+    // A symlink planted at the partial file would redirect the write
+    let part = policy.check_entry(&part_path(&path))?;
+    if fs::symlink_metadata(&part)
+        .await
+        .is_ok_and(|metadata| metadata.is_symlink())
+    {
+        return Err(anyhow!("{} is a symlink", part.display()));
+    }
+    let mut options = OpenOptions::new();
+    options.create(true).truncate(false).read(true).write(true);
+    #[cfg(unix)]
+    options.custom_flags(libc::O_NOFOLLOW);
+    let mut file = options.open(&part).await?;
+    let mut offset = file.metadata().await?.len();
+    if offset > size {
+        file.set_len(0).await?;
+        offset = 0;
+    }
+    let hasher = hash_prefix(&mut file, offset).await?;
+
+    log::info!(
+        "Receiving {} from {} of {} bytes",
+        path.display(),
+        offset,
+        size
+    );
+    tx.send(encode(&FileMsg::Start { size, offset })).await?;
+
+    let mut next_progress = offset + PROGRESS_INTERVAL;
+    let sha256 = receive_file(rx, &mut file, hasher, offset, |received| {
+        if received >= next_progress {
+            let _ = tx.try_send(encode(&FileMsg::Progress { bytes: received }));
+            next_progress = received + PROGRESS_INTERVAL;
+        }
+    })
+    .await?;
+    let expected = recv_hash(rx).await?;
+    if sha256 != expected {
+        fs::remove_file(&part).await?;
+        return Err(anyhow!("SHA-256 mismatch on {}", path.display()));
+    }
+
+    fs::rename(&part, &path).await?;
+    tx.send(encode(&FileMsg::Verified)).await?;
+    log::info!("Received {}", path.display());
+    Ok(())
+}
+
+// Client end of a file, sync or clipboard channel. Messages go through the same mpsc
+// pair as on the server so both sides share the transfer code
+pub struct FileChannel {
+    pub d: Arc<RTCDataChannel>,
+    pub tx: mpsc::Sender<Bytes>,
+    pub rx: mpsc::Receiver<Bytes>,
+}
+
+impl FileChannel {
+    pub async fn open(
+        peer_connection: &Arc<RTCPeerConnection>,
+        variant: &str,
+        compression: Option<Compression>,
+    ) -> Result<Self> {
+        let label = to_json(DataChannelSettingsMsg {
+            variant: variant.into(),
+            compression: compression.map(|compression| vec![compression]),
+            ..Default::default()
+        })?;
+        let d = peer_connection.create_data_channel(&label, None).await?;
+
+        let (in_tx, rx) = mpsc::channel::<Bytes>(100);
+        let (tx, mut out_rx) = mpsc::channel::<Bytes>(100);
+        let (open_tx, mut open_rx) = mpsc::channel::<()>(1);
+        let (closed_tx, mut closed_rx) = mpsc::channel::<()>(1);
+        let (ack_tx, ack_rx) = oneshot::channel::<Option<Compression>>();
+        let pending_ack: Arc<PendingAck> =
+            Arc::new(std::sync::Mutex::new(compression.map(|_| ack_tx)));
+        let decompressor = Arc::new(std::sync::Mutex::new(None::<Decompressor>));
+
+        // Dropping the sender on close ends whatever waits on `rx`
+        let in_tx = Arc::new(std::sync::Mutex::new(Some(in_tx)));
+        let in_tx2 = in_tx.clone();
+        d.on_close(Box::new(move || {
+            in_tx2.lock().unwrap().take();
+            let _ = closed_tx.try_send(());
+            Box::pin(async {})
+        }));
+        d.on_message(Box::new(move |msg: DataChannelMessage| {
+            let in_tx = in_tx.lock().unwrap().clone();
+            let data = decode_message(&pending_ack, &decompressor, msg.data);
+            Box::pin(async move {
+                match (in_tx, data) {
+                    (Some(in_tx), Result::Ok(Some(data))) => {
+                        let _ = in_tx.send(data).await;
+                    }
+                    (_, Err(e)) => log::error!("Failed to decode message: {}", e),
+                    _ => (),
+                }
+            })
+        }));
+        d.on_open(Box::new(move || {
+            let _ = open_tx.try_send(());
+            Box::pin(async {})
+        }));
+
+        tokio::select! {
+            _ = open_rx.recv() => (),
+            _ = closed_rx.recv() => return Err(anyhow!("File channel closed before it opened")),
+        }
+        // Without an offer the sender is dropped and nothing is negotiated
+        let negotiated = tokio::select! {
+            negotiated = ack_rx => negotiated.ok().flatten(),
+            _ = closed_rx.recv() => return Err(anyhow!("File channel closed before the ack")),
+        };
+        log::info!("{} channel compression {:?}", variant, negotiated);
+        let mut compressor = negotiated.map(Compressor::new).transpose()?;
+
+        let d2 = d.clone();
+        tokio::spawn(async move {
+            while let Some(data) = out_rx.recv().await {
+                let data = match compressor.as_mut() {
+                    Some(compressor) if !is_eof(&data) => match compressor.compress(&data) {
+                        Result::Ok(data) => data,
+                        Err(e) => {
+                            log::error!("Failed to compress message: {}", e);
+                            break;
+                        }
+                    },
+                    _ => data,
+                };
+                if let Err(e) = d2.send(&data).await {
+                    log::error!("File channel send error: {}", e);
+                    break;
+                }
+            }
+        });
+
+        Ok(Self { d, tx, rx })
+    }
+}
+
+// Sends the request and waits for the server to say where the transfer starts
+async fn start(
+    tx: &mpsc::Sender<Bytes>,
+    rx: &mut mpsc::Receiver<Bytes>,
+    request: FileMsg,
+) -> Result<(u64, u64)> {
+    tx.send(encode(&request)).await?;
+    match recv_msg(rx).await? {
+        FileMsg::Start { size, offset } => Ok((size, offset)),
+        msg => Err(anyhow!("Unexpected file message {:?}", msg)),
+    }
+}
+
+// One line on stderr, redrawn whenever the percentage changes
+struct Progress<'a> {
+    name: &'a str,
+    size: u64,
+    percent: Option<u64>,
+}
+
+impl<'a> Progress<'a> {
+    fn new(name: &'a str, size: u64) -> Self {
+        Self {
+            name,
+            size,
+            percent: None,
+        }
+    }
+
+    fn update(&mut self, bytes: u64) {
+        let percent = match self.size {
+            0 => 100,
+            size => bytes * 100 / size,
+        };
+        if self.percent != Some(percent) {
+            self.percent = Some(percent);
+            eprint!(
+                "\r{}  {}/{} bytes  {}%",
+                self.name, bytes, self.size, percent
+            );
+        }
+    }
+
+    fn finish(&mut self) {
+        self.update(self.size);
+        eprintln!();
+    }
+}
+
+// Copies `remote` on the server to `local`, continuing an earlier partial copy
+pub async fn download(
+    peer_connection: &Arc<RTCPeerConnection>,
+    remote: &str,
+    local: &Path,
+    compression: Option<Compression>,
+) -> Result<()> {
+    let part = part_path(local);
+    let mut file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .read(true)
+        .write(true)
+        .open(&part)
+        .await?;
+    let have = file.metadata().await?.len();
+
+    let FileChannel { d, tx, mut rx } =
+        FileChannel::open(peer_connection, "file", compression).await?;
+    let request = FileMsg::Download {
+        path: remote.into(),
+        offset: have,
+    };
+    let (size, offset) = start(&tx, &mut rx, request).await?;
+    if offset > 0 {
+        log::info!("Resuming {} at {} of {} bytes", remote, offset, size);
+    }
+    file.set_len(offset).await?;
+    let hasher = hash_prefix(&mut file, offset).await?;
+
+    let mut progress = Progress::new(remote, size);
+    let sha256 = receive_file(&mut rx, &mut file, hasher, offset, |received| {
+        progress.update(received)
+    })
+    .await?;
+    let expected = recv_hash(&mut rx).await?;
+    d.close().await?;
+    if sha256 != expected {
+        fs::remove_file(&part).await?;
+        return Err(anyhow!("SHA-256 mismatch on {}", remote));
+    }
+
+    fs::rename(&part, local).await?;
+    progress.finish();
+    Ok(())
+}
+
+async fn wait_verified(rx: &mut mpsc::Receiver<Bytes>, progress: &mut Progress<'_>) -> Result<()> {
+    loop {
+        match recv_msg(rx).await? {
+            FileMsg::Progress { bytes } => progress.update(bytes),
+            FileMsg::Verified => return Ok(()),
+            msg => return Err(anyhow!("Unexpected file message {:?}", msg)),
+        }
+    }
+}
+
+// Copies `local` to `remote` on the server, the server keeps partial
+// uploads and tells where to continue
+pub async fn upload(
+    peer_connection: &Arc<RTCPeerConnection>,
+    local: &Path,
+    remote: &str,
+    compression: Option<Compression>,
+) -> Result<()> {
+    let mut file = File::open(local).await?;
+    let size = file.metadata().await?.len();
+
+    let FileChannel { d, tx, mut rx } =
+        FileChannel::open(peer_connection, "file", compression).await?;
+    let request = FileMsg::Upload {
+        path: remote.into(),
+        size,
+    };
+    let (_, offset) = start(&tx, &mut rx, request).await?;
+    if offset > 0 {
+        log::info!("Resuming {} at {} of {} bytes", remote, offset, size);
+    }
+
+    let mut progress = Progress::new(remote, size);
+    let sending = send_file(&tx, &mut file, offset);
+    let verified = wait_verified(&mut rx, &mut progress);
+    tokio::try_join!(sending, verified)?;
+    d.close().await?;
+
+    progress.finish();
+    Ok(())
+}
@@ -0,0 +1,205 @@
+use std::fmt::Display;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::compression::Compression;
+use crate::policy::UNIX_PREFIX;
+use crate::utils::load_config;
+
+const DEFAULT_BIND: &str = "127.0.0.1";
+
+// Splits on ':' but keeps bracketed ipv6 addresses and `unix:/path` together
+fn split_spec(spec: &str) -> Result<Vec<String>> {
+    let mut parts = vec![];
+    let mut current = String::new();
+    let mut bracket = false;
+    for c in spec.chars() {
+        match c {
+            '[' => bracket = true,
+            ']' => bracket = false,
+            ':' if !bracket => parts.push(std::mem::take(&mut current)),
+            c => current.push(c),
+        }
+    }
+    if bracket {
+        return Err(anyhow!("Unclosed bracket in {}", spec));
+    }
+    parts.push(current);
+
+    let mut merged: Vec<String> = vec![];
+    for part in parts {
+        match merged.last_mut() {
+            Some(last) if last == "unix" && part.starts_with('/') => {
+                last.push(':');
+                last.push_str(&part);
+            }
+            _ => merged.push(part),
+        }
+    }
+    Ok(merged)
+}
+
+fn is_unix(part: &str) -> bool {
+    part.starts_with(UNIX_PREFIX)
+}
+
+fn join_host_port(host: &str, port: &str) -> String {
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+// `unix:/path`, `port` or `bind_addr:port`
+fn parse_bind(parts: &[String]) -> Result<String> {
+    let (bind_addr, port) = match parts {
+        [unix] if is_unix(unix) => return Ok(unix.clone()),
+        [port] => (DEFAULT_BIND, port),
+        [bind_addr, port] => (bind_addr.as_str(), port),
+        _ => return Err(anyhow!("expected [bind_addr:]port or unix:/path")),
+    };
+    port.parse::<u16>().context("Invalid port")?;
+    let bind_addr = if bind_addr.is_empty() || bind_addr == "*" {
+        "0.0.0.0"
+    } else {
+        bind_addr
+    };
+    Ok(join_host_port(bind_addr, port))
+}
+
+// `unix:/path` or `host:hostport`
+fn parse_target(parts: &[String]) -> Result<String> {
+    match parts {
+        [unix] if is_unix(unix) => Ok(unix.clone()),
+        [host, hostport] => {
+            hostport.parse::<u16>().context("Invalid host port")?;
+            Ok(join_host_port(host, hostport))
+        }
+        _ => Err(anyhow!("expected host:hostport or unix:/path")),
+    }
+}
+
+// `-L [bind_addr:]port:host:hostport`, like ssh, either side can be `unix:/path`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LocalForward {
+    pub bind: String,
+    pub target: String,
+}
+
+impl FromStr for LocalForward {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parts = split_spec(s)?;
+        let target_len = if parts.last().is_some_and(|part| is_unix(part)) {
+            1
+        } else {
+            2
+        };
+        if parts.len() <= target_len {
+            return Err(anyhow!(
+                "Forward {} must be [bind_addr:]port:host:hostport",
+                s
+            ));
+        }
+        let (bind, target) = parts.split_at(parts.len() - target_len);
+        Ok(Self {
+            bind: parse_bind(bind).with_context(|| format!("Invalid forward {}", s))?,
+            target: parse_target(target).with_context(|| format!("Invalid forward {}", s))?,
+        })
+    }
+}
+
+// `-R` uses the same spec, the server listens on `bind` and the client dials `target`
+pub type RemoteForward = LocalForward;
+
+// `-U` forwards datagrams from a local udp port instead of tcp connections
+pub type UdpForward = LocalForward;
+
+// `--http` serves a local port whose requests go to a web app next to the server
+pub type HttpForward = LocalForward;
+
+impl Display for LocalForward {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} -> {}", self.bind, self.target)
+    }
+}
+
+// `-D [bind_addr:]port` or `-D unix:/path`, a local SOCKS5 / HTTP CONNECT proxy
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DynamicForward {
+    pub bind: String,
+}
+
+impl FromStr for DynamicForward {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parts = split_spec(s)?;
+        Ok(Self {
+            bind: parse_bind(&parts).with_context(|| format!("Invalid dynamic forward {}", s))?,
+        })
+    }
+}
+
+impl Display for DynamicForward {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} -> socks", self.bind)
+    }
+}
+
+// Optional json config for the client, cli arguments are added on top
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClientConfig {
+    pub server: Option<String>,
+    pub url: Option<String>,
+    pub compress: Option<Compression>,
+    pub forwards: Vec<LocalForward>,
+    pub remote_forwards: Vec<RemoteForward>,
+    pub dynamic_forwards: Vec<DynamicForward>,
+    pub udp_forwards: Vec<UdpForward>,
+    pub http_forwards: Vec<HttpForward>,
+}
+
+impl ClientConfig {
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        load_config(path)
+    }
+}
+
+// Connection counters of one forward, only used for logging
+#[derive(Default)]
+pub struct ForwardStats {
+    active: AtomicUsize,
+    total: AtomicUsize,
+}
+
+impl ForwardStats {
+    pub fn opened(&self, forward: &impl Display) {
+        let active = self.active.fetch_add(1, Ordering::Relaxed) + 1;
+        let total = self.total.fetch_add(1, Ordering::Relaxed) + 1;
+        log::info!(
+            "[{}] connection opened (active {}, total {})",
+            forward,
+            active,
+            total
+        );
+    }
+
+    pub fn closed(&self, forward: &impl Display) {
+        let active = self.active.fetch_sub(1, Ordering::Relaxed) - 1;
+        let total = self.total.load(Ordering::Relaxed);
+        log::info!(
+            "[{}] connection closed (active {}, total {})",
+            forward,
+            active,
+            total
+        );
+    }
+}
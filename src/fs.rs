@@ -0,0 +1,197 @@
+use std::fs::{self, Metadata};
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use crate::policy::FilePolicy;
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize, TS)]
+pub struct FsPathMsg {
+    pub path: String,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize, TS)]
+pub struct FsRenameMsg {
+    pub from: String,
+    pub to: String,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize, TS)]
+pub struct FsRemoveMsg {
+    pub path: String,
+    // Directories are only removed with their contents when set
+    pub recursive: bool,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize, TS)]
+pub struct FsChmodMsg {
+    pub path: String,
+    // Permission bits like 0o755, anything above 0o7777 is ignored
+    pub mode: u32,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize, TS)]
+pub enum FsKind {
+    #[default]
+    File,
+    Dir,
+    Symlink,
+    Other,
+}
+
+// Symlinks are described as themselves, not their targets
+#[derive(Debug, Default, Clone, Serialize, Deserialize, TS)]
+pub struct FsEntry {
+    pub name: String,
+    pub path: String,
+    pub kind: FsKind,
+    #[ts(type = "number")]
+    pub size: u64,
+    pub mode: u32,
+    pub modified: String,
+}
+
+#[cfg(unix)]
+fn mode(meta: &Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    meta.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn mode(meta: &Metadata) -> u32 {
+    if meta.permissions().readonly() {
+        0o444
+    } else {
+        0o644
+    }
+}
+
+fn entry(path: &Path, meta: &Metadata) -> Result<FsEntry> {
+    let file_type = meta.file_type();
+    let kind = if file_type.is_symlink() {
+        FsKind::Symlink
+    } else if file_type.is_dir() {
+        FsKind::Dir
+    } else if file_type.is_file() {
+        FsKind::File
+    } else {
+        FsKind::Other
+    };
+    let modified: DateTime<Utc> = meta.modified()?.into();
+    Ok(FsEntry {
+        name: path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default(),
+        path: path.to_string_lossy().to_string(),
+        kind,
+        size: meta.len(),
+        mode: mode(meta),
+        modified: modified.to_rfc3339(),
+    })
+}
+
+// Directories first, then by name
+pub fn read_dir(policy: &FilePolicy, msg: FsPathMsg) -> Result<Vec<FsEntry>> {
+    let dir = policy.check(Path::new(&msg.path))?;
+    let mut entries = vec![];
+    for dir_entry in fs::read_dir(&dir)? {
+        let dir_entry = dir_entry?;
+        let meta = fs::symlink_metadata(dir_entry.path())?;
+        entries.push(entry(&dir_entry.path(), &meta)?);
+    }
+    entries.sort_by(|a, b| {
+        let a_dir = matches!(a.kind, FsKind::Dir);
+        let b_dir = matches!(b.kind, FsKind::Dir);
+        b_dir.cmp(&a_dir).then_with(|| a.name.cmp(&b.name))
+    });
+    Ok(entries)
+}
+
+pub fn stat(policy: &FilePolicy, msg: FsPathMsg) -> Result<FsEntry> {
+    let path = policy.check_entry(Path::new(&msg.path))?;
+    entry(&path, &fs::symlink_metadata(&path)?)
+}
+
+pub fn make_dir(policy: &FilePolicy, msg: FsPathMsg) -> Result<()> {
+    let path = policy.check_entry(Path::new(&msg.path))?;
+    fs::create_dir(path)?;
+    Ok(())
+}
+
+pub fn rename(policy: &FilePolicy, msg: FsRenameMsg) -> Result<()> {
+    let from = policy.check_entry(Path::new(&msg.from))?;
+    let to = policy.check_entry(Path::new(&msg.to))?;
+    // Renaming onto a root would replace it just like renaming it away
+    for path in [&from, &to] {
+        if policy.is_root(path) {
+            return Err(anyhow!("{} is a root directory", path.display()));
+        }
+    }
+    fs::rename(from, to)?;
+    Ok(())
+}
+
+pub fn remove(policy: &FilePolicy, msg: FsRemoveMsg) -> Result<()> {
+    let path = policy.check_entry(Path::new(&msg.path))?;
+    if policy.is_root(&path) {
+        return Err(anyhow!("{} is a root directory", path.display()));
+    }
+    // A symlink to a directory is removed as a link
+    if fs::symlink_metadata(&path)?.is_dir() {
+        if msg.recursive {
+            fs::remove_dir_all(path)?;
+        } else {
+            fs::remove_dir(path)?;
+        }
+    } else {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+pub fn chmod(policy: &FilePolicy, msg: FsChmodMsg) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let path = policy.check(Path::new(&msg.path))?;
+    fs::set_permissions(path, fs::Permissions::from_mode(msg.mode & 0o7777))?;
+    Ok(())
+}
+
+// Only the owner write bit maps to something, the readonly flag
+#[cfg(not(unix))]
+pub fn chmod(policy: &FilePolicy, msg: FsChmodMsg) -> Result<()> {
+    let path = policy.check(Path::new(&msg.path))?;
+    let mut permissions = fs::metadata(&path)?.permissions();
+    permissions.set_readonly(msg.mode & 0o200 == 0);
+    fs::set_permissions(path, permissions)?;
+    Ok(())
+}
+
+// Unlike the other calls the path has to exist. The policy goes first so
+// paths outside of it all fail the same, whether they exist or not
+pub fn real_path(policy: &FilePolicy, msg: FsPathMsg) -> Result<FsPathMsg> {
+    let path = policy.check(Path::new(&msg.path))?;
+    let path = fs::canonicalize(path)?;
+    Ok(FsPathMsg {
+        path: path.to_string_lossy().to_string(),
+    })
+}
+
+// Runs one of the calls above off the async workers, they block on the
+// disk and a recursive remove can take a long while
+pub async fn run<M, T>(
+    policy: &FilePolicy,
+    msg: M,
+    call: fn(&FilePolicy, M) -> Result<T>,
+) -> Result<T>
+where
+    M: Send + 'static,
+    T: Send + 'static,
+{
+    let policy = policy.clone();
+    tokio::task::spawn_blocking(move || call(&policy, msg)).await?
+}
@@ -0,0 +1,207 @@
+use anyhow::{anyhow, Result};
+use bytes::{Bytes, BytesMut};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, mpsc};
+
+use crate::policy::{HttpPolicy, Target};
+use crate::port::{is_eof, pump};
+
+const MAX_HEAD: usize = 64 * 1024;
+
+struct RequestHead {
+    method: String,
+    path: String,
+    version: String,
+    headers: Vec<(String, String)>,
+}
+
+impl RequestHead {
+    fn parse(head: &str) -> Result<Self> {
+        let mut lines = head.split("\r\n").filter(|line| !line.is_empty());
+        let request_line = lines.next().ok_or(anyhow!("Empty request"))?;
+        let mut parts = request_line.split_whitespace();
+        let (Some(method), Some(path), Some(version)) = (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(anyhow!("Invalid request line {}", request_line));
+        };
+        let headers = lines
+            .map(|line| {
+                let (name, value) = line
+                    .split_once(':')
+                    .ok_or(anyhow!("Invalid header {}", line))?;
+                Ok((name.trim().to_owned(), value.trim().to_owned()))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            method: method.to_owned(),
+            path: path.to_owned(),
+            version: version.to_owned(),
+            headers,
+        })
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    // Only a websocket handshake may keep the connection open, any other
+    // Upgrade header would let later requests past the allowlist
+    fn upgrade(&self) -> bool {
+        self.header("connection").is_some_and(|connection| {
+            connection
+                .split(',')
+                .any(|token| token.trim().eq_ignore_ascii_case("upgrade"))
+        }) && self
+            .header("upgrade")
+            .is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket"))
+    }
+
+    // Host points at the app, and every request gets its own channel so
+    // keep-alive is turned off unless the connection is being upgraded
+    fn rewrite(&self, target: &Target) -> String {
+        let upgrade = self.upgrade();
+        let mut head = format!("{} {} {}\r\n", self.method, self.path, self.version);
+        for (name, value) in &self.headers {
+            match name.to_ascii_lowercase().as_str() {
+                "host" => continue,
+                "connection" | "keep-alive" | "proxy-connection" if !upgrade => continue,
+                _ => head.push_str(&format!("{}: {}\r\n", name, value)),
+            }
+        }
+        head.push_str(&format!("Host: {}\r\n", target));
+        if let Some(host) = self.header("host") {
+            head.push_str(&format!("X-Forwarded-Host: {}\r\n", host));
+        }
+        if !upgrade {
+            head.push_str("Connection: close\r\n");
+        }
+        head.push_str("\r\n");
+        head
+    }
+}
+
+// Collects the request head, returns it together with the body bytes that came along
+async fn read_head(rx: &mut mpsc::Receiver<Bytes>) -> Result<(String, Bytes)> {
+    let mut buffer = BytesMut::new();
+    loop {
+        if let Some(end) = buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+            let body = buffer.split_off(end + 4).freeze();
+            return Ok((String::from_utf8(buffer.to_vec())?, body));
+        }
+        if buffer.len() > MAX_HEAD {
+            return Err(anyhow!("Request head too large"));
+        }
+        match rx.recv().await {
+            Some(data) if !is_eof(&data) => buffer.extend_from_slice(&data),
+            _ => return Err(anyhow!("Channel ended before the request head")),
+        }
+    }
+}
+
+// Reads the app's answer to an upgrade up to the end of its head, returns
+// everything read so far and whether the app switched protocols
+async fn read_response(stream: &mut TcpStream) -> Result<(Bytes, bool)> {
+    let mut buffer = BytesMut::new();
+    loop {
+        if let Some(end) = buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+            let head = std::str::from_utf8(&buffer[..end])?;
+            let switched = head.split_whitespace().nth(1) == Some("101");
+            return Ok((buffer.freeze(), switched));
+        }
+        if buffer.len() > MAX_HEAD {
+            return Err(anyhow!("Response head too large"));
+        }
+        if stream.read_buf(&mut buffer).await? == 0 {
+            return Err(anyhow!("Connection closed before the response head"));
+        }
+    }
+}
+
+async fn respond(tx: &mpsc::Sender<Bytes>, status: u16, reason: &str) {
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}\n",
+        status,
+        reason,
+        reason.len() + 1,
+        reason
+    );
+    let _ = tx.send(response.into()).await;
+    let _ = tx.send(Bytes::new()).await;
+}
+
+// One request (or one upgraded connection) to a web app on the server side,
+// checked against the route allowlist before anything reaches the app
+pub async fn handle_http(
+    tx: mpsc::Sender<Bytes>,       // From server to the client
+    mut rx: mpsc::Receiver<Bytes>, // From clients to server
+    done_rx: broadcast::Receiver<()>,
+    target: Target,
+    policy: HttpPolicy,
+    max_chunk: usize,
+) {
+    let (head, body) = match read_head(&mut rx).await {
+        Ok(head) => head,
+        Err(e) => {
+            log::error!("Failed to read http request: {}", e);
+            return respond(&tx, 400, "Bad Request").await;
+        }
+    };
+    let request = match RequestHead::parse(&head) {
+        Ok(request) => request,
+        Err(e) => {
+            log::error!("Failed to parse http request: {}", e);
+            return respond(&tx, 400, "Bad Request").await;
+        }
+    };
+    if !policy.allows(&target, &request.method, &request.path) {
+        log::warn!("Refusing {} {} on {}", request.method, request.path, target);
+        return respond(&tx, 403, "Forbidden").await;
+    }
+
+    let mut stream = match TcpStream::connect((target.host.as_str(), target.port)).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            log::error!("Failed to connect to {}: {}", target, e);
+            return respond(&tx, 502, "Bad Gateway").await;
+        }
+    };
+    log::info!("{} {} -> {}", request.method, request.path, target);
+    let head = request.rewrite(&target);
+    if let Err(e) = stream.write_all(head.as_bytes()).await {
+        log::error!("Failed to send request to {}: {}", target, e);
+        return respond(&tx, 502, "Bad Gateway").await;
+    }
+    if let Err(e) = stream.write_all(&body).await {
+        log::error!("Failed to send request to {}: {}", target, e);
+        return respond(&tx, 502, "Bad Gateway").await;
+    }
+
+    let rx = if request.upgrade() {
+        let (response, switched) = match read_response(&mut stream).await {
+            Ok(response) => response,
+            Err(e) => {
+                log::error!("Failed to read upgrade response from {}: {}", target, e);
+                return respond(&tx, 502, "Bad Gateway").await;
+            }
+        };
+        if tx.send(response).await.is_err() {
+            return;
+        }
+        if switched {
+            rx
+        } else {
+            // The app declined, the rest of its answer still goes back but
+            // nothing more from the client reaches it
+            log::warn!("{} did not switch protocols for {}", target, request.path);
+            mpsc::channel(1).1
+        }
+    } else {
+        rx
+    };
+
+    pump(tx, rx, done_rx, Box::new(stream), &target, max_chunk).await;
+}
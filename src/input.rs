@@ -0,0 +1,1005 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, mpsc};
+use ts_rs::TS;
+use webrtc::peer_connection::RTCPeerConnection;
+
+use crate::peer::peer_key;
+use crate::port::is_eof;
+use crate::settings::InputSettings;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, TS)]
+pub enum MouseButton {
+    Left,
+    Right,
+    Middle,
+    Back,
+    Forward,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+pub enum InputEvent {
+    // Fractions of the captured display, 0,0 is its top left and 1,1 its bottom right
+    PointerMove { x: f64, y: f64 },
+    // Pixels, for pointer lock
+    PointerMoveBy { dx: i32, dy: i32 },
+    Button { button: MouseButton, down: bool },
+    // 120 per notch like WHEEL_DELTA, positive scrolls down and right as in the DOM
+    Wheel { dx: i32, dy: i32 },
+    // PC set 1 scancode, extended keys as 0xe0xx
+    Key { scancode: u16, down: bool },
+    // Typed characters and IME commits, injected as unicode whatever the
+    // server's layout is
+    Text { text: String },
+}
+
+// How key events of a session are meant. Physical keys go through the
+// server's layout as they are, fine while both sides use the same one. With
+// typed text the client sends characters as Text and keys that would type
+// something are dropped unless they're part of a shortcut
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, TS)]
+pub enum InputMode {
+    #[default]
+    PhysicalKeys,
+    TypedText,
+}
+
+// Sent on the control channel. A missing mode is picked by comparing the
+// client's layout with the server's
+#[derive(Debug, Default, Clone, Serialize, Deserialize, TS)]
+pub struct KeyboardMsg {
+    pub layout: Option<String>,
+    pub mode: Option<InputMode>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize, TS)]
+pub struct KeyboardInfo {
+    // xkb layout like "de(nodeadkeys)" on Linux, a KLID like "00000407" on Windows
+    pub layout: Option<String>,
+    pub mode: InputMode,
+}
+
+// Input mode of every peer connection, shared by its control and input channels
+pub type KeyboardMap = Arc<Mutex<HashMap<usize, Arc<Mutex<InputMode>>>>>;
+
+pub fn session_mode(keyboards: &KeyboardMap, pc: &Arc<RTCPeerConnection>) -> Arc<Mutex<InputMode>> {
+    keyboards
+        .lock()
+        .unwrap()
+        .entry(peer_key(pc))
+        .or_default()
+        .clone()
+}
+
+pub fn forget_session(keyboards: &KeyboardMap, pc: &Arc<RTCPeerConnection>) {
+    keyboards.lock().unwrap().remove(&peer_key(pc));
+}
+
+pub fn negotiate(mode: &Mutex<InputMode>, msg: KeyboardMsg) -> KeyboardInfo {
+    let layout = server_layout();
+    let chosen = msg.mode.or_else(|| {
+        let (client, server) = (msg.layout.as_ref()?, layout.as_ref()?);
+        Some(if client.eq_ignore_ascii_case(server) {
+            InputMode::PhysicalKeys
+        } else {
+            InputMode::TypedText
+        })
+    });
+    let mut mode = mode.lock().unwrap();
+    if let Some(chosen) = chosen {
+        *mode = chosen;
+    }
+    KeyboardInfo {
+        layout,
+        mode: *mode,
+    }
+}
+
+// Keys of the main block that type a character in any layout. The keypad is
+// left alone, without num lock it's navigation
+fn is_character_key(scancode: u16) -> bool {
+    matches!(
+        scancode,
+        0x02..=0x0d | 0x10..=0x1b | 0x1e..=0x29 | 0x2b..=0x35 | 0x39 | 0x56
+    )
+}
+
+// Ctrl, left Alt and the Windows keys. AltGr is missing on purpose, with it
+// held a key still types a character
+fn is_shortcut_modifier(scancode: u16) -> bool {
+    matches!(scancode, 0x1d | 0xe01d | 0x38 | 0xe05b | 0xe05c)
+}
+
+// Per event and axis, anything beyond is cut off. X11 clicks notch by notch
+// and Windows takes the delta as a u32, a huge one is only ever a bad client
+const MAX_WHEEL: i32 = 10 * 120;
+
+// One message of an input channel. The channel is meant to be opened
+// unordered but reliable, a pointer move shouldn't wait for a lost one while
+// no key up may go missing. `seq` counts up by one per message so events that
+// overtook each other can be put right
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct InputMsg {
+    #[ts(type = "number")]
+    pub seq: u64,
+    pub event: InputEvent,
+}
+
+// Where the captured display sits on the desktop, in pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    fn point(&self, x: f64, y: f64) -> (i32, i32) {
+        let span = |from: i32, len: u32, fraction: f64| {
+            from.saturating_add(
+                (fraction.clamp(0.0, 1.0) * len.saturating_sub(1) as f64).round() as i32,
+            )
+        };
+        (span(self.x, self.width, x), span(self.y, self.height, y))
+    }
+}
+
+// What actually moves the pointer and presses keys. Positions are desktop
+// pixels, the mapping from the captured display happens before
+pub trait Injector {
+    fn display(&self) -> Rect;
+    fn move_to(&mut self, x: i32, y: i32) -> Result<()>;
+    fn move_by(&mut self, dx: i32, dy: i32) -> Result<()>;
+    fn button(&mut self, button: MouseButton, down: bool) -> Result<()>;
+    fn wheel(&mut self, dx: i32, dy: i32) -> Result<()>;
+    fn key(&mut self, scancode: u16, down: bool) -> Result<()>;
+    fn text(&mut self, text: &str) -> Result<()>;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Injected {
+    MoveTo { x: i32, y: i32 },
+    MoveBy { dx: i32, dy: i32 },
+    Button { button: MouseButton, down: bool },
+    Wheel { dx: i32, dy: i32 },
+    Key { scancode: u16, down: bool },
+    Text(String),
+}
+
+// Records what it was asked to do instead of touching the desktop, for tests
+// and for trying a client against a headless server
+pub struct MockInjector {
+    pub display: Rect,
+    pub injected: Arc<Mutex<Vec<Injected>>>,
+}
+
+impl Default for MockInjector {
+    fn default() -> Self {
+        Self {
+            display: Rect {
+                x: 0,
+                y: 0,
+                width: 1920,
+                height: 1080,
+            },
+            injected: Default::default(),
+        }
+    }
+}
+
+impl MockInjector {
+    fn record(&mut self, injected: Injected) -> Result<()> {
+        // Keys and text are what the user types, never write them to the log
+        match &injected {
+            Injected::Key { .. } | Injected::Text(_) => log::trace!("Mock keyboard input"),
+            _ => log::trace!("Mock input {:?}", injected),
+        }
+        self.injected.lock().unwrap().push(injected);
+        Ok(())
+    }
+}
+
+impl Injector for MockInjector {
+    fn display(&self) -> Rect {
+        self.display
+    }
+
+    fn move_to(&mut self, x: i32, y: i32) -> Result<()> {
+        self.record(Injected::MoveTo { x, y })
+    }
+
+    fn move_by(&mut self, dx: i32, dy: i32) -> Result<()> {
+        self.record(Injected::MoveBy { dx, dy })
+    }
+
+    fn button(&mut self, button: MouseButton, down: bool) -> Result<()> {
+        self.record(Injected::Button { button, down })
+    }
+
+    fn wheel(&mut self, dx: i32, dy: i32) -> Result<()> {
+        self.record(Injected::Wheel { dx, dy })
+    }
+
+    fn key(&mut self, scancode: u16, down: bool) -> Result<()> {
+        self.record(Injected::Key { scancode, down })
+    }
+
+    fn text(&mut self, text: &str) -> Result<()> {
+        self.record(Injected::Text(text.into()))
+    }
+}
+
+// Keeps what's held down so the channel ending can't leave a key stuck, and
+// the seq of the last event per key and button so a late down can't undo an up
+#[derive(Default)]
+struct Applied {
+    moved: Option<u64>,
+    keys: HashMap<u16, (u64, bool)>,
+    buttons: HashMap<MouseButton, (u64, bool)>,
+}
+
+fn is_stale<K: std::hash::Hash + Eq>(last: &HashMap<K, (u64, bool)>, key: &K, seq: u64) -> bool {
+    last.get(key).is_some_and(|&(last, _)| last > seq)
+}
+
+impl Applied {
+    fn is_down(&self, scancode: u16) -> bool {
+        self.keys.get(&scancode).is_some_and(|&(_, down)| down)
+    }
+
+    // In typed text mode a character key only goes through as part of a
+    // shortcut, its up follows whatever happened to its down
+    fn is_typed(&self, mode: InputMode, scancode: u16, down: bool) -> bool {
+        if mode != InputMode::TypedText || !is_character_key(scancode) {
+            return false;
+        }
+        if down {
+            !self
+                .keys
+                .iter()
+                .any(|(&key, &(_, down))| down && is_shortcut_modifier(key))
+        } else {
+            !self.is_down(scancode)
+        }
+    }
+
+    fn apply(&mut self, injector: &mut dyn Injector, mode: InputMode, msg: InputMsg) -> Result<()> {
+        let seq = msg.seq;
+        match msg.event {
+            InputEvent::PointerMove { x, y } => {
+                if !x.is_finite() || !y.is_finite() {
+                    return Err(anyhow!("Pointer position {},{} is not a number", x, y));
+                }
+                if self.moved.is_some_and(|moved| moved > seq) {
+                    return Ok(());
+                }
+                self.moved = Some(seq);
+                let (x, y) = injector.display().point(x, y);
+                injector.move_to(x, y)
+            }
+            InputEvent::PointerMoveBy { dx, dy } => injector.move_by(dx, dy),
+            InputEvent::Button { button, down } => {
+                if is_stale(&self.buttons, &button, seq) {
+                    return Ok(());
+                }
+                self.buttons.insert(button, (seq, down));
+                injector.button(button, down)
+            }
+            InputEvent::Wheel { dx, dy } => injector.wheel(
+                dx.clamp(-MAX_WHEEL, MAX_WHEEL),
+                dy.clamp(-MAX_WHEEL, MAX_WHEEL),
+            ),
+            InputEvent::Key { scancode, down } => {
+                if is_stale(&self.keys, &scancode, seq) || self.is_typed(mode, scancode, down) {
+                    return Ok(());
+                }
+                self.keys.insert(scancode, (seq, down));
+                injector.key(scancode, down)
+            }
+            InputEvent::Text { text } => injector.text(&text),
+        }
+    }
+
+    fn release(&mut self, injector: &mut dyn Injector) {
+        for (&scancode, &(_, down)) in &self.keys {
+            if down {
+                let _ = injector.key(scancode, false);
+            }
+        }
+        for (&button, &(_, down)) in &self.buttons {
+            if down {
+                let _ = injector.button(button, false);
+            }
+        }
+    }
+}
+
+#[cfg(windows)]
+mod send_input {
+    use anyhow::{anyhow, Result};
+    use windows::Win32::UI::Input::KeyboardAndMouse::{
+        SendInput, INPUT, INPUT_0, INPUT_KEYBOARD, INPUT_MOUSE, KEYBDINPUT, KEYBD_EVENT_FLAGS,
+        KEYEVENTF_EXTENDEDKEY, KEYEVENTF_KEYUP, KEYEVENTF_SCANCODE, KEYEVENTF_UNICODE,
+        MOUSEEVENTF_ABSOLUTE, MOUSEEVENTF_HWHEEL, MOUSEEVENTF_LEFTDOWN, MOUSEEVENTF_LEFTUP,
+        MOUSEEVENTF_MIDDLEDOWN, MOUSEEVENTF_MIDDLEUP, MOUSEEVENTF_MOVE, MOUSEEVENTF_RIGHTDOWN,
+        MOUSEEVENTF_RIGHTUP, MOUSEEVENTF_VIRTUALDESK, MOUSEEVENTF_WHEEL, MOUSEEVENTF_XDOWN,
+        MOUSEEVENTF_XUP, MOUSEINPUT, MOUSE_EVENT_FLAGS, VIRTUAL_KEY,
+    };
+    use windows::Win32::UI::WindowsAndMessaging::{
+        GetSystemMetrics, SM_CXSCREEN, SM_CXVIRTUALSCREEN, SM_CYSCREEN, SM_CYVIRTUALSCREEN,
+        SM_XVIRTUALSCREEN, SM_YVIRTUALSCREEN, XBUTTON1, XBUTTON2,
+    };
+
+    use super::{Injector, MouseButton, Rect};
+
+    fn mouse(dx: i32, dy: i32, data: u32, flags: MOUSE_EVENT_FLAGS) -> INPUT {
+        INPUT {
+            r#type: INPUT_MOUSE,
+            Anonymous: INPUT_0 {
+                mi: MOUSEINPUT {
+                    dx,
+                    dy,
+                    mouseData: data,
+                    dwFlags: flags,
+                    time: 0,
+                    dwExtraInfo: 0,
+                },
+            },
+        }
+    }
+
+    fn keyboard(scan: u16, flags: KEYBD_EVENT_FLAGS) -> INPUT {
+        INPUT {
+            r#type: INPUT_KEYBOARD,
+            Anonymous: INPUT_0 {
+                ki: KEYBDINPUT {
+                    wVk: VIRTUAL_KEY(0),
+                    wScan: scan,
+                    dwFlags: flags,
+                    time: 0,
+                    dwExtraInfo: 0,
+                },
+            },
+        }
+    }
+
+    fn send(inputs: &[INPUT]) -> Result<()> {
+        let sent = unsafe { SendInput(inputs, std::mem::size_of::<INPUT>() as i32) };
+        if sent as usize != inputs.len() {
+            return Err(anyhow!(
+                "SendInput failed: {}",
+                std::io::Error::last_os_error()
+            ));
+        }
+        Ok(())
+    }
+
+    // The capture is of the primary display, which starts at 0,0
+    pub struct SendInputInjector {
+        display: Rect,
+        desktop: Rect,
+    }
+
+    impl SendInputInjector {
+        pub fn primary() -> Self {
+            let metric = |index| unsafe { GetSystemMetrics(index) };
+            Self {
+                display: Rect {
+                    x: 0,
+                    y: 0,
+                    width: metric(SM_CXSCREEN) as u32,
+                    height: metric(SM_CYSCREEN) as u32,
+                },
+                desktop: Rect {
+                    x: metric(SM_XVIRTUALSCREEN),
+                    y: metric(SM_YVIRTUALSCREEN),
+                    width: metric(SM_CXVIRTUALSCREEN) as u32,
+                    height: metric(SM_CYVIRTUALSCREEN) as u32,
+                },
+            }
+        }
+    }
+
+    impl Injector for SendInputInjector {
+        fn display(&self) -> Rect {
+            self.display
+        }
+
+        // Absolute moves are 0..=65535 across the whole virtual desktop
+        fn move_to(&mut self, x: i32, y: i32) -> Result<()> {
+            let scale = |at: i32, from: i32, len: u32| {
+                ((at - from) as i64 * 65535 / (len.max(2) - 1) as i64) as i32
+            };
+            let x = scale(x, self.desktop.x, self.desktop.width);
+            let y = scale(y, self.desktop.y, self.desktop.height);
+            let flags = MOUSEEVENTF_MOVE | MOUSEEVENTF_ABSOLUTE | MOUSEEVENTF_VIRTUALDESK;
+            send(&[mouse(x, y, 0, flags)])
+        }
+
+        fn move_by(&mut self, dx: i32, dy: i32) -> Result<()> {
+            send(&[mouse(dx, dy, 0, MOUSEEVENTF_MOVE)])
+        }
+
+        fn button(&mut self, button: MouseButton, down: bool) -> Result<()> {
+            let (data, flags) = match (button, down) {
+                (MouseButton::Left, true) => (0, MOUSEEVENTF_LEFTDOWN),
+                (MouseButton::Left, false) => (0, MOUSEEVENTF_LEFTUP),
+                (MouseButton::Right, true) => (0, MOUSEEVENTF_RIGHTDOWN),
+                (MouseButton::Right, false) => (0, MOUSEEVENTF_RIGHTUP),
+                (MouseButton::Middle, true) => (0, MOUSEEVENTF_MIDDLEDOWN),
+                (MouseButton::Middle, false) => (0, MOUSEEVENTF_MIDDLEUP),
+                (MouseButton::Back, true) => (XBUTTON1, MOUSEEVENTF_XDOWN),
+                (MouseButton::Back, false) => (XBUTTON1, MOUSEEVENTF_XUP),
+                (MouseButton::Forward, true) => (XBUTTON2, MOUSEEVENTF_XDOWN),
+                (MouseButton::Forward, false) => (XBUTTON2, MOUSEEVENTF_XUP),
+            };
+            send(&[mouse(0, 0, data as u32, flags)])
+        }
+
+        // Windows scrolls up for a positive delta
+        fn wheel(&mut self, dx: i32, dy: i32) -> Result<()> {
+            if dy != 0 {
+                send(&[mouse(0, 0, dy.saturating_neg() as u32, MOUSEEVENTF_WHEEL)])?;
+            }
+            if dx != 0 {
+                send(&[mouse(0, 0, dx as u32, MOUSEEVENTF_HWHEEL)])?;
+            }
+            Ok(())
+        }
+
+        fn key(&mut self, scancode: u16, down: bool) -> Result<()> {
+            let mut flags = KEYEVENTF_SCANCODE;
+            if scancode & 0xff00 == 0xe000 {
+                flags |= KEYEVENTF_EXTENDEDKEY;
+            }
+            if !down {
+                flags |= KEYEVENTF_KEYUP;
+            }
+            send(&[keyboard(scancode & 0xff, flags)])
+        }
+
+        fn text(&mut self, text: &str) -> Result<()> {
+            let inputs: Vec<INPUT> = text
+                .encode_utf16()
+                .flat_map(|unit| {
+                    [
+                        keyboard(unit, KEYEVENTF_UNICODE),
+                        keyboard(unit, KEYEVENTF_UNICODE | KEYEVENTF_KEYUP),
+                    ]
+                })
+                .collect();
+            send(&inputs)
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod xtest {
+    use std::os::raw::{c_int, c_uint};
+    use std::ptr;
+
+    use anyhow::{anyhow, Result};
+    use x11::xlib;
+    use x11::xtest;
+
+    use super::{Injector, MouseButton, Rect};
+
+    const WHEEL_DELTA: i32 = 120;
+
+    // Set 1 scancodes below 0x80 are the evdev key codes, the extended ones
+    // need looking up
+    fn evdev(scancode: u16) -> Option<u32> {
+        if scancode < 0x80 {
+            return Some(scancode as u32);
+        }
+        let code = match scancode {
+            0xe01c => 96,  // KEY_KPENTER
+            0xe01d => 97,  // KEY_RIGHTCTRL
+            0xe035 => 98,  // KEY_KPSLASH
+            0xe037 => 99,  // KEY_SYSRQ
+            0xe038 => 100, // KEY_RIGHTALT
+            0xe047 => 102, // KEY_HOME
+            0xe048 => 103, // KEY_UP
+            0xe049 => 104, // KEY_PAGEUP
+            0xe04b => 105, // KEY_LEFT
+            0xe04d => 106, // KEY_RIGHT
+            0xe04f => 107, // KEY_END
+            0xe050 => 108, // KEY_DOWN
+            0xe051 => 109, // KEY_PAGEDOWN
+            0xe052 => 110, // KEY_INSERT
+            0xe053 => 111, // KEY_DELETE
+            0xe05b => 125, // KEY_LEFTMETA
+            0xe05c => 126, // KEY_RIGHTMETA
+            0xe05d => 127, // KEY_COMPOSE
+            _ => return None,
+        };
+        Some(code)
+    }
+
+    fn keysym(c: char) -> xlib::KeySym {
+        match c {
+            '\n' => 0xff0d, // XK_Return
+            '\t' => 0xff09, // XK_Tab
+            c if (c as u32) < 0x100 => c as xlib::KeySym,
+            c => 0x0100_0000 | c as xlib::KeySym,
+        }
+    }
+
+    // Drives the X server's default screen through the XTEST extension, the
+    // capture is of that whole screen
+    pub struct XTestInjector {
+        display: *mut xlib::Display,
+        screen: Rect,
+        // A keycode without keysyms, text is typed by binding each
+        // character to it in turn like xdotool does
+        scratch: Option<c_int>,
+        // Wheel deltas short of a notch, kept for the next event
+        wheel: (i32, i32),
+    }
+
+    impl XTestInjector {
+        pub fn open() -> Result<Self> {
+            let display = unsafe { xlib::XOpenDisplay(ptr::null()) };
+            if display.is_null() {
+                return Err(anyhow!("Can't open X display, is DISPLAY set?"));
+            }
+            let mut injector = Self {
+                display,
+                screen: Rect {
+                    x: 0,
+                    y: 0,
+                    width: 0,
+                    height: 0,
+                },
+                scratch: None,
+                wheel: (0, 0),
+            };
+            let (mut event, mut error, mut major, mut minor) = (0, 0, 0, 0);
+            let has_xtest = unsafe {
+                xtest::XTestQueryExtension(display, &mut event, &mut error, &mut major, &mut minor)
+            };
+            if has_xtest == 0 {
+                return Err(anyhow!("X server has no XTEST extension"));
+            }
+            unsafe {
+                let screen = xlib::XDefaultScreen(display);
+                injector.screen.width = xlib::XDisplayWidth(display, screen) as u32;
+                injector.screen.height = xlib::XDisplayHeight(display, screen) as u32;
+            }
+            injector.scratch = injector.find_scratch();
+            Ok(injector)
+        }
+
+        fn find_scratch(&self) -> Option<c_int> {
+            let (mut min, mut max, mut per) = (0, 0, 0);
+            unsafe {
+                xlib::XDisplayKeycodes(self.display, &mut min, &mut max);
+                let count = max - min + 1;
+                let map = xlib::XGetKeyboardMapping(self.display, min as u8, count, &mut per);
+                if map.is_null() {
+                    return None;
+                }
+                let syms = std::slice::from_raw_parts(map, (count * per) as usize);
+                let scratch = syms
+                    .chunks(per as usize)
+                    .position(|syms| syms.iter().all(|&sym| sym == 0))
+                    .map(|index| min + index as c_int);
+                xlib::XFree(map.cast());
+                scratch
+            }
+        }
+
+        fn flush(&self) {
+            unsafe { xlib::XFlush(self.display) };
+        }
+
+        fn click(&self, button: c_uint, times: i32) {
+            for _ in 0..times {
+                unsafe {
+                    xtest::XTestFakeButtonEvent(self.display, button, 1, 0);
+                    xtest::XTestFakeButtonEvent(self.display, button, 0, 0);
+                }
+            }
+        }
+
+        fn type_char(&self, c: char) -> Result<()> {
+            let scratch = self
+                .scratch
+                .ok_or(anyhow!("No free keycode to type text with"))?;
+            let mut syms = [keysym(c), keysym(c)];
+            unsafe {
+                xlib::XChangeKeyboardMapping(self.display, scratch, 2, syms.as_mut_ptr(), 1);
+                xlib::XSync(self.display, xlib::False);
+                xtest::XTestFakeKeyEvent(self.display, scratch as c_uint, 1, 0);
+                xtest::XTestFakeKeyEvent(self.display, scratch as c_uint, 0, 0);
+                xlib::XSync(self.display, xlib::False);
+                let mut none = [0, 0];
+                xlib::XChangeKeyboardMapping(self.display, scratch, 2, none.as_mut_ptr(), 1);
+                xlib::XSync(self.display, xlib::False);
+            }
+            Ok(())
+        }
+    }
+
+    impl Drop for XTestInjector {
+        fn drop(&mut self) {
+            unsafe { xlib::XCloseDisplay(self.display) };
+        }
+    }
+
+    impl Injector for XTestInjector {
+        fn display(&self) -> Rect {
+            self.screen
+        }
+
+        fn move_to(&mut self, x: i32, y: i32) -> Result<()> {
+            unsafe { xtest::XTestFakeMotionEvent(self.display, -1, x, y, 0) };
+            self.flush();
+            Ok(())
+        }
+
+        fn move_by(&mut self, dx: i32, dy: i32) -> Result<()> {
+            unsafe { xtest::XTestFakeRelativeMotionEvent(self.display, dx, dy, 0) };
+            self.flush();
+            Ok(())
+        }
+
+        fn button(&mut self, button: MouseButton, down: bool) -> Result<()> {
+            let button = match button {
+                MouseButton::Left => 1,
+                MouseButton::Middle => 2,
+                MouseButton::Right => 3,
+                MouseButton::Back => 8,
+                MouseButton::Forward => 9,
+            };
+            unsafe { xtest::XTestFakeButtonEvent(self.display, button, down as c_int, 0) };
+            self.flush();
+            Ok(())
+        }
+
+        // Buttons 4 to 7 are one notch each way
+        fn wheel(&mut self, dx: i32, dy: i32) -> Result<()> {
+            self.wheel.0 = self.wheel.0.saturating_add(dx);
+            self.wheel.1 = self.wheel.1.saturating_add(dy);
+            let (notches_x, notches_y) = (self.wheel.0 / WHEEL_DELTA, self.wheel.1 / WHEEL_DELTA);
+            self.wheel.0 %= WHEEL_DELTA;
+            self.wheel.1 %= WHEEL_DELTA;
+            self.click(if notches_y < 0 { 4 } else { 5 }, notches_y.abs());
+            self.click(if notches_x < 0 { 6 } else { 7 }, notches_x.abs());
+            self.flush();
+            Ok(())
+        }
+
+        fn key(&mut self, scancode: u16, down: bool) -> Result<()> {
+            let code = evdev(scancode).ok_or(anyhow!("Unknown scancode {:#x}", scancode))?;
+            // X keycodes are evdev codes shifted by 8
+            unsafe { xtest::XTestFakeKeyEvent(self.display, code + 8, down as c_int, 0) };
+            self.flush();
+            Ok(())
+        }
+
+        fn text(&mut self, text: &str) -> Result<()> {
+            for c in text.chars() {
+                self.type_char(c)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(windows)]
+fn server_layout() -> Option<String> {
+    use windows::Win32::UI::Input::KeyboardAndMouse::GetKeyboardLayoutNameW;
+
+    let mut name = [0u16; 9];
+    unsafe { GetKeyboardLayoutNameW(&mut name) }.ok()?;
+    let len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+    Some(String::from_utf16_lossy(&name[..len]))
+}
+
+// The layout and variant fields of the root window's _XKB_RULES_NAMES
+#[cfg(target_os = "linux")]
+fn server_layout() -> Option<String> {
+    use std::os::raw::{c_int, c_uchar, c_ulong};
+    use x11::xlib;
+
+    unsafe {
+        let display = xlib::XOpenDisplay(std::ptr::null());
+        if display.is_null() {
+            return None;
+        }
+        let atom = xlib::XInternAtom(display, c"_XKB_RULES_NAMES".as_ptr(), xlib::True);
+        let mut actual_type: xlib::Atom = 0;
+        let mut format: c_int = 0;
+        let mut items: c_ulong = 0;
+        let mut after: c_ulong = 0;
+        let mut prop: *mut c_uchar = std::ptr::null_mut();
+        let status = (atom != 0).then(|| {
+            xlib::XGetWindowProperty(
+                display,
+                xlib::XDefaultRootWindow(display),
+                atom,
+                0,
+                1024,
+                xlib::False,
+                xlib::AnyPropertyType as xlib::Atom,
+                &mut actual_type,
+                &mut format,
+                &mut items,
+                &mut after,
+                &mut prop,
+            )
+        });
+        let mut layout = None;
+        if status == Some(xlib::Success as c_int) && !prop.is_null() {
+            let names = std::slice::from_raw_parts(prop, items as usize);
+            let names: Vec<String> = names
+                .split(|&b| b == 0)
+                .map(|name| String::from_utf8_lossy(name).into_owned())
+                .collect();
+            // rules, model, layout, variant, options
+            layout = names.get(2).filter(|name| !name.is_empty()).map(|name| {
+                match names.get(3).filter(|variant| !variant.is_empty()) {
+                    Some(variant) => format!("{}({})", name, variant),
+                    None => name.clone(),
+                }
+            });
+            xlib::XFree(prop.cast());
+        }
+        xlib::XCloseDisplay(display);
+        layout
+    }
+}
+
+#[cfg(not(any(windows, target_os = "linux")))]
+fn server_layout() -> Option<String> {
+    None
+}
+
+#[cfg(windows)]
+fn system_injector() -> Result<Box<dyn Injector>> {
+    Ok(Box::new(send_input::SendInputInjector::primary()))
+}
+
+#[cfg(target_os = "linux")]
+fn system_injector() -> Result<Box<dyn Injector>> {
+    Ok(Box::new(xtest::XTestInjector::open()?))
+}
+
+#[cfg(not(any(windows, target_os = "linux")))]
+fn system_injector() -> Result<Box<dyn Injector>> {
+    Err(anyhow!("Input injection is not supported on this platform"))
+}
+
+// Remote keyboard and mouse for the desktop shown by StartVideo. Injection
+// runs on a blocking thread of its own, the platform calls aren't async and
+// Xlib's connection can't move between threads. `mode` can change any time
+// through the control channel
+pub async fn handle_input(
+    mut rx: mpsc::Receiver<Bytes>, // From clients to server
+    mut done_rx: broadcast::Receiver<()>,
+    settings: InputSettings,
+    mode: Arc<Mutex<InputMode>>,
+) {
+    if !settings.enabled {
+        log::error!("Input channels are disabled");
+        return;
+    }
+
+    let (events_tx, mut events_rx) = mpsc::channel::<InputMsg>(256);
+    let injecting = tokio::task::spawn_blocking(move || -> Result<()> {
+        let mut injector: Box<dyn Injector> = if settings.mock {
+            Box::new(MockInjector::default())
+        } else {
+            system_injector()?
+        };
+        let mut applied = Applied::default();
+        while let Some(msg) = events_rx.blocking_recv() {
+            let mode = *mode.lock().unwrap();
+            if let Err(e) = applied.apply(injector.as_mut(), mode, msg) {
+                log::warn!("Failed to inject input: {}", e);
+            }
+        }
+        applied.release(injector.as_mut());
+        Ok(())
+    });
+
+    loop {
+        tokio::select! {
+            data = rx.recv() => {
+                let Some(data) = data.filter(|data| !is_eof(data)) else {
+                    break;
+                };
+                let msg = match serde_json::from_slice::<InputMsg>(&data) {
+                    Ok(msg) => msg,
+                    Err(e) => {
+                        log::warn!("Bad input message: {}", e);
+                        continue;
+                    }
+                };
+                // Closed once the injector failed to start
+                if events_tx.send(msg).await.is_err() {
+                    break;
+                }
+            }
+            _ = done_rx.recv() => break,
+        }
+    }
+
+    drop(events_tx);
+    match injecting.await {
+        Ok(Err(e)) => log::error!("Input injection failed: {}", e),
+        Err(e) => log::error!("Input injection failed: {}", e),
+        Ok(Ok(())) => (),
+    }
+    log::info!("handle_input exiting");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Applies the events in the order given, `seq` is what they were sent as
+    fn apply(mode: InputMode, events: Vec<(u64, InputEvent)>) -> (Applied, MockInjector) {
+        let mut injector = MockInjector::default();
+        let mut applied = Applied::default();
+        for (seq, event) in events {
+            applied
+                .apply(&mut injector, mode, InputMsg { seq, event })
+                .unwrap();
+        }
+        (applied, injector)
+    }
+
+    fn injected(injector: &MockInjector) -> Vec<Injected> {
+        injector.injected.lock().unwrap().clone()
+    }
+
+    fn key(scancode: u16, down: bool) -> InputEvent {
+        InputEvent::Key { scancode, down }
+    }
+
+    #[test]
+    fn late_events_dont_undo_newer_ones() {
+        let (_, injector) = apply(
+            InputMode::PhysicalKeys,
+            vec![
+                (2, key(0x1e, false)),
+                (1, key(0x1e, true)),
+                (4, InputEvent::PointerMove { x: 1.0, y: 1.0 }),
+                (3, InputEvent::PointerMove { x: 0.0, y: 0.0 }),
+            ],
+        );
+        assert_eq!(
+            injected(&injector),
+            vec![
+                Injected::Key {
+                    scancode: 0x1e,
+                    down: false
+                },
+                Injected::MoveTo { x: 1919, y: 1079 },
+            ]
+        );
+    }
+
+    #[test]
+    fn release_lets_go_of_what_is_still_held() {
+        let (mut applied, mut injector) = apply(
+            InputMode::PhysicalKeys,
+            vec![
+                (1, key(0x1d, true)),
+                (2, key(0x2e, true)),
+                (3, key(0x2e, false)),
+                (
+                    4,
+                    InputEvent::Button {
+                        button: MouseButton::Left,
+                        down: true,
+                    },
+                ),
+            ],
+        );
+        injector.injected.lock().unwrap().clear();
+        applied.release(&mut injector);
+        assert_eq!(
+            injected(&injector),
+            vec![
+                Injected::Key {
+                    scancode: 0x1d,
+                    down: false
+                },
+                Injected::Button {
+                    button: MouseButton::Left,
+                    down: false
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn typed_text_only_passes_shortcuts() {
+        let (_, injector) = apply(
+            InputMode::TypedText,
+            vec![
+                (1, key(0x1e, true)),
+                (2, key(0x1e, false)),
+                (3, key(0x1d, true)),
+                (4, key(0x2e, true)),
+                (5, key(0x2e, false)),
+            ],
+        );
+        let keys: Vec<_> = injected(&injector)
+            .into_iter()
+            .filter_map(|injected| match injected {
+                Injected::Key { scancode, down } => Some((scancode, down)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(keys, vec![(0x1d, true), (0x2e, true), (0x2e, false)]);
+    }
+
+    #[test]
+    fn wheel_is_clamped_per_event() {
+        let (_, injector) = apply(
+            InputMode::PhysicalKeys,
+            vec![(
+                1,
+                InputEvent::Wheel {
+                    dx: i32::MIN,
+                    dy: i32::MAX,
+                },
+            )],
+        );
+        assert_eq!(
+            injected(&injector),
+            vec![Injected::Wheel {
+                dx: -MAX_WHEEL,
+                dy: MAX_WHEEL
+            }]
+        );
+    }
+
+    #[test]
+    fn pointer_position_must_be_a_number() {
+        let mut injector = MockInjector::default();
+        let msg = InputMsg {
+            seq: 1,
+            event: InputEvent::PointerMove {
+                x: f64::NAN,
+                y: 0.5,
+            },
+        };
+        assert!(Applied::default()
+            .apply(&mut injector, InputMode::PhysicalKeys, msg)
+            .is_err());
+        assert!(injected(&injector).is_empty());
+    }
+
+    #[test]
+    fn points_map_into_the_display() {
+        let rect = Rect {
+            x: 100,
+            y: 50,
+            width: 1920,
+            height: 1080,
+        };
+        assert_eq!(rect.point(0.0, 0.0), (100, 50));
+        assert_eq!(rect.point(1.0, 1.0), (2019, 1129));
+        assert_eq!(rect.point(0.5, 0.25), (1060, 320));
+        assert_eq!(rect.point(-1.0, 2.0), (100, 1129));
+
+        let edge = Rect {
+            x: i32::MAX - 10,
+            y: 0,
+            width: 100,
+            height: 0,
+        };
+        assert_eq!(edge.point(1.0, 1.0), (i32::MAX, 0));
+    }
+}
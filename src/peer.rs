@@ -1,17 +1,32 @@
-use crate::port::handle_port;
-use crate::shell::{handle_pty, Session, SessionMap};
+use crate::asciicast::Recorder;
+use crate::clipboard::handle_clipboard;
+use crate::compression::{negotiate, Compression, Compressor, Decompressor};
+use crate::file::handle_file;
+use crate::http::handle_http;
+use crate::input::{handle_input, session_mode};
+use crate::policy::{Endpoint, Target};
+use crate::port::{handle_listen, handle_port, is_eof, max_chunk};
+use crate::screen::{new_screen, subscribe};
+use crate::sftp::handle_sftp;
+use crate::shell::{handle_pty, Session, SessionMap, INITIAL_SIZE};
 use crate::signal::Signaling;
 use crate::state::State;
+use crate::sync::handle_sync;
+use crate::udp::handle_udp;
+use crate::utils::to_json;
 use anyhow::{anyhow, Ok, Result};
 use bytes::Bytes;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::mpsc::{self};
-use tokio::sync::{broadcast, Mutex};
+use tokio::sync::{broadcast, Mutex, Notify};
 use ts_rs::TS;
 use uuid::Uuid;
 use webrtc::data_channel::data_channel_message::DataChannelMessage;
+use webrtc::data_channel::data_channel_state::RTCDataChannelState;
 use webrtc::data_channel::RTCDataChannel;
 use webrtc::peer_connection::RTCPeerConnection;
 
@@ -22,11 +37,48 @@ pub struct Peer {
 
 pub type PeerMap = Arc<Mutex<HashMap<String, Peer>>>;
 
+// Identifies a peer connection for as long as it lives
+pub fn peer_key(pc: &Arc<RTCPeerConnection>) -> usize {
+    Arc::as_ptr(pc) as usize
+}
+
+// How long a finished session waits for its channels to send queued output
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+// What a channel forwards to its peer
+enum Output {
+    Broadcast(broadcast::Receiver<Bytes>),
+    Direct(mpsc::Receiver<Bytes>),
+}
+
+impl Output {
+    // None once the session output ended, or when a broadcast reader fell behind
+    async fn recv(&mut self) -> Option<Bytes> {
+        match self {
+            Self::Broadcast(rx) => rx.recv().await.ok(),
+            Self::Direct(rx) => rx.recv().await,
+        }
+    }
+}
+
 #[derive(Default, Clone, Serialize, Deserialize, TS)]
 #[ts(export)]
 pub struct DataChannelSettingsMsg {
-    variant: String,
-    session_id: Option<String>,
+    pub variant: String,
+    pub session_id: Option<String>,
+    // Offered algorithms in order of preference
+    pub compression: Option<Vec<Compression>>,
+    // host:port or unix:/path for port channels, defaults to the local sshd
+    pub target: Option<String>,
+    // host:port the server listens on for listen channels (`-R`)
+    pub bind: Option<String>,
+}
+
+// First message sent back on channels that offered compression
+#[derive(Default, Clone, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct DataChannelAckMsg {
+    pub compression: Option<Compression>,
 }
 
 impl<T> State<T>
@@ -38,50 +90,176 @@ where
     pub fn create_session(
         self: Arc<Self>,
         pc: Arc<RTCPeerConnection>,
+        session_id: String,
         variant: String,
+        target: Option<Endpoint>,
+        bind: Option<Target>,
         mut peer_done_rx: broadcast::Receiver<()>,
     ) -> Result<Session> {
+        let creator = peer_key(&pc);
         let (to_pty_tx, to_pty_rx) = mpsc::channel::<Bytes>(100);
         let (from_pty_tx, _) = broadcast::channel::<Bytes>(100);
         let (done_tx, done_rx) = broadcast::channel::<()>(1);
+        // Everything but shells and control has exactly one reader, skip the broadcast hop
+        let direct = matches!(
+            variant.as_str(),
+            "port" | "udp" | "http" | "file" | "sftp" | "sync" | "clipboard" | "input"
+        );
+        let (direct_tx, direct_rx) = mpsc::channel::<Bytes>(100);
 
-        // send done if peer is done
-        let done_tx_copy = done_tx.clone();
-        tokio::spawn(async move {
-            if let Err(e) = peer_done_rx
-                .recv()
-                .await
-                .map_err(|e| anyhow!(e))
-                .and_then(|_| done_tx_copy.send(()).map_err(|e| anyhow!(e)))
-            {
-                log::error!("Some error idc 2: {}", e);
-            }
-        });
+        // send done if peer is done, shared shells end when the last participant leaves
+        if variant != "web_shell" {
+            let done_tx_copy = done_tx.clone();
+            tokio::spawn(async move {
+                if let Err(e) = peer_done_rx
+                    .recv()
+                    .await
+                    .map_err(|e| anyhow!(e))
+                    .and_then(|_| done_tx_copy.send(()).map_err(|e| anyhow!(e)))
+                {
+                    log::error!("Some error idc 2: {}", e);
+                }
+            });
+        }
+
+        let screen = (variant == "web_shell").then(|| new_screen(INITIAL_SIZE));
+        let variant_copy = variant.clone();
 
         let self_clone = self.clone();
         // Start PTY handler
         tokio::spawn({
             let from_pty_tx = from_pty_tx.clone();
             let self_clone = self_clone.clone();
+            let screen = screen.clone();
+            let session_id = session_id.clone();
+            let done_tx = done_tx.clone();
             async move {
-                match variant.as_str() {
-                    "control" => {
-                        self_clone
-                            .handle_control(pc, from_pty_tx, to_pty_rx, done_rx)
-                            .await
+                let handler = async move {
+                    match variant.as_str() {
+                        "control" => {
+                            self_clone
+                                .handle_control(pc, from_pty_tx, to_pty_rx, done_rx)
+                                .await
+                        }
+                        "web_shell" => {
+                            let recording = &self_clone.settings.recording;
+                            let recorder = if recording.enabled {
+                                match Recorder::create(
+                                    &recording.dir,
+                                    &session_id,
+                                    INITIAL_SIZE,
+                                    recording.capture_input,
+                                ) {
+                                    Result::Ok(recorder) => Some(recorder),
+                                    Err(e) => {
+                                        log::error!(
+                                            "Failed to start recording, refusing shell: {}",
+                                            e
+                                        );
+                                        return;
+                                    }
+                                }
+                            } else {
+                                None
+                            };
+                            let screen = screen.unwrap_or_else(|| new_screen(INITIAL_SIZE));
+                            handle_pty(from_pty_tx, to_pty_rx, done_rx, recorder, screen).await
+                        }
+                        "port" => {
+                            let target = target.unwrap_or_default();
+                            let policy = self_clone.settings.forward.clone();
+                            let max_chunk = max_chunk(&pc).await;
+                            handle_port(direct_tx, to_pty_rx, done_rx, target, policy, max_chunk)
+                                .await
+                        }
+                        "udp" => {
+                            let Some(Endpoint::Tcp(target)) = target else {
+                                log::error!("udp channel needs a host:port target");
+                                return;
+                            };
+                            let policy = self_clone.settings.forward.clone();
+                            let idle_timeout =
+                                Duration::from_secs(self_clone.settings.udp.idle_timeout_secs);
+                            handle_udp(direct_tx, to_pty_rx, done_rx, target, policy, idle_timeout)
+                                .await
+                        }
+                        "http" => {
+                            let Some(Endpoint::Tcp(target)) = target else {
+                                log::error!("http channel needs a host:port target");
+                                return;
+                            };
+                            let policy = self_clone.settings.http.clone();
+                            let max_chunk = max_chunk(&pc).await;
+                            handle_http(direct_tx, to_pty_rx, done_rx, target, policy, max_chunk)
+                                .await
+                        }
+                        "sftp" => {
+                            let policy = self_clone.settings.files.clone();
+                            handle_sftp(direct_tx, to_pty_rx, done_rx, policy).await
+                        }
+                        "file" => {
+                            let policy = self_clone.settings.files.clone();
+                            handle_file(direct_tx, to_pty_rx, done_rx, policy).await
+                        }
+                        "sync" => {
+                            let policy = self_clone.settings.files.clone();
+                            handle_sync(direct_tx, to_pty_rx, done_rx, policy).await
+                        }
+                        "clipboard" => {
+                            let settings = self_clone.settings.clipboard.clone();
+                            handle_clipboard(direct_tx, to_pty_rx, done_rx, settings).await
+                        }
+                        "input" => {
+                            let settings = self_clone.settings.input.clone();
+                            let mode = session_mode(&self_clone.keyboards, &pc);
+                            handle_input(to_pty_rx, done_rx, settings, mode).await
+                        }
+                        "listen" => {
+                            let (Some(bind), Some(target)) = (bind, target) else {
+                                log::error!("listen channel needs bind and target");
+                                return;
+                            };
+                            let policy = self_clone.settings.reverse.bind.clone();
+                            handle_listen(pc, done_rx, bind, target.to_string(), policy).await
+                        }
+                        _ => log::error!("unknown data channel"),
                     }
-                    "web_shell" => handle_pty(from_pty_tx, to_pty_rx, done_rx).await,
-                    "port" => handle_port(from_pty_tx, to_pty_rx, done_rx).await,
-                    _ => log::error!("unknown data channel"),
+                };
+                handler.await;
+                // Handler is gone, close every channel attached to it
+                if done_tx.is_empty() {
+                    let _ = done_tx.send(());
                 }
             }
         });
 
         let session = Session {
-            to_pty: to_pty_tx.clone(),
-            from_pty: from_pty_tx.clone(),
-            done_tx: done_tx.clone(),
+            creator,
+            ..Session::new(
+                to_pty_tx.clone(),
+                from_pty_tx.clone(),
+                done_tx.clone(),
+                variant_copy,
+                screen,
+                Duration::from_secs(self.settings.sessions.detach_timeout_secs),
+                direct.then_some(direct_rx),
+            )
         };
+
+        // Forget the session once it's done, unless its id was taken over since
+        let session_map = self.session_map.clone();
+        let mut session_done_rx = done_tx.subscribe();
+        let peers = session.peers.clone();
+        tokio::spawn(async move {
+            let _ = session_done_rx.recv().await;
+            let mut map = session_map.lock().unwrap();
+            if map
+                .get(&session_id)
+                .is_some_and(|s| Arc::ptr_eq(&s.peers, &peers))
+            {
+                map.remove(&session_id);
+            }
+        });
         Ok(session)
     }
 
@@ -101,72 +279,221 @@ where
         let msg: DataChannelSettingsMsg = serde_json::from_str(d_label.as_str()).unwrap();
 
         let variant = msg.variant;
+        let shared = variant == "web_shell";
+
+        let target = match (variant.as_str(), &msg.target) {
+            ("port", Some(target)) => Some(target.parse::<Endpoint>()?),
+            ("port", None) => Some(Endpoint::default()),
+            ("listen" | "udp" | "http", target) => Some(
+                target
+                    .as_deref()
+                    .ok_or(anyhow!("{} channel without target", variant))?
+                    .parse::<Endpoint>()?,
+            ),
+            _ => None,
+        };
+        let bind = match (variant.as_str(), &msg.bind) {
+            ("listen", Some(bind)) => Some(bind.parse::<Target>()?),
+            ("listen", None) => return Err(anyhow!("listen channel without bind")),
+            _ => None,
+        };
+        let port = match &target {
+            Some(Endpoint::Tcp(target)) if variant == "port" || variant == "http" => {
+                Some(target.port)
+            }
+            _ => None,
+        };
+        // Datagrams can be lost, a streaming compressor can't survive that
+        let allowed = self.settings.compression.allows(port) && variant != "udp";
+        let compression = msg.compression.map(|offer| negotiate(&offer, allowed));
+        let mut compressor = compression.flatten().map(Compressor::new).transpose()?;
+        let decompressor = compression
+            .flatten()
+            .map(Decompressor::new)
+            .transpose()?
+            .map(|d| Arc::new(std::sync::Mutex::new(d)));
+        let ack = compression.map(|compression| DataChannelAckMsg { compression });
         let session_id = match msg.session_id {
             Some(id) => id,
             None => Uuid::new_v4().to_string(),
         };
 
-        log::info!("New DataChannel {variant} {d_id}");
+        log::info!("New DataChannel {variant} {d_id} compression {compression:?}");
 
         // Check if session already exists
         let session = {
             let mut map = session_map.lock().unwrap();
-            if variant == "port" {
-                let session = self.create_session(pc, variant, peer_done_rx.resubscribe())?;
+            if matches!(
+                variant.as_str(),
+                "port"
+                    | "listen"
+                    | "udp"
+                    | "http"
+                    | "file"
+                    | "sftp"
+                    | "sync"
+                    | "clipboard"
+                    | "input"
+            ) {
+                // Only shells are shared, anything else would replace a live session
+                if map.contains_key(&session_id) {
+                    return Err(anyhow!("Session {} already exists", session_id));
+                }
+                let session = self.create_session(
+                    pc,
+                    session_id.clone(),
+                    variant,
+                    target,
+                    bind,
+                    peer_done_rx.resubscribe(),
+                )?;
                 map.insert(session_id.clone(), session.clone());
                 session
             } else {
                 if let Some(pty_session) = map.get(&session_id) {
                     pty_session.clone()
                 } else {
-                    let session = self.create_session(pc, variant, peer_done_rx.resubscribe())?;
+                    let session = self.create_session(
+                        pc,
+                        session_id.clone(),
+                        variant,
+                        None,
+                        None,
+                        peer_done_rx.resubscribe(),
+                    )?;
                     map.insert(session_id.clone(), session.clone());
                     session
                 }
             }
         };
 
-        let session_done_rx_copy = session.done_tx.clone();
+        let participant = shared.then(|| session.join(peer_key(&pc)).id);
+        session.attached.fetch_add(1, Ordering::Relaxed);
+
+        // Called on channel close and on peer done, only the first call counts
+        let detached = Arc::new(AtomicBool::new(false));
+        let detach = {
+            let session = session.clone();
+            let participant = participant.clone();
+            move || {
+                if detached.swap(true, Ordering::AcqRel) {
+                    return;
+                }
+                session.attached.fetch_sub(1, Ordering::Relaxed);
+                match &participant {
+                    Some(id) => session.leave(id),
+                    None => session.kill(),
+                }
+            }
+        };
+
+        let detach_copy = detach.clone();
         tokio::spawn(async move {
-            if let Err(e) = peer_done_rx
-                .recv()
-                .await
-                .map_err(|e| anyhow!(e))
-                .and_then(|_| session_done_rx_copy.send(()).map_err(|e| anyhow!(e)))
-            {
+            if let Err(e) = peer_done_rx.recv().await {
                 log::error!("Some error idc 3: {}", e);
             }
+            detach_copy();
+        });
+        // Close the channel when the session ends, once the output the
+        // handler produced before ending went out
+        let mut session_done_rx = session.done_tx.subscribe();
+        let mut sender_done_rx = session.done_tx.subscribe();
+        let flushed = Arc::new(Notify::new());
+        let flushed_copy = flushed.clone();
+        let d_copy = Arc::clone(&d);
+        tokio::spawn(async move {
+            let _ = session_done_rx.recv().await;
+            if d_copy.ready_state() == RTCDataChannelState::Open {
+                let _ = tokio::time::timeout(FLUSH_TIMEOUT, flushed_copy.notified()).await;
+            }
+            if let Err(e) = d_copy.close().await {
+                log::error!("Failed to close data channel: {}", e);
+            }
         });
+
         // Register channel opening handling
         let d2 = Arc::clone(&d);
         // let d_label2 = d_label.clone();
         // let d_id2 = d_id;
         d.on_close(Box::new(move || {
             log::info!("Data channel closed");
-            if session.done_tx.is_empty() {
-                let _ = session.done_tx.send(());
-            }
+            detach();
             Box::pin(async {})
         }));
 
         // Now we have the PTYSession
         // Subscribe to the broadcast channel to receive data from PTY
-        let mut from_pty_rx = session.from_pty.subscribe();
+        let (mut from_pty_rx, repaint) = match (&session.screen, session.take_direct()) {
+            (_, Some(rx)) => (Output::Direct(rx), None),
+            (Some(screen), None) => {
+                let (rx, repaint) = subscribe(screen, &session.from_pty);
+                (Output::Broadcast(rx), Some(repaint))
+            }
+            (None, None) => (Output::Broadcast(session.from_pty.subscribe()), None),
+        };
 
         // Clone the sender to send data to PTY
         let to_pty = session.to_pty.clone();
 
+        let session_copy = session.clone();
+        let participant_copy = participant.clone();
         d.on_open(Box::new(move || {
+            // Welcome first, then repaint the current screen for late joiners
+            let mut pending: Vec<Bytes> = participant_copy
+                .map(|id| session_copy.welcome(&id))
+                .into_iter()
+                .chain(repaint)
+                .collect();
+            pending.reverse();
             let d_clone = Arc::clone(&d2); // Clone the Arc to use in the async block
             Box::pin(async move {
                 // Launch a task to handle sending messages received via the channel
                 tokio::spawn(async move {
-                    while let Result::Ok(message) = from_pty_rx.recv().await {
-                        if d_clone.send(&message).await.is_err() {
-                            log::error!("Failed to send message over data channel");
-                            break;
+                    let send_loop = async {
+                        if let Some(ack) = ack {
+                            let sent = match to_json(&ack) {
+                                Result::Ok(json) => d_clone.send(&json.into()).await.is_ok(),
+                                Err(_) => false,
+                            };
+                            if !sent {
+                                log::error!("Failed to send channel ack");
+                                return;
+                            }
                         }
-                    }
+                        loop {
+                            let message = match pending.pop() {
+                                Some(message) => message,
+                                // Queued output wins, the session ending only stops an idle sender
+                                None => tokio::select! {
+                                    biased;
+                                    message = from_pty_rx.recv() => match message {
+                                        Some(message) => message,
+                                        None => break,
+                                    },
+                                    _ = sender_done_rx.recv() => break,
+                                },
+                            };
+                            let message = match compressor.as_mut() {
+                                Some(compressor) if !is_eof(&message) => {
+                                    match compressor.compress(&message) {
+                                        Result::Ok(message) => message,
+                                        Err(e) => {
+                                            log::error!("Failed to compress message: {}", e);
+                                            break;
+                                        }
+                                    }
+                                }
+                                _ => message,
+                            };
+                            if d_clone.send(&message).await.is_err() {
+                                log::error!("Failed to send message over data channel");
+                                break;
+                            }
+                            session_copy.touch();
+                        }
+                    };
+                    send_loop.await;
+                    flushed.notify_one();
                 });
             })
         }));
@@ -175,10 +502,30 @@ where
         d.on_message(Box::new(move |msg: DataChannelMessage| {
             let ptx_clone = to_pty.clone(); // Clone the sender for use in the async context
                                             // let msg_str = String::from_utf8(msg.data.to_vec()).unwrap(); // Convert the received message to a String
+            let data = match &decompressor {
+                Some(decompressor) if !is_eof(&msg.data) => {
+                    decompressor.lock().unwrap().decompress(&msg.data)
+                }
+                _ => Result::Ok(msg.data),
+            };
+            session.touch();
+            // Viewers' input is dropped here
+            let data = match (&participant, data) {
+                (Some(id), Result::Ok(data)) => Result::Ok(session.filter_incoming(id, data)),
+                (_, data) => data.map(Some),
+            };
 
             Box::pin(async move {
+                let data = match data {
+                    Result::Ok(Some(data)) => data,
+                    Result::Ok(None) => return,
+                    Err(e) => {
+                        log::error!("Failed to decompress message: {}", e);
+                        return;
+                    }
+                };
                 // Send the message to the PTY task asynchronously
-                if let Err(e) = ptx_clone.send(msg.data).await {
+                if let Err(e) = ptx_clone.send(data).await {
                     log::error!("Failed to send message to PTY: {}", e);
                 }
             })
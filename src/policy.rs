@@ -0,0 +1,634 @@
+use std::fmt::Display;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use tokio::net::lookup_host;
+
+use crate::port::{SSH_SERVER_HOST, SSH_SERVER_PORT};
+
+// Destination of a port channel, `host:port` or `[v6]:port`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Target {
+    pub host: String,
+    pub port: u16,
+}
+
+impl Default for Target {
+    fn default() -> Self {
+        Self {
+            host: SSH_SERVER_HOST.into(),
+            port: SSH_SERVER_PORT,
+        }
+    }
+}
+
+impl FromStr for Target {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (host, port) = s
+            .rsplit_once(':')
+            .ok_or(anyhow!("Target {} must be host:port", s))?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(anyhow!("Target {} has no host", s));
+        }
+        Ok(Self {
+            host: host.to_owned(),
+            port: port.parse()?,
+        })
+    }
+}
+
+impl Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+pub const UNIX_PREFIX: &str = "unix:";
+
+// Either end of a forward, `host:port` or `unix:/path`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Endpoint {
+    Tcp(Target),
+    Unix(PathBuf),
+}
+
+impl Default for Endpoint {
+    fn default() -> Self {
+        Self::Tcp(Target::default())
+    }
+}
+
+impl FromStr for Endpoint {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.strip_prefix(UNIX_PREFIX) {
+            Some("") => Err(anyhow!("Endpoint {} has no path", s)),
+            Some(path) => Ok(Self::Unix(path.into())),
+            None => Ok(Self::Tcp(s.parse()?)),
+        }
+    }
+}
+
+impl Display for Endpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tcp(target) => target.fmt(f),
+            Self::Unix(path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+        }
+    }
+}
+
+// "22", "5432-5440" or a comma separated list of both, None allows any port
+fn port_matches(spec: Option<&str>, port: u16) -> bool {
+    let Some(spec) = spec else {
+        return true;
+    };
+    spec.split(',')
+        .map(str::trim)
+        .any(|part| match part.split_once('-') {
+            Some((from, to)) => match (from.trim().parse::<u16>(), to.trim().parse::<u16>()) {
+                (Ok(from), Ok(to)) => (from..=to).contains(&port),
+                _ => false,
+            },
+            None => part.parse::<u16>() == Ok(port),
+        })
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    #[default]
+    Tcp,
+    Udp,
+}
+
+// Every given field has to match, a rule with no host and no cidr matches any address.
+// Rules without a protocol only allow tcp
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ForwardRule {
+    pub host: Option<String>,
+    pub cidr: Option<IpNet>,
+    pub ports: Option<String>,
+    #[serde(default)]
+    pub protocol: Protocol,
+}
+
+impl ForwardRule {
+    fn matches(&self, target: &Target, protocol: Protocol, ip: IpAddr) -> bool {
+        self.protocol == protocol
+            && self
+                .host
+                .as_ref()
+                .map_or(true, |host| host.eq_ignore_ascii_case(&target.host))
+            && self.cidr.map_or(true, |cidr| cidr.contains(&ip))
+            && port_matches(self.ports.as_deref(), target.port)
+    }
+}
+
+// Denies everything that no rule allows
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ForwardPolicy {
+    pub rules: Vec<ForwardRule>,
+    // Unix sockets, or directories holding them, port channels may connect to
+    pub unix_sockets: Vec<PathBuf>,
+}
+
+impl Default for ForwardPolicy {
+    fn default() -> Self {
+        Self {
+            rules: vec![ForwardRule {
+                host: Some(SSH_SERVER_HOST.into()),
+                cidr: None,
+                ports: Some(SSH_SERVER_PORT.to_string()),
+                protocol: Protocol::Tcp,
+            }],
+            unix_sockets: vec![],
+        }
+    }
+}
+
+impl ForwardPolicy {
+    // Resolves the target and keeps only the addresses some rule allows,
+    // those exact addresses are dialed so a second lookup can't sneak around the check
+    pub async fn resolve(&self, target: &Target, protocol: Protocol) -> Result<Vec<SocketAddr>> {
+        let addrs: Vec<SocketAddr> = lookup_host((target.host.as_str(), target.port))
+            .await?
+            .filter(|addr| {
+                self.rules
+                    .iter()
+                    .any(|rule| rule.matches(target, protocol, addr.ip()))
+            })
+            .collect();
+        if addrs.is_empty() {
+            return Err(anyhow!(
+                "Forwarding to {}/{:?} is not allowed",
+                target,
+                protocol
+            ));
+        }
+        Ok(addrs)
+    }
+
+    // Symlinks are resolved before matching, and the socket has to be
+    // writable by the user the server runs as
+    #[cfg(unix)]
+    pub fn check_unix(&self, path: &std::path::Path) -> Result<PathBuf> {
+        use std::os::unix::ffi::OsStrExt;
+        use std::os::unix::fs::FileTypeExt;
+
+        let denied = || anyhow!("Forwarding to {} is not allowed", path.display());
+        let path = std::fs::canonicalize(path).map_err(|_| denied())?;
+        let allowed = self.unix_sockets.iter().any(|allowed| {
+            std::fs::canonicalize(allowed).is_ok_and(|allowed| path.starts_with(allowed))
+        });
+        if !allowed {
+            return Err(denied());
+        }
+        if !std::fs::metadata(&path)?.file_type().is_socket() {
+            return Err(anyhow!("{} is not a unix socket", path.display()));
+        }
+        let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+        if unsafe { libc::access(c_path.as_ptr(), libc::R_OK | libc::W_OK) } != 0 {
+            return Err(anyhow!(
+                "{} is not accessible: {}",
+                path.display(),
+                std::io::Error::last_os_error()
+            ));
+        }
+        Ok(path)
+    }
+}
+
+// One allowed part of one web app behind the server
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HttpRoute {
+    pub host: String,
+    pub port: u16,
+    // Request paths have to start with this, "/" allows the whole app
+    pub path_prefix: String,
+    // None allows any method
+    pub methods: Option<Vec<String>>,
+}
+
+// Denies every http channel request that no route allows
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HttpPolicy {
+    pub routes: Vec<HttpRoute>,
+}
+
+// `..` segments would let a path escape its prefix once the app normalizes it.
+// Encoded slashes and backslashes are rejected outright, apps that decode or
+// normalize them would see segments the split below doesn't
+fn has_dot_segments(path: &str) -> bool {
+    let path = path.to_ascii_lowercase();
+    if path.contains('\\') || path.contains("%2f") || path.contains("%5c") {
+        return true;
+    }
+    path.split('/').any(|segment| {
+        let segment = segment.replace("%2e", ".");
+        segment == "." || segment == ".."
+    })
+}
+
+// "/api" allows "/api" and "/api/x" but not "/apix", query and fragment
+// are already cut off
+fn prefix_matches(path: &str, prefix: &str) -> bool {
+    path.strip_prefix(prefix)
+        .is_some_and(|rest| prefix.ends_with('/') || rest.is_empty() || rest.starts_with('/'))
+}
+
+impl HttpPolicy {
+    pub fn allows(&self, target: &Target, method: &str, path: &str) -> bool {
+        let path = path.split(['?', '#']).next().unwrap_or_default();
+        if !path.starts_with('/') || has_dot_segments(path) {
+            return false;
+        }
+        self.routes.iter().any(|route| {
+            route.host.eq_ignore_ascii_case(&target.host)
+                && route.port == target.port
+                && prefix_matches(path, &route.path_prefix)
+                && route.methods.as_ref().map_or(true, |methods| {
+                    methods.iter().any(|m| m.eq_ignore_ascii_case(method))
+                })
+        })
+    }
+}
+
+// Directories file channels and the fs calls may touch, nothing by default
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FilePolicy {
+    pub roots: Vec<PathBuf>,
+}
+
+impl FilePolicy {
+    // Symlinks are resolved before matching, a file that doesn't exist yet
+    // is checked through its parent directory. A dangling symlink is denied,
+    // writing through it would create its target wherever that is
+    pub fn check(&self, path: &Path) -> Result<PathBuf> {
+        match std::fs::canonicalize(path) {
+            Ok(resolved) => self.confine(path, resolved),
+            Err(_) => match std::fs::symlink_metadata(path) {
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => self.check_entry(path),
+                _ => Err(anyhow!("Access to {} is not allowed", path.display())),
+            },
+        }
+    }
+
+    // Only the parent is resolved, a symlink at `path` itself is not
+    // followed. For calls on the directory entry like remove and rename
+    pub fn check_entry(&self, path: &Path) -> Result<PathBuf> {
+        let denied = || anyhow!("Access to {} is not allowed", path.display());
+        let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+            return Err(denied());
+        };
+        let parent = if parent.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            parent
+        };
+        let resolved = std::fs::canonicalize(parent)
+            .map_err(|_| denied())?
+            .join(name);
+        self.confine(path, resolved)
+    }
+
+    pub fn is_root(&self, resolved: &Path) -> bool {
+        self.roots
+            .iter()
+            .any(|root| std::fs::canonicalize(root).is_ok_and(|root| root == resolved))
+    }
+
+    fn confine(&self, path: &Path, resolved: PathBuf) -> Result<PathBuf> {
+        let allowed = self
+            .roots
+            .iter()
+            .any(|root| std::fs::canonicalize(root).is_ok_and(|root| resolved.starts_with(root)));
+        if !allowed {
+            return Err(anyhow!("Access to {} is not allowed", path.display()));
+        }
+        Ok(resolved)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(host: Option<&str>, cidr: Option<&str>, ports: Option<&str>) -> ForwardRule {
+        ForwardRule {
+            host: host.map(Into::into),
+            cidr: cidr.map(|cidr| cidr.parse().unwrap()),
+            ports: ports.map(Into::into),
+            protocol: Protocol::Tcp,
+        }
+    }
+
+    fn target(host: &str, port: u16) -> Target {
+        Target {
+            host: host.into(),
+            port,
+        }
+    }
+
+    // Fresh directory per test under the system temp dir
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("policy-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn targets_parse_and_print() {
+        assert_eq!(
+            "localhost:22".parse::<Target>().unwrap(),
+            target("localhost", 22)
+        );
+        assert_eq!("[::1]:8080".parse::<Target>().unwrap(), target("::1", 8080));
+        assert_eq!(target("::1", 8080).to_string(), "[::1]:8080");
+        assert_eq!(target("10.0.0.1", 80).to_string(), "10.0.0.1:80");
+        assert!("localhost".parse::<Target>().is_err());
+        assert!(":22".parse::<Target>().is_err());
+        assert!("localhost:http".parse::<Target>().is_err());
+        assert!("localhost:65536".parse::<Target>().is_err());
+    }
+
+    #[test]
+    fn endpoints_parse_and_print() {
+        assert_eq!(
+            "unix:/run/app.sock".parse::<Endpoint>().unwrap(),
+            Endpoint::Unix("/run/app.sock".into())
+        );
+        assert_eq!(
+            "localhost:5432".parse::<Endpoint>().unwrap(),
+            Endpoint::Tcp(target("localhost", 5432))
+        );
+        assert_eq!(
+            Endpoint::Unix("/run/app.sock".into()).to_string(),
+            "unix:/run/app.sock"
+        );
+        assert!("unix:".parse::<Endpoint>().is_err());
+        assert!("/run/app.sock".parse::<Endpoint>().is_err());
+    }
+
+    #[test]
+    fn port_specs() {
+        assert!(port_matches(None, 1));
+        assert!(port_matches(Some("22"), 22));
+        assert!(!port_matches(Some("22"), 23));
+        assert!(port_matches(Some("5432-5440"), 5432));
+        assert!(port_matches(Some("5432-5440"), 5440));
+        assert!(!port_matches(Some("5432-5440"), 5441));
+        assert!(port_matches(Some("22, 8000 - 8080"), 8080));
+        assert!(!port_matches(Some("8080-8000"), 8040));
+        assert!(!port_matches(Some("x-8080"), 8040));
+        assert!(!port_matches(Some(""), 0));
+    }
+
+    #[test]
+    fn rules_match_every_given_field() {
+        let ip: IpAddr = "10.1.2.3".parse().unwrap();
+        let db = target("db.internal", 5432);
+        assert!(rule(None, None, None).matches(&db, Protocol::Tcp, ip));
+        assert!(rule(Some("DB.internal"), None, None).matches(&db, Protocol::Tcp, ip));
+        assert!(!rule(Some("web.internal"), None, None).matches(&db, Protocol::Tcp, ip));
+        assert!(rule(None, Some("10.0.0.0/8"), None).matches(&db, Protocol::Tcp, ip));
+        assert!(!rule(None, Some("192.168.0.0/16"), None).matches(&db, Protocol::Tcp, ip));
+        assert!(rule(None, None, Some("5432")).matches(&db, Protocol::Tcp, ip));
+        assert!(!rule(None, None, Some("22")).matches(&db, Protocol::Tcp, ip));
+        assert!(
+            !rule(Some("db.internal"), Some("192.168.0.0/16"), Some("5432")).matches(
+                &db,
+                Protocol::Tcp,
+                ip
+            )
+        );
+    }
+
+    #[test]
+    fn rules_only_allow_their_protocol() {
+        let ip: IpAddr = "10.1.2.3".parse().unwrap();
+        let dns = target("10.1.2.3", 53);
+        let tcp = rule(None, None, Some("53"));
+        assert!(!tcp.matches(&dns, Protocol::Udp, ip));
+        let udp = ForwardRule {
+            protocol: Protocol::Udp,
+            ..tcp
+        };
+        assert!(udp.matches(&dns, Protocol::Udp, ip));
+        assert!(!udp.matches(&dns, Protocol::Tcp, ip));
+    }
+
+    #[tokio::test]
+    async fn resolve_keeps_only_allowed_addresses() {
+        let policy = ForwardPolicy {
+            rules: vec![rule(None, Some("127.0.0.0/8"), Some("8000-8080"))],
+            unix_sockets: vec![],
+        };
+        let addrs = policy
+            .resolve(&target("127.0.0.1", 8000), Protocol::Tcp)
+            .await
+            .unwrap();
+        assert_eq!(addrs, vec!["127.0.0.1:8000".parse().unwrap()]);
+        assert!(policy
+            .resolve(&target("127.0.0.1", 22), Protocol::Tcp)
+            .await
+            .is_err());
+        assert!(policy
+            .resolve(&target("::1", 8000), Protocol::Tcp)
+            .await
+            .is_err());
+        assert!(policy
+            .resolve(&target("127.0.0.1", 8000), Protocol::Udp)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn default_policy_only_allows_ssh() {
+        let policy = ForwardPolicy::default();
+        assert!(policy
+            .resolve(&target(SSH_SERVER_HOST, SSH_SERVER_PORT), Protocol::Tcp)
+            .await
+            .is_ok());
+        assert!(policy
+            .resolve(&target(SSH_SERVER_HOST, 80), Protocol::Tcp)
+            .await
+            .is_err());
+    }
+
+    fn http_policy(path_prefix: &str, methods: Option<&[&str]>) -> HttpPolicy {
+        HttpPolicy {
+            routes: vec![HttpRoute {
+                host: "localhost".into(),
+                port: 8080,
+                path_prefix: path_prefix.into(),
+                methods: methods.map(|methods| methods.iter().map(|m| m.to_string()).collect()),
+            }],
+        }
+    }
+
+    #[test]
+    fn prefixes_match_whole_segments() {
+        assert!(prefix_matches("/api", "/api"));
+        assert!(prefix_matches("/api/users", "/api"));
+        assert!(!prefix_matches("/apix", "/api"));
+        assert!(prefix_matches("/api/users", "/api/"));
+        assert!(!prefix_matches("/api", "/api/"));
+        assert!(prefix_matches("/anything", "/"));
+        assert!(!prefix_matches("/other", "/api"));
+    }
+
+    #[test]
+    fn http_routes_match_host_port_and_method() {
+        let app = target("LOCALHOST", 8080);
+        let policy = http_policy("/api", Some(&["get"]));
+        assert!(policy.allows(&app, "GET", "/api/users?page=2"));
+        assert!(policy.allows(&app, "GET", "/api#top"));
+        assert!(!policy.allows(&app, "POST", "/api/users"));
+        assert!(!policy.allows(&app, "GET", "/admin"));
+        assert!(!policy.allows(&app, "GET", "/apix"));
+        assert!(!policy.allows(&app, "GET", "api/users"));
+        assert!(!policy.allows(&target("localhost", 8081), "GET", "/api"));
+        assert!(!policy.allows(&target("example.com", 8080), "GET", "/api"));
+        assert!(http_policy("/", None).allows(&app, "DELETE", "/anything"));
+        assert!(!HttpPolicy::default().allows(&app, "GET", "/"));
+    }
+
+    #[test]
+    fn http_paths_cannot_escape_their_prefix() {
+        let app = target("localhost", 8080);
+        let policy = http_policy("/api", None);
+        for path in [
+            "/api/../admin",
+            "/api/./../admin",
+            "/api/%2e%2e/admin",
+            "/api/%2E./admin",
+            "/api/..%2fadmin",
+            "/api/%2e%2e%2fadmin",
+            "/api/..%2Fadmin",
+            "/api/..%5cadmin",
+            "/api\\..\\admin",
+            "/api/..",
+        ] {
+            assert!(!policy.allows(&app, "GET", path), "{path}");
+        }
+        assert!(policy.allows(&app, "GET", "/api/..name/x"));
+        assert!(policy.allows(&app, "GET", "/api/file.tar.gz"));
+    }
+
+    // A root with a file and a subdirectory, next to a directory outside it
+    fn file_policy(name: &str) -> (PathBuf, FilePolicy) {
+        let dir = scratch(name).canonicalize().unwrap();
+        std::fs::create_dir_all(dir.join("root/sub")).unwrap();
+        std::fs::create_dir_all(dir.join("outside")).unwrap();
+        std::fs::write(dir.join("root/file"), b"").unwrap();
+        std::fs::write(dir.join("outside/secret"), b"").unwrap();
+        let policy = FilePolicy {
+            roots: vec![dir.join("root")],
+        };
+        (dir, policy)
+    }
+
+    #[test]
+    fn files_must_be_under_a_root() {
+        let (dir, policy) = file_policy("files");
+        let root = dir.join("root");
+        assert_eq!(policy.check(&root.join("file")).unwrap(), root.join("file"));
+        assert_eq!(
+            policy.check(&root.join("sub/new")).unwrap(),
+            root.join("sub/new")
+        );
+        assert!(policy.check(&root).is_ok());
+        assert!(policy.check(&root.join("../outside/secret")).is_err());
+        assert!(policy
+            .check(&root.join("sub/../../outside/secret"))
+            .is_err());
+        assert!(policy.check(&root.join("../outside/new")).is_err());
+        assert!(policy.check(&dir.join("outside/secret")).is_err());
+        assert!(policy.check(Path::new("/etc/passwd")).is_err());
+        assert!(policy.check(&root.join("missing/new")).is_err());
+        assert!(FilePolicy::default().check(&root.join("file")).is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_are_resolved_before_matching() {
+        let (dir, policy) = file_policy("symlinks");
+        let root = dir.join("root");
+        std::os::unix::fs::symlink(dir.join("outside"), root.join("parent")).unwrap();
+        std::os::unix::fs::symlink(dir.join("outside/secret"), root.join("link")).unwrap();
+        std::os::unix::fs::symlink(dir.join("outside/gone"), root.join("dangling")).unwrap();
+        std::os::unix::fs::symlink(root.join("file"), root.join("inner")).unwrap();
+
+        assert!(policy.check(&root.join("parent/secret")).is_err());
+        assert!(policy.check(&root.join("parent/new")).is_err());
+        assert!(policy.check_entry(&root.join("parent/secret")).is_err());
+        assert!(policy.check(&root.join("link")).is_err());
+        assert!(policy.check(&root.join("dangling")).is_err());
+        assert_eq!(
+            policy.check(&root.join("inner")).unwrap(),
+            root.join("file")
+        );
+        // The entry itself is not followed, removing a link is fine
+        assert_eq!(
+            policy.check_entry(&root.join("link")).unwrap(),
+            root.join("link")
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn only_the_roots_themselves_are_roots() {
+        let (dir, policy) = file_policy("roots");
+        assert!(policy.is_root(&dir.join("root")));
+        assert!(!policy.is_root(&dir.join("root/sub")));
+        assert!(!policy.is_root(&dir));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_sockets_must_be_under_an_allowed_path() {
+        let dir = scratch("unix");
+        let allowed = dir.join("allowed");
+        let other = dir.join("other");
+        std::fs::create_dir_all(&allowed).unwrap();
+        std::fs::create_dir_all(&other).unwrap();
+        let _inside = std::os::unix::net::UnixListener::bind(allowed.join("app.sock")).unwrap();
+        let _outside = std::os::unix::net::UnixListener::bind(other.join("app.sock")).unwrap();
+        std::fs::write(allowed.join("file"), b"").unwrap();
+        std::os::unix::fs::symlink(other.join("app.sock"), allowed.join("link.sock")).unwrap();
+        let policy = ForwardPolicy {
+            rules: vec![],
+            unix_sockets: vec![allowed.clone()],
+        };
+
+        let resolved = policy.check_unix(&allowed.join("app.sock")).unwrap();
+        assert_eq!(resolved, allowed.canonicalize().unwrap().join("app.sock"));
+        assert!(policy.check_unix(&other.join("app.sock")).is_err());
+        assert!(policy.check_unix(&allowed.join("link.sock")).is_err());
+        assert!(policy
+            .check_unix(&allowed.join("../other/app.sock"))
+            .is_err());
+        assert!(policy.check_unix(&allowed.join("file")).is_err());
+        assert!(policy.check_unix(&allowed.join("missing.sock")).is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
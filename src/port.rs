@@ -1,35 +1,172 @@
-use bytes::Bytes;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use std::sync::Arc;
+
+use anyhow::Result;
+use bytes::{BufMut, Bytes, BytesMut};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
 use tokio::sync::mpsc::Receiver;
 
-use tokio::sync::{broadcast, mpsc};
+use tokio::sync::{broadcast, mpsc, oneshot, Notify};
+use webrtc::data_channel::data_channel_message::DataChannelMessage;
+use webrtc::peer_connection::RTCPeerConnection;
+
+use crate::compression::{Compression, Compressor, Decompressor};
+use crate::peer::{DataChannelAckMsg, DataChannelSettingsMsg};
+use crate::policy::{Endpoint, ForwardPolicy, Protocol, Target};
+use crate::utils::to_json;
+
+pub const SSH_SERVER_HOST: &str = "localhost";
+pub const SSH_SERVER_PORT: u16 = 22;
+
+// webrtc-rs sends sctp messages up to 64KiB, whatever the remote allows
+const SCTP_MAX_MESSAGE: usize = 64 * 1024;
+// Room for the compression framing on top of a full chunk
+const CHUNK_HEADROOM: usize = 1024;
+// One read fills at most one message, used where nothing was negotiated
+pub const MAX_CHUNK: usize = SCTP_MAX_MESSAGE - CHUNK_HEADROOM;
+
+// Chunk size for the negotiated sctp max message size, asked once the
+// connection is up. 0 means no limit for both the transport and the sdp
+pub async fn max_chunk(pc: &RTCPeerConnection) -> usize {
+    let transport = pc.sctp().get_capabilities().max_message_size as usize;
+    let remote = pc.remote_description().await.and_then(|desc| {
+        desc.sdp
+            .lines()
+            .find_map(|line| line.strip_prefix("a=max-message-size:"))
+            .and_then(|size| size.trim().parse::<usize>().ok())
+    });
+    let negotiated = [Some(transport), remote]
+        .into_iter()
+        .flatten()
+        .filter(|&size| size > 0)
+        .fold(SCTP_MAX_MESSAGE, usize::min);
+    negotiated
+        .saturating_sub(CHUNK_HEADROOM)
+        .max(negotiated / 2)
+}
+
+// An empty message on a port channel is EOF of that direction, real data
+// is never empty. Each side shuts down its write half on EOF and keeps
+// reading, the channel is closed once both directions are done
+pub fn is_eof(data: &[u8]) -> bool {
+    data.is_empty()
+}
+
+// Anything the pumps can move bytes through, tcp or unix sockets
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+pub type BoxedStream = Box<dyn Stream>;
+
+// Dials an endpoint after the policy allowed it
+async fn connect(target: &Endpoint, policy: &ForwardPolicy) -> Result<BoxedStream> {
+    match target {
+        Endpoint::Tcp(target) => {
+            let addrs = policy.resolve(target, Protocol::Tcp).await?;
+            Ok(Box::new(TcpStream::connect(&addrs[..]).await?))
+        }
+        #[cfg(unix)]
+        Endpoint::Unix(path) => {
+            let path = policy.check_unix(path)?;
+            Ok(Box::new(UnixStream::connect(path).await?))
+        }
+        #[cfg(not(unix))]
+        Endpoint::Unix(_) => Err(anyhow::anyhow!(
+            "Unix sockets are not supported on this platform"
+        )),
+    }
+}
+
+// A socket file left behind by an earlier run makes bind fail. Only sockets
+// nobody accepts on are removed, anything else is left for bind to report
+#[cfg(unix)]
+fn remove_stale_socket(path: &std::path::Path) -> Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let is_socket = std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket());
+    if is_socket && std::os::unix::net::UnixStream::connect(path).is_err() {
+        log::info!("Removing stale socket {}", path.display());
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+// Client side listener of -L and -D forwards
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    pub async fn bind(addr: &str) -> Result<Self> {
+        match addr.parse::<Endpoint>()? {
+            Endpoint::Tcp(target) => Ok(Self::Tcp(TcpListener::bind(target.to_string()).await?)),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => {
+                remove_stale_socket(&path)?;
+                Ok(Self::Unix(UnixListener::bind(path)?))
+            }
+            #[cfg(not(unix))]
+            Endpoint::Unix(_) => Err(anyhow::anyhow!(
+                "Unix sockets are not supported on this platform"
+            )),
+        }
+    }
+
+    pub async fn accept(&self) -> io::Result<BoxedStream> {
+        match self {
+            Self::Tcp(listener) => Ok(Box::new(listener.accept().await?.0)),
+            #[cfg(unix)]
+            Self::Unix(listener) => Ok(Box::new(listener.accept().await?.0)),
+        }
+    }
+}
 
 pub async fn handle_port(
-    tx: broadcast::Sender<Bytes>,  // From server to clients
-    mut rx: mpsc::Receiver<Bytes>, // From clients to server
-    _done_rx: broadcast::Receiver<()>,
+    tx: mpsc::Sender<Bytes>,   // From server to the client
+    rx: mpsc::Receiver<Bytes>, // From clients to server
+    done_rx: broadcast::Receiver<()>,
+    target: Endpoint,
+    policy: ForwardPolicy,
+    max_chunk: usize,
 ) {
-    const SSH_SERVER_HOST: &str = "localhost";
-    const SSH_SERVER_PORT: u16 = 22;
-
-    // Create a TCP connection to the SSH server
-    let ssh_addr = format!("{}:{}", SSH_SERVER_HOST, SSH_SERVER_PORT);
-    let tcp_stream = match TcpStream::connect(&ssh_addr).await {
+    let stream = match connect(&target, &policy).await {
         Ok(s) => {
-            log::info!("Connected to SSH server");
+            log::info!("Connected to {}", target);
             s
         }
         Err(e) => {
-            log::error!("TCP connect error: {}", e);
+            log::error!("Refusing port channel to {}: {}", target, e);
             return;
         }
     };
 
-    let (mut tcp_reader, mut tcp_writer) = tcp_stream.into_split();
+    pump(tx, rx, done_rx, stream, &target, max_chunk).await;
+
+    log::info!("handle_port exiting");
+}
+
+// Moves bytes between a channel and a connected stream until both
+// directions saw EOF or the session ended
+pub async fn pump(
+    tx: mpsc::Sender<Bytes>,
+    mut rx: mpsc::Receiver<Bytes>,
+    mut done_rx: broadcast::Receiver<()>,
+    stream: BoxedStream,
+    target: &impl std::fmt::Display,
+    max_chunk: usize,
+) {
+    let (mut tcp_reader, mut tcp_writer) = io::split(stream);
 
     let ws_to_tcp = async {
         while let Some(msg) = rx.recv().await {
+            if is_eof(&msg) {
+                break;
+            }
             if let Err(e) = tcp_writer.write_all(&msg).await {
                 log::error!("TCP write error: {}", e);
                 break;
@@ -41,13 +178,12 @@ pub async fn handle_port(
 
     // Handle TCP socket data and send it over the WebSocket
     let tcp_to_ws = async {
-        let mut buffer = [0u8; 1024];
+        let mut buffer = BytesMut::with_capacity(max_chunk);
         loop {
-            match tcp_reader.read(&mut buffer).await {
+            match read_chunk(&mut tcp_reader, &mut buffer, max_chunk).await {
                 Ok(0) => break,
-                Ok(n) => {
-                    let data = Bytes::copy_from_slice(&buffer[..n]);
-                    if let Err(e) = tx.send(data) {
+                Ok(_) => {
+                    if let Err(e) = tx.send(buffer.split().freeze()).await {
                         log::error!("WebSocket send error: {}", e);
                         break;
                     }
@@ -58,14 +194,239 @@ pub async fn handle_port(
                 }
             }
         }
-        // tx.close().await.ok();
-        log::info!("Disconnected from SSH server");
+        let _ = tx.send(Bytes::new()).await;
+        log::info!("Disconnected from {}", target);
     };
 
+    // The session ending (channel closed) cuts both directions short
     tokio::select! {
-        _ = ws_to_tcp => (),
-        _ = tcp_to_ws => (),
+        _ = async { tokio::join!(ws_to_tcp, tcp_to_ws) } => (),
+        _ = done_rx.recv() => (),
     }
+}
 
-    log::info!("handle_port exiting");
+// Reads at most one message worth into `buffer`. Chunks split off it are
+// sent without copying, and its allocation is reused once they were dropped
+pub async fn read_chunk<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    buffer: &mut BytesMut,
+    max_chunk: usize,
+) -> io::Result<usize> {
+    buffer.reserve(max_chunk);
+    reader.read_buf(&mut buffer.limit(max_chunk)).await
+}
+
+pub type PendingAck = std::sync::Mutex<Option<oneshot::Sender<Option<Compression>>>>;
+
+// The first message on a channel that offered compression is the server ack
+pub fn decode_message(
+    pending_ack: &PendingAck,
+    decompressor: &std::sync::Mutex<Option<Decompressor>>,
+    data: Bytes,
+) -> Result<Option<Bytes>> {
+    if let Some(ack_tx) = pending_ack.lock().unwrap().take() {
+        let ack: DataChannelAckMsg = serde_json::from_slice(&data)?;
+        if let Some(compression) = ack.compression {
+            *decompressor.lock().unwrap() = Some(Decompressor::new(compression)?);
+        }
+        let _ = ack_tx.send(ack.compression);
+        return Ok(None);
+    }
+    match decompressor.lock().unwrap().as_mut() {
+        Some(decompressor) if !is_eof(&data) => decompressor.decompress(&data).map(Some),
+        _ => Ok(Some(data)),
+    }
+}
+
+// Pumps a local stream through a new port channel opened from this side
+pub async fn forward_stream<S>(
+    peer_connection: Arc<RTCPeerConnection>,
+    tcp_stream: S,
+    settings: DataChannelSettingsMsg,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let compression = settings
+        .compression
+        .as_ref()
+        .and_then(|offer| offer.first().copied());
+    let label = to_json(settings)?;
+    let max_chunk = max_chunk(&peer_connection).await;
+    let d = peer_connection.create_data_channel(&label, None).await?;
+
+    let (to_pty_tx, mut to_pty_rx) = mpsc::channel::<Bytes>(100);
+
+    let (done_tx, mut done_rx) = mpsc::channel::<()>(1);
+
+    let (ack_tx, ack_rx) = oneshot::channel::<Option<Compression>>();
+    let pending_ack: Arc<PendingAck> = Arc::new(std::sync::Mutex::new(compression.map(|_| ack_tx)));
+    let decompressor = Arc::new(std::sync::Mutex::new(None::<Decompressor>));
+    let closed = Arc::new(Notify::new());
+
+    let done_tx2 = done_tx.clone();
+    let closed2 = closed.clone();
+    d.on_close(Box::new(move || {
+        closed2.notify_one();
+        let _ = done_tx2.try_send(());
+        Box::pin(async {})
+    }));
+
+    d.on_message(Box::new(move |msg: DataChannelMessage| {
+        let ptx_clone = to_pty_tx.clone();
+        let data = decode_message(&pending_ack, &decompressor, msg.data);
+
+        Box::pin(async move {
+            match data {
+                Result::Ok(Some(data)) => {
+                    // Send the message to the PTY task asynchronously
+                    if let Err(e) = ptx_clone.send(data).await {
+                        log::error!("Failed to send message to PTY: {}", e);
+                    }
+                }
+                Result::Ok(None) => (),
+                Err(e) => log::error!("Failed to decode message: {}", e),
+            }
+        })
+    }));
+
+    let d2 = d.clone();
+
+    d.on_open(Box::new(move || {
+        let d_clone = Arc::clone(&d2); // Clone the Arc to use in the async block
+        Box::pin(async move {
+            let (mut tcp_reader, mut tcp_writer) = io::split(tcp_stream);
+
+            // Without an offer the sender is dropped and nothing is negotiated
+            let negotiated = ack_rx.await.ok().flatten();
+            log::info!("Port channel compression {:?}", negotiated);
+            let mut compressor = match negotiated.map(Compressor::new).transpose() {
+                Result::Ok(compressor) => compressor,
+                Err(e) => {
+                    log::error!("Failed to create compressor: {}", e);
+                    let _ = done_tx.send(()).await;
+                    return;
+                }
+            };
+
+            let tcp_to_ws = async {
+                let mut buffer = BytesMut::with_capacity(max_chunk);
+                loop {
+                    match read_chunk(&mut tcp_reader, &mut buffer, max_chunk).await {
+                        Result::Ok(0) => break,
+                        Result::Ok(_) => {
+                            let data = match compressor.as_mut() {
+                                Some(compressor) => match compressor.compress(&buffer) {
+                                    Result::Ok(data) => {
+                                        buffer.clear();
+                                        data
+                                    }
+                                    Err(e) => {
+                                        log::error!("Failed to compress message: {}", e);
+                                        break;
+                                    }
+                                },
+                                None => buffer.split().freeze(),
+                            };
+                            if let Err(e) = d_clone.send(&data).await {
+                                log::error!("WebSocket send error: {}", e);
+                                break;
+                            }
+                        }
+                        Err(e) => {
+                            log::error!("TCP read error: {}", e);
+                            break;
+                        }
+                    }
+                }
+                if let Err(e) = d_clone.send(&Bytes::new()).await {
+                    log::error!("Failed to send EOF: {}", e);
+                }
+                log::info!("TCP client disconnected");
+            };
+
+            let ws_to_tcp = async {
+                while let Some(msg) = to_pty_rx.recv().await {
+                    if is_eof(&msg) {
+                        break;
+                    }
+                    if let Err(e) = tcp_writer.write_all(&msg).await {
+                        log::error!("TCP write error: {}", e);
+                        break;
+                    }
+                }
+                tcp_writer.shutdown().await.ok();
+                log::info!("WebSocket connection closed");
+            };
+
+            tokio::select! {
+                _ = async { tokio::join!(tcp_to_ws, ws_to_tcp) } => (),
+                _ = closed.notified() => log::info!("Port channel closed by peer"),
+            }
+            let _ = done_tx.send(()).await;
+        })
+    }));
+
+    done_rx.recv().await;
+    d.close().await?;
+
+    log::info!("connection end");
+
+    Ok(())
+}
+
+// Server side of `-R`: listens on `bind` and sends every accepted connection
+// back to the peer as a port channel to `target`
+pub async fn handle_listen(
+    pc: Arc<RTCPeerConnection>,
+    mut done_rx: broadcast::Receiver<()>,
+    bind: Target,
+    target: String,
+    policy: ForwardPolicy,
+) {
+    let addrs = match policy.resolve(&bind, Protocol::Tcp).await {
+        Ok(addrs) => addrs,
+        Err(e) => {
+            log::error!("Refusing listen channel: {}", e);
+            return;
+        }
+    };
+
+    let listener = match TcpListener::bind(&addrs[..]).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            log::error!("Failed to listen on {}: {}", bind, e);
+            return;
+        }
+    };
+    log::info!("Listening on {} for {}", bind, target);
+
+    loop {
+        let tcp_stream = tokio::select! {
+            _ = done_rx.recv() => break,
+            accepted = listener.accept() => match accepted {
+                Ok((tcp_stream, addr)) => {
+                    log::info!("Reverse connection from {} to {}", addr, target);
+                    tcp_stream
+                }
+                Err(e) => {
+                    log::error!("Accept error on {}: {}", bind, e);
+                    break;
+                }
+            },
+        };
+        let settings = DataChannelSettingsMsg {
+            variant: "port".into(),
+            target: Some(target.clone()),
+            ..Default::default()
+        };
+        let pc = pc.clone();
+        tokio::spawn(async move {
+            if let Err(e) = forward_stream(pc, tcp_stream, settings).await {
+                log::error!("Reverse forward failed: {}", e);
+            }
+        });
+    }
+
+    log::info!("Stopped listening on {}", bind);
 }
@@ -1,11 +1,13 @@
 use anyhow::anyhow;
 use anyhow::Result;
 use bytes::Bytes;
+use std::collections::HashMap;
 use std::io::ErrorKind::WouldBlock;
-use std::sync::Arc;
+use std::sync::mpsc as std_mpsc;
+use std::sync::{Arc, Mutex};
 use std::time::{self, Instant};
 use tokio::runtime::Handle;
-use tokio::sync::broadcast;
+use tokio::sync::{broadcast, oneshot};
 use tokio::task;
 use tokio::time::Duration;
 use webrtc::api::media_engine::{
@@ -13,9 +15,10 @@ use webrtc::api::media_engine::{
 };
 use webrtc::media::Sample;
 
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicU32, Ordering};
 use webrtc::peer_connection::RTCPeerConnection;
 use webrtc::rtp_transceiver::rtp_codec::RTCRtpCodecCapability;
+use webrtc::rtp_transceiver::rtp_sender::RTCRtpSender;
 use webrtc::track::track_local::track_local_static_sample::TrackLocalStaticSample;
 use webrtc::track::track_local::TrackLocal;
 
@@ -24,79 +27,169 @@ use gst::prelude::*;
 use gstreamer as gst;
 use gstreamer_app as gst_app;
 
-use crate::control::StartVideoMsg;
+use crate::control::{StartVideoMsg, UpdateVideoMsg, VideoStream};
 
-// Add a single video track
+// What the capture thread of a stream is told while it runs
+enum VideoControl {
+    Pause,
+    Resume,
+    Update(UpdateVideoMsg),
+    Stop,
+}
+
+// A running stream, kept so later control messages can reach it. Only the
+// peer that started it may touch it
+pub struct Video {
+    pc: Arc<RTCPeerConnection>,
+    sender: Arc<RTCRtpSender>,
+    controls: std_mpsc::Sender<VideoControl>,
+    display_id: u32,
+    // Dropped with the stream, tells whoever waits on it that it stopped
+    _stopped: oneshot::Sender<()>,
+}
+
+pub type VideoMap = Arc<Mutex<HashMap<u32, Video>>>;
+
+static NEXT_VIDEO_ID: AtomicU32 = AtomicU32::new(1);
+
+// Add a single video track, it runs until StopVideo or the session ends.
+// `stopped` is closed once it did
 pub async fn add_video(
     pc: Arc<RTCPeerConnection>,
     msg: StartVideoMsg,
+    monitor: u64,
+    videos: VideoMap,
     mut done_rx: broadcast::Receiver<()>,
-) -> Result<()> {
+    stopped: oneshot::Sender<()>,
+) -> Result<VideoStream> {
+    let id = NEXT_VIDEO_ID.fetch_add(1, Ordering::Relaxed);
+    let display_id = msg.display_id;
+    let track_id = format!("video-{}", id);
     let video_track = Arc::new(TrackLocalStaticSample::new(
         RTCRtpCodecCapability {
             mime_type: MIME_TYPE_H264.to_owned(),
             ..Default::default()
         },
-        format!("video-{}", rand::random::<u32>()),
-        format!("video-{}", rand::random::<u32>()),
+        track_id.clone(),
+        track_id.clone(),
     ));
 
-    let rtp_sender = match pc
+    let rtp_sender = pc
         .add_track(Arc::clone(&video_track) as Arc<dyn TrackLocal + Send + Sync>)
-        .await
-    {
-        Ok(rtp_sender) => rtp_sender,
-        Err(err) => panic!("{}", err),
-    };
+        .await?;
 
     // Read incoming RTCP packets
     // Before these packets are returned they are processed by interceptors. For things
     // like NACK this needs to be called.
+    let rtcp_sender = rtp_sender.clone();
     tokio::spawn(async move {
         let mut rtcp_buf = vec![0u8; 1500];
-        while let Ok((_, _)) = rtp_sender.read(&mut rtcp_buf).await {}
+        while let Ok((_, _)) = rtcp_sender.read(&mut rtcp_buf).await {}
         Result::<()>::Ok(())
     });
 
-    // This bool can be used to stop the loop from another place if you want.
-    let stop_flag = Arc::new(AtomicBool::new(false));
-    let stop_flag_clone = stop_flag.clone();
-    // Clone track and stop-flag for the blocking thread:
+    let (controls, controls_rx) = std_mpsc::channel();
+    videos.lock().unwrap().insert(
+        id,
+        Video {
+            pc: pc.clone(),
+            sender: rtp_sender,
+            controls,
+            display_id,
+            _stopped: stopped,
+        },
+    );
+
     tokio::spawn(async move {
-        if let Err(e) = done_rx.recv().await.map_err(|e| anyhow!(e)).and_then(|_| {
-            stop_flag_clone.swap(true, Ordering::Acquire);
-            tokio::spawn(async move {
-                if let Err(e) = remove_video(pc).await {
-                    log::error!("Failed to remove display: {}", e);
-                }
-            });
-            Ok(())
-        }) {
-            log::error!("Some error idc 2: {}", e);
+        let _ = done_rx.recv().await;
+        // Usually gone already through StopVideo
+        if let Err(e) = stop_video(&videos, &pc, id).await {
+            log::debug!("Video {} not stopped at session end: {}", id, e);
         }
     });
 
     tokio::spawn(async move {
-        let res = write_video_to_track2(video_track, msg, stop_flag).await;
+        let res = write_video_to_track2(video_track, msg, monitor, controls_rx).await;
         dbg!(res);
     });
 
     println!("Video track has been added");
-    Ok(())
+    Ok(VideoStream {
+        id,
+        display_id,
+        track_id,
+    })
 }
 
-// Remove a single sender
-async fn remove_video(pc: Arc<RTCPeerConnection>) -> Result<()> {
-    let senders = pc.get_senders().await;
-    if !senders.is_empty() {
-        if let Err(err) = pc.remove_track(&senders[0]).await {
-            panic!("{}", err);
+// Whether any running stream still captures the display
+pub fn shows_display(videos: &VideoMap, display_id: u32) -> bool {
+    videos
+        .lock()
+        .unwrap()
+        .values()
+        .any(|video| video.display_id == display_id)
+}
+
+fn send_control(
+    videos: &VideoMap,
+    pc: &Arc<RTCPeerConnection>,
+    id: u32,
+    control: VideoControl,
+) -> Result<()> {
+    let videos = videos.lock().unwrap();
+    let video = videos
+        .get(&id)
+        .filter(|video| Arc::ptr_eq(&video.pc, pc))
+        .ok_or(anyhow!("Video {} not found", id))?;
+    video
+        .controls
+        .send(control)
+        .map_err(|_| anyhow!("Video {} has stopped", id))
+}
+
+// Removes exactly this stream's sender, other streams keep running
+pub async fn stop_video(videos: &VideoMap, pc: &Arc<RTCPeerConnection>, id: u32) -> Result<()> {
+    let video = {
+        let mut videos = videos.lock().unwrap();
+        match videos.get(&id) {
+            Some(video) if Arc::ptr_eq(&video.pc, pc) => videos.remove(&id).unwrap(),
+            _ => return Err(anyhow!("Video {} not found", id)),
         }
-    }
+    };
+    let _ = video.controls.send(VideoControl::Stop);
+    pc.remove_track(&video.sender).await?;
 
     println!("Video track has been removed");
     Ok(())
 }
+
+// The track stays, it just gets no frames until resumed
+pub fn pause_video(
+    videos: &VideoMap,
+    pc: &Arc<RTCPeerConnection>,
+    id: u32,
+    paused: bool,
+) -> Result<()> {
+    let control = if paused {
+        VideoControl::Pause
+    } else {
+        VideoControl::Resume
+    };
+    send_control(videos, pc, id, control)
+}
+
+// Applied to the running pipeline, the track and its negotiation stay as they are
+pub fn update_video(
+    videos: &VideoMap,
+    pc: &Arc<RTCPeerConnection>,
+    msg: UpdateVideoMsg,
+) -> Result<()> {
+    if [msg.bitrate, msg.fps, msg.width, msg.height].contains(&Some(0)) {
+        return Err(anyhow!("Video settings can't be zero"));
+    }
+    send_control(videos, pc, msg.id, VideoControl::Update(msg))
+}
+
 #[derive(Debug)]
 struct CapturedFrame {
     data: Bytes,
@@ -106,7 +199,8 @@ struct CapturedFrame {
 pub async fn write_video_to_track2(
     track: Arc<TrackLocalStaticSample>,
     msg: StartVideoMsg,
-    stop_flag: Arc<AtomicBool>,
+    monitor: u64,
+    controls: std_mpsc::Receiver<VideoControl>,
 ) -> Result<()> {
     dbg!("sus????");
 
@@ -115,7 +209,7 @@ pub async fn write_video_to_track2(
 
     // Spawn a blocking thread to do the capturing:
     task::spawn_blocking(move || {
-        if let Err(e) = capture_loop_gstreamer(track_clone, msg, stop_flag, &handle) {
+        if let Err(e) = capture_loop_gstreamer(track_clone, msg, monitor, controls, &handle) {
             eprintln!("Capture loop error: {:?}", e);
         }
     });
@@ -123,29 +217,74 @@ pub async fn write_video_to_track2(
     Ok(())
 }
 
+fn element(pipeline: &gst::Pipeline, name: &str) -> Result<gst::Element> {
+    pipeline
+        .by_name(name)
+        .ok_or_else(|| anyhow!("Failed to find {} in pipeline", name))
+}
+
+// New caps on the capsfilters make videoscale and videorate adapt, the
+// encoder picks up the bitrate on the fly and h264parse repeats SPS/PPS so
+// the receiver follows a size change without renegotiating
+fn update_pipeline(pipeline: &gst::Pipeline, msg: &UpdateVideoMsg) -> Result<()> {
+    if let Some(fps) = msg.fps {
+        let caps = gst::Caps::builder("video/x-raw")
+            .field("framerate", gst::Fraction::new(fps as i32, 1))
+            .build();
+        element(pipeline, "rate")?.set_property("caps", caps);
+    }
+    if msg.width.is_some() || msg.height.is_some() {
+        let mut caps = gst::Caps::builder("video/x-raw");
+        if let Some(width) = msg.width {
+            caps = caps.field("width", width as i32);
+        }
+        if let Some(height) = msg.height {
+            caps = caps.field("height", height as i32);
+        }
+        element(pipeline, "size")?.set_property("caps", caps.build());
+    }
+    if let Some(bitrate) = msg.bitrate {
+        element(pipeline, "encoder")?.set_property("bitrate", bitrate);
+    }
+    Ok(())
+}
+
 fn capture_loop_gstreamer(
     track: Arc<TrackLocalStaticSample>,
     msg: StartVideoMsg,
-    stop: Arc<AtomicBool>,
+    monitor: u64,
+    controls: std_mpsc::Receiver<VideoControl>,
     handle: &Handle,
 ) -> Result<()> {
-    let pipeline_str = r#"
-    d3d11screencapturesrc monitor-index=-1 show-cursor=true
-        ! video/x-raw,framerate=60/1
+    // Unset sizes keep the monitor's own, which the display was just set to
+    let mut size_caps = String::from("video/x-raw");
+    if let Some(width) = msg.width {
+        size_caps.push_str(&format!(",width={}", width));
+    }
+    if let Some(height) = msg.height {
+        size_caps.push_str(&format!(",height={}", height));
+    }
+    let pipeline_str = format!(
+        r#"
+    d3d11screencapturesrc monitor-handle={monitor} show-cursor=true
+        ! videorate
+        ! capsfilter name=rate caps="video/x-raw,framerate={fps}/1"
         ! videoscale
-        ! video/x-raw,width=[1,1920],height=[1,1080]
+        ! capsfilter name=size caps="{size_caps}"
         ! videoconvert
-        ! nvh264enc
+        ! nvh264enc name=encoder
             preset=p1
             tune=ultra-low-latency
             zerolatency=true
         ! h264parse
             config-interval=-1 
         ! appsink name=appsink emit-signals=true sync=false
-    "#;
+    "#,
+        fps = msg.refresh_rate.unwrap_or(60),
+    );
 
     // Build and downcast to a Pipeline
-    let pipeline = gst::parse::launch(pipeline_str)?;
+    let pipeline = gst::parse::launch(&pipeline_str)?;
     let pipeline = pipeline
         .dynamic_cast::<gst::Pipeline>()
         .map_err(|_| anyhow!("Failed to cast parsed element to Pipeline"))?;
@@ -164,15 +303,44 @@ fn capture_loop_gstreamer(
     let mut last_instant: Option<Instant> = None;
 
     // Pull encoded samples from the appsink in a loop
-    while !stop.load(Ordering::Acquire) {
-        // This will block until a new sample is ready, or end-of-stream/error
-        match appsink.pull_sample() {
-            Err(e) => {
-                dbg!(e);
-                // The pipeline might have hit EOS or an error
-                break;
+    'capture: loop {
+        loop {
+            match controls.try_recv() {
+                Ok(VideoControl::Pause) => {
+                    if let Err(e) = pipeline.set_state(gst::State::Paused) {
+                        log::error!("Failed to pause video: {}", e);
+                        break 'capture;
+                    }
+                }
+                Ok(VideoControl::Resume) => {
+                    if let Err(e) = pipeline.set_state(gst::State::Playing) {
+                        log::error!("Failed to resume video: {}", e);
+                        break 'capture;
+                    }
+                    // Don't stretch the first frame over the pause
+                    last_instant = Some(Instant::now());
+                }
+                Ok(VideoControl::Update(update)) => {
+                    if let Err(e) = update_pipeline(&pipeline, &update) {
+                        log::error!("Failed to update video {}: {}", update.id, e);
+                    }
+                }
+                Ok(VideoControl::Stop) | Err(std_mpsc::TryRecvError::Disconnected) => {
+                    break 'capture;
+                }
+                Err(std_mpsc::TryRecvError::Empty) => break,
             }
-            Ok(gst_sample) => {
+        }
+
+        // Wakes up regularly so controls are seen while paused
+        match appsink.try_pull_sample(gst::ClockTime::from_mseconds(100)) {
+            None => {
+                if appsink.is_eos() {
+                    // The pipeline hit EOS or an error
+                    break;
+                }
+            }
+            Some(gst_sample) => {
                 // Compute duration between frames for the webrtc::Sample
                 let now = Instant::now();
                 let duration = if let Some(prev) = last_instant {
@@ -182,15 +350,18 @@ fn capture_loop_gstreamer(
                 };
                 last_instant = Some(now);
 
-                // Extract the actual encoded buffer
-                let buffer = gst_sample
-                    .buffer()
-                    .ok_or_else(|| anyhow!("Failed to get buffer from GStreamer sample"))?;
+                // Extract the actual encoded buffer, a bad sample is skipped
+                // so the teardown below always runs
+                let Some(buffer) = gst_sample.buffer() else {
+                    log::error!("Failed to get buffer from GStreamer sample");
+                    continue;
+                };
 
                 // Map it as read-only to get the encoded data
-                let map = buffer
-                    .map_readable()
-                    .map_err(|_| anyhow!("Failed to map GStreamer buffer as readable"))?;
+                let Ok(map) = buffer.map_readable() else {
+                    log::error!("Failed to map GStreamer buffer as readable");
+                    continue;
+                };
 
                 // Copy into a Bytes (webrtc-rs requires Bytes for the sample data)
                 let encoded_bytes = Bytes::copy_from_slice(map.as_slice());
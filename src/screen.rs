@@ -0,0 +1,90 @@
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use portable_pty::PtySize;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use ts_rs::TS;
+
+use crate::shell::ShellMsg;
+use crate::utils::to_json;
+
+const SCROLLBACK: usize = 1000;
+
+// Headless terminal fed with everything the pty prints, so attaching
+// clients can be repainted with the exact current screen
+pub type Screen = Arc<Mutex<vt100::Parser>>;
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize, TS)]
+pub struct ScreenDumpMsg {
+    pub session_id: String,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize, TS)]
+pub struct ScreenDump {
+    pub rows: u16,
+    pub cols: u16,
+    pub cursor_row: u16,
+    pub cursor_col: u16,
+    pub alternate_screen: bool,
+    pub title: String,
+    pub contents: String,
+}
+
+pub fn new_screen(size: PtySize) -> Screen {
+    Arc::new(Mutex::new(vt100::Parser::new(
+        size.rows, size.cols, SCROLLBACK,
+    )))
+}
+
+// Subscribes to the pty output together with a snapshot of the screen
+// at that point, so nothing is lost or repeated in between
+pub fn subscribe(
+    screen: &Screen,
+    from_pty: &broadcast::Sender<Bytes>,
+) -> (broadcast::Receiver<Bytes>, Bytes) {
+    let parser = screen.lock().unwrap();
+    (from_pty.subscribe(), snapshot(&parser))
+}
+
+// Shell message with the escape sequences needed to redraw the screen,
+// `resize` tells the client which size the snapshot was taken at
+fn snapshot(parser: &vt100::Parser) -> Bytes {
+    let screen = parser.screen();
+    let (rows, cols) = screen.size();
+
+    let mut repaint = vec![];
+    if screen.alternate_screen() {
+        repaint.extend_from_slice(b"\x1b[?1049h");
+    }
+    repaint.extend(screen.state_formatted());
+
+    to_json(&ShellMsg {
+        resize: Some(PtySize {
+            rows,
+            cols,
+            pixel_width: 0,
+            pixel_height: 0,
+        }),
+        output: Some(String::from_utf8_lossy(&repaint).to_string()),
+        ..Default::default()
+    })
+    .unwrap()
+    .into()
+}
+
+pub fn dump(screen: &Screen) -> ScreenDump {
+    let parser = screen.lock().unwrap();
+    let screen = parser.screen();
+    let (rows, cols) = screen.size();
+    let (cursor_row, cursor_col) = screen.cursor_position();
+    ScreenDump {
+        rows,
+        cols,
+        cursor_row,
+        cursor_col,
+        alternate_screen: screen.alternate_screen(),
+        title: screen.title().to_owned(),
+        contents: screen.contents(),
+    }
+}
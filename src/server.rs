@@ -1,17 +1,34 @@
+use std::path::PathBuf;
+
 use anyhow::Result;
 
 use clap::Parser;
 use env_logger::Env;
 use gstreamer as gst;
+use settings::Settings;
 
+pub mod asciicast;
+pub mod clipboard;
+pub mod compression;
 pub mod convert;
+pub mod file;
+pub mod fs;
 pub mod peer;
+pub mod policy;
 pub mod port;
 pub mod recording;
+pub mod screen;
+pub mod settings;
+pub mod sftp;
+pub mod sync;
 pub mod shell;
 pub mod signal;
 pub mod state;
 pub mod control;
+pub mod display;
+pub mod http;
+pub mod input;
+pub mod udp;
 pub mod utils;
 
 #[derive(Parser)]
@@ -22,6 +39,10 @@ struct Cli {
     #[arg(short, long)]
     url: Option<String>,
 
+    /// Path to a json config with server policies
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+
     /// Turn debugging information on
     #[arg(short, long, action = clap::ArgAction::Count)]
     debug: u8,
@@ -45,6 +66,7 @@ async fn main() -> Result<()> {
     log::info!("PLSSSSS3333");
     let cli = Cli::parse();
     env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+    let settings = Settings::load(cli.config.as_deref())?;
 
     loop {
         log::info!("Starting app");
@@ -54,6 +76,7 @@ async fn main() -> Result<()> {
             cli.url
                 .clone()
                 .unwrap_or("ws://amogos.pro:8002/signaling".into()),
+            settings.clone(),
         )
         .await
         {
@@ -0,0 +1,158 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::policy::{FilePolicy, ForwardPolicy, ForwardRule, HttpPolicy, Protocol};
+use crate::utils::load_config;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CompressionPolicy {
+    pub enabled: bool,
+    // Ports that already carry encrypted traffic (ssh), compressing them is wasted cpu
+    pub disabled_ports: Vec<u16>,
+}
+
+impl Default for CompressionPolicy {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            disabled_ports: vec![22],
+        }
+    }
+}
+
+impl CompressionPolicy {
+    pub fn allows(&self, port: Option<u16>) -> bool {
+        self.enabled && !port.is_some_and(|port| self.disabled_ports.contains(&port))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RecordingSettings {
+    pub enabled: bool,
+    pub dir: PathBuf,
+    pub capture_input: bool,
+}
+
+impl Default for RecordingSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            dir: PathBuf::from("recordings"),
+            capture_input: true,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SessionSettings {
+    // Seconds a shell keeps running after its last client left, 0 ends it right away
+    pub detach_timeout_secs: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UdpSettings {
+    // A udp flow is dropped after this many seconds without a datagram in either direction
+    pub idle_timeout_secs: u64,
+}
+
+impl Default for UdpSettings {
+    fn default() -> Self {
+        Self {
+            idle_timeout_secs: 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReverseSettings {
+    // Addresses clients may ask the server to listen on with `-R`
+    pub bind: ForwardPolicy,
+}
+
+impl Default for ReverseSettings {
+    fn default() -> Self {
+        let loopback = |cidr: &str| ForwardRule {
+            host: None,
+            cidr: Some(cidr.parse().unwrap()),
+            ports: Some("1024-65535".into()),
+            protocol: Protocol::Tcp,
+        };
+        Self {
+            bind: ForwardPolicy {
+                rules: vec![loopback("127.0.0.0/8"), loopback("::1/128")],
+                unix_sockets: vec![],
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClipboardSettings {
+    // Clipboard channels are refused when off
+    pub enabled: bool,
+    // Text and html, counted together for html
+    pub max_text_bytes: u32,
+    // Decoded size of an image, 4 bytes a pixel
+    pub max_image_bytes: u32,
+    // How often the local clipboard is checked for changes
+    pub poll_interval_ms: u64,
+}
+
+impl Default for ClipboardSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_text_bytes: 1024 * 1024,
+            max_image_bytes: 32 * 1024 * 1024,
+            poll_interval_ms: 500,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct InputSettings {
+    // Input channels are refused when off, the desktop is then view only
+    pub enabled: bool,
+    // Log events instead of injecting them, for trying clients against a headless server
+    pub mock: bool,
+}
+
+impl Default for InputSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            mock: false,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub compression: CompressionPolicy,
+    pub recording: RecordingSettings,
+    pub sessions: SessionSettings,
+    pub forward: ForwardPolicy,
+    pub reverse: ReverseSettings,
+    pub udp: UdpSettings,
+    // Server-local web apps reachable over http channels, nothing by default
+    pub http: HttpPolicy,
+    pub files: FilePolicy,
+    pub clipboard: ClipboardSettings,
+    pub input: InputSettings,
+}
+
+impl Settings {
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        load_config(path)
+    }
+}
@@ -0,0 +1,814 @@
+// SFTP version 3 (draft-ietf-secsh-filexfer-02) served straight from the
+// server's filesystem, the channel carries the same byte stream sshd's
+// sftp subsystem would
+use std::collections::HashMap;
+use std::fs::{self, FileTimes, Metadata};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use chrono::{DateTime, Utc};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, SeekFrom};
+use tokio::sync::{broadcast, mpsc};
+
+use crate::policy::FilePolicy;
+use crate::port::{is_eof, MAX_CHUNK};
+
+const VERSION: u32 = 3;
+// Same limits as OpenSSH's sftp-server
+const MAX_PACKET: usize = 256 * 1024;
+const MAX_READ: u32 = 64 * 1024;
+const READDIR_BATCH: usize = 100;
+// Open files and directories per session, past it opens fail
+const MAX_HANDLES: usize = 256;
+
+const SSH_FXP_INIT: u8 = 1;
+const SSH_FXP_VERSION: u8 = 2;
+const SSH_FXP_OPEN: u8 = 3;
+const SSH_FXP_CLOSE: u8 = 4;
+const SSH_FXP_READ: u8 = 5;
+const SSH_FXP_WRITE: u8 = 6;
+const SSH_FXP_LSTAT: u8 = 7;
+const SSH_FXP_FSTAT: u8 = 8;
+const SSH_FXP_SETSTAT: u8 = 9;
+const SSH_FXP_FSETSTAT: u8 = 10;
+const SSH_FXP_OPENDIR: u8 = 11;
+const SSH_FXP_READDIR: u8 = 12;
+const SSH_FXP_REMOVE: u8 = 13;
+const SSH_FXP_MKDIR: u8 = 14;
+const SSH_FXP_RMDIR: u8 = 15;
+const SSH_FXP_REALPATH: u8 = 16;
+const SSH_FXP_STAT: u8 = 17;
+const SSH_FXP_RENAME: u8 = 18;
+const SSH_FXP_READLINK: u8 = 19;
+const SSH_FXP_SYMLINK: u8 = 20;
+const SSH_FXP_STATUS: u8 = 101;
+const SSH_FXP_HANDLE: u8 = 102;
+const SSH_FXP_DATA: u8 = 103;
+const SSH_FXP_NAME: u8 = 104;
+const SSH_FXP_ATTRS: u8 = 105;
+const SSH_FXP_EXTENDED: u8 = 200;
+
+const SSH_FX_OK: u32 = 0;
+const SSH_FX_EOF: u32 = 1;
+const SSH_FX_NO_SUCH_FILE: u32 = 2;
+const SSH_FX_PERMISSION_DENIED: u32 = 3;
+const SSH_FX_FAILURE: u32 = 4;
+const SSH_FX_BAD_MESSAGE: u32 = 5;
+const SSH_FX_OP_UNSUPPORTED: u32 = 8;
+
+const SSH_FILEXFER_ATTR_SIZE: u32 = 0x1;
+const SSH_FILEXFER_ATTR_UIDGID: u32 = 0x2;
+const SSH_FILEXFER_ATTR_PERMISSIONS: u32 = 0x4;
+const SSH_FILEXFER_ATTR_ACMODTIME: u32 = 0x8;
+const SSH_FILEXFER_ATTR_EXTENDED: u32 = 0x80000000;
+
+const SSH_FXF_READ: u32 = 0x1;
+const SSH_FXF_WRITE: u32 = 0x2;
+const SSH_FXF_APPEND: u32 = 0x4;
+const SSH_FXF_CREAT: u32 = 0x8;
+const SSH_FXF_TRUNC: u32 = 0x10;
+const SSH_FXF_EXCL: u32 = 0x20;
+
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+const POSIX_RENAME: &str = "posix-rename@openssh.com";
+
+// What goes back in a status reply
+struct SftpError {
+    code: u32,
+    message: String,
+}
+
+impl From<io::Error> for SftpError {
+    fn from(e: io::Error) -> Self {
+        let code = match e.kind() {
+            io::ErrorKind::NotFound => SSH_FX_NO_SUCH_FILE,
+            io::ErrorKind::PermissionDenied => SSH_FX_PERMISSION_DENIED,
+            _ => SSH_FX_FAILURE,
+        };
+        Self {
+            code,
+            message: e.to_string(),
+        }
+    }
+}
+
+impl SftpError {
+    fn new(code: u32, message: &str) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    fn denied(e: anyhow::Error) -> Self {
+        Self {
+            code: SSH_FX_PERMISSION_DENIED,
+            message: e.to_string(),
+        }
+    }
+}
+
+type SftpResult<T> = std::result::Result<T, SftpError>;
+
+// std::fs calls block, they run off the async workers
+async fn blocking<T, F>(call: F) -> SftpResult<T>
+where
+    F: FnOnce() -> io::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(call)
+        .await
+        .map_err(|e| SftpError::new(SSH_FX_FAILURE, &e.to_string()))?
+        .map_err(SftpError::from)
+}
+
+// Checked reads from a request, a short packet is a bad message instead of a panic
+struct Reader(Bytes);
+
+impl Reader {
+    fn u8(&mut self) -> SftpResult<u8> {
+        if self.0.remaining() < 1 {
+            return Err(SftpError::new(SSH_FX_BAD_MESSAGE, "Short packet"));
+        }
+        Ok(self.0.get_u8())
+    }
+
+    fn u32(&mut self) -> SftpResult<u32> {
+        if self.0.remaining() < 4 {
+            return Err(SftpError::new(SSH_FX_BAD_MESSAGE, "Short packet"));
+        }
+        Ok(self.0.get_u32())
+    }
+
+    fn u64(&mut self) -> SftpResult<u64> {
+        if self.0.remaining() < 8 {
+            return Err(SftpError::new(SSH_FX_BAD_MESSAGE, "Short packet"));
+        }
+        Ok(self.0.get_u64())
+    }
+
+    fn bytes(&mut self) -> SftpResult<Bytes> {
+        let len = self.u32()? as usize;
+        if self.0.remaining() < len {
+            return Err(SftpError::new(SSH_FX_BAD_MESSAGE, "Short packet"));
+        }
+        Ok(self.0.split_to(len))
+    }
+
+    fn string(&mut self) -> SftpResult<String> {
+        Ok(String::from_utf8_lossy(&self.bytes()?).into_owned())
+    }
+
+    fn attrs(&mut self) -> SftpResult<Attrs> {
+        let flags = self.u32()?;
+        let mut attrs = Attrs::default();
+        if flags & SSH_FILEXFER_ATTR_SIZE != 0 {
+            attrs.size = Some(self.u64()?);
+        }
+        if flags & SSH_FILEXFER_ATTR_UIDGID != 0 {
+            attrs.uid_gid = Some((self.u32()?, self.u32()?));
+        }
+        if flags & SSH_FILEXFER_ATTR_PERMISSIONS != 0 {
+            attrs.permissions = Some(self.u32()?);
+        }
+        if flags & SSH_FILEXFER_ATTR_ACMODTIME != 0 {
+            attrs.times = Some((self.u32()?, self.u32()?));
+        }
+        if flags & SSH_FILEXFER_ATTR_EXTENDED != 0 {
+            for _ in 0..self.u32()? {
+                self.bytes()?;
+                self.bytes()?;
+            }
+        }
+        Ok(attrs)
+    }
+}
+
+// One response, the length in front is filled in by `finish`
+struct Reply(BytesMut);
+
+impl Reply {
+    fn new(kind: u8, id: u32) -> Self {
+        let mut reply = Self::version_less(kind);
+        reply.0.put_u32(id);
+        reply
+    }
+
+    // Only SSH_FXP_VERSION goes without a request id
+    fn version_less(kind: u8) -> Self {
+        let mut buffer = BytesMut::new();
+        buffer.put_u32(0);
+        buffer.put_u8(kind);
+        Self(buffer)
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.0.put_u32(value);
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.0.put_u64(value);
+    }
+
+    fn bytes(&mut self, value: &[u8]) {
+        self.0.put_u32(value.len() as u32);
+        self.0.put_slice(value);
+    }
+
+    fn attrs(&mut self, stat: Option<&Stat>) {
+        let Some(stat) = stat else {
+            return self.u32(0);
+        };
+        self.u32(
+            SSH_FILEXFER_ATTR_SIZE
+                | SSH_FILEXFER_ATTR_UIDGID
+                | SSH_FILEXFER_ATTR_PERMISSIONS
+                | SSH_FILEXFER_ATTR_ACMODTIME,
+        );
+        self.u64(stat.size);
+        self.u32(stat.uid);
+        self.u32(stat.gid);
+        self.u32(stat.mode);
+        self.u32(stat.atime);
+        self.u32(stat.mtime);
+    }
+
+    fn finish(mut self) -> Bytes {
+        let len = (self.0.len() - 4) as u32;
+        self.0[..4].copy_from_slice(&len.to_be_bytes());
+        self.0.freeze()
+    }
+}
+
+fn status(id: u32, code: u32, message: &str) -> Bytes {
+    let mut reply = Reply::new(SSH_FXP_STATUS, id);
+    reply.u32(code);
+    reply.bytes(message.as_bytes());
+    reply.bytes(b"en");
+    reply.finish()
+}
+
+fn ok(id: u32) -> Bytes {
+    status(id, SSH_FX_OK, "Success")
+}
+
+#[derive(Default)]
+struct Attrs {
+    size: Option<u64>,
+    uid_gid: Option<(u32, u32)>,
+    permissions: Option<u32>,
+    times: Option<(u32, u32)>,
+}
+
+// The subset of a stat sftp v3 can carry, mode includes the file type bits
+struct Stat {
+    size: u64,
+    uid: u32,
+    gid: u32,
+    mode: u32,
+    atime: u32,
+    mtime: u32,
+}
+
+fn unix_time(time: io::Result<SystemTime>) -> u32 {
+    time.ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |since| since.as_secs() as u32)
+}
+
+#[cfg(unix)]
+fn stat(meta: &Metadata) -> Stat {
+    use std::os::unix::fs::MetadataExt;
+    Stat {
+        size: meta.size(),
+        uid: meta.uid(),
+        gid: meta.gid(),
+        mode: meta.mode(),
+        atime: unix_time(meta.accessed()),
+        mtime: unix_time(meta.modified()),
+    }
+}
+
+#[cfg(not(unix))]
+fn stat(meta: &Metadata) -> Stat {
+    let file_type = meta.file_type();
+    let mode = if file_type.is_symlink() {
+        S_IFLNK | 0o777
+    } else if file_type.is_dir() {
+        S_IFDIR | 0o755
+    } else {
+        S_IFREG | 0o644
+    };
+    let mode = if meta.permissions().readonly() {
+        mode & !0o222
+    } else {
+        mode
+    };
+    Stat {
+        size: meta.len(),
+        uid: 0,
+        gid: 0,
+        mode,
+        atime: unix_time(meta.accessed()),
+        mtime: unix_time(meta.modified()),
+    }
+}
+
+// `ls -l` style line, some clients show it as is
+fn longname(name: &str, stat: &Stat) -> String {
+    let kind = match stat.mode & S_IFMT {
+        S_IFDIR => 'd',
+        S_IFLNK => 'l',
+        S_IFREG => '-',
+        _ => '?',
+    };
+    let bits: String = "rwxrwxrwx"
+        .chars()
+        .enumerate()
+        .map(|(i, c)| {
+            if stat.mode & (0o400 >> i) != 0 {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    let modified: DateTime<Utc> = (UNIX_EPOCH + Duration::from_secs(stat.mtime as u64)).into();
+    format!(
+        "{}{} 1 {:<8} {:<8} {:>8} {} {}",
+        kind,
+        bits,
+        stat.uid,
+        stat.gid,
+        stat.size,
+        modified.format("%b %e %H:%M"),
+        name
+    )
+}
+
+// Entries that vanished since the listing started are skipped
+fn read_dir_batch(entries: &mut fs::ReadDir) -> io::Result<Vec<(String, Stat)>> {
+    let mut names = vec![];
+    for entry in entries.by_ref().take(READDIR_BATCH) {
+        let entry = entry?;
+        let Ok(meta) = fs::symlink_metadata(entry.path()) else {
+            continue;
+        };
+        let name = entry.file_name().to_string_lossy().into_owned();
+        names.push((name, stat(&meta)));
+    }
+    Ok(names)
+}
+
+fn set_attrs(path: &Path, attrs: &Attrs) -> io::Result<()> {
+    if let Some(size) = attrs.size {
+        fs::OpenOptions::new()
+            .write(true)
+            .open(path)?
+            .set_len(size)?;
+    }
+    if let Some(permissions) = attrs.permissions {
+        set_permissions(path, permissions)?;
+    }
+    #[cfg(unix)]
+    if let Some((uid, gid)) = attrs.uid_gid {
+        std::os::unix::fs::chown(path, Some(uid), Some(gid))?;
+    }
+    if let Some((atime, mtime)) = attrs.times {
+        let time = |secs: u32| UNIX_EPOCH + Duration::from_secs(secs as u64);
+        let times = FileTimes::new()
+            .set_accessed(time(atime))
+            .set_modified(time(mtime));
+        fs::File::open(path)?.set_times(times)?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_permissions(path: &Path, permissions: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(permissions & 0o7777))
+}
+
+#[cfg(not(unix))]
+fn set_permissions(path: &Path, permissions: u32) -> io::Result<()> {
+    let mut current = fs::metadata(path)?.permissions();
+    current.set_readonly(permissions & 0o200 == 0);
+    fs::set_permissions(path, current)
+}
+
+enum Handle {
+    File { file: File, path: PathBuf },
+    Dir { entries: fs::ReadDir },
+}
+
+struct SftpServer {
+    policy: FilePolicy,
+    handles: HashMap<String, Handle>,
+    next_handle: u64,
+}
+
+impl SftpServer {
+    fn new(policy: FilePolicy) -> Self {
+        Self {
+            policy,
+            handles: HashMap::new(),
+            next_handle: 0,
+        }
+    }
+
+    // Relative paths start in the first root, the closest thing to a home directory
+    fn resolve(&self, path: &str) -> PathBuf {
+        let path = Path::new(path);
+        if path.is_absolute() {
+            return path.into();
+        }
+        let home = self.policy.roots.first().cloned().unwrap_or_default();
+        home.join(path)
+    }
+
+    // Follows a symlink at `path`
+    fn check(&self, path: &str) -> SftpResult<PathBuf> {
+        self.policy
+            .check(&self.resolve(path))
+            .map_err(SftpError::denied)
+    }
+
+    // Acts on the symlink at `path` itself
+    fn check_entry(&self, path: &str) -> SftpResult<PathBuf> {
+        self.policy
+            .check_entry(&self.resolve(path))
+            .map_err(SftpError::denied)
+    }
+
+    fn add_handle(&mut self, id: u32, handle: Handle) -> SftpResult<Bytes> {
+        if self.handles.len() >= MAX_HANDLES {
+            return Err(SftpError::new(SSH_FX_FAILURE, "Too many open handles"));
+        }
+        let name = self.next_handle.to_string();
+        self.next_handle += 1;
+        self.handles.insert(name.clone(), handle);
+        let mut reply = Reply::new(SSH_FXP_HANDLE, id);
+        reply.bytes(name.as_bytes());
+        Ok(reply.finish())
+    }
+
+    fn handle(&mut self, name: &str) -> SftpResult<&mut Handle> {
+        self.handles
+            .get_mut(name)
+            .ok_or(SftpError::new(SSH_FX_FAILURE, "Invalid handle"))
+    }
+
+    fn file(&mut self, name: &str) -> SftpResult<(&mut File, &Path)> {
+        match self.handle(name)? {
+            Handle::File { file, path } => Ok((file, path.as_path())),
+            Handle::Dir { .. } => Err(SftpError::new(SSH_FX_FAILURE, "Not a file handle")),
+        }
+    }
+
+    fn not_root(&self, path: &Path) -> SftpResult<()> {
+        if self.policy.is_root(path) {
+            return Err(SftpError::new(
+                SSH_FX_PERMISSION_DENIED,
+                "Root directories can't be changed",
+            ));
+        }
+        Ok(())
+    }
+
+    async fn request(&mut self, packet: Bytes) -> SftpResult<Bytes> {
+        let mut packet = Reader(packet);
+        let kind = packet.u8()?;
+        if kind == SSH_FXP_INIT {
+            // Whatever the client speaks, we answer with 3 and it has to follow
+            let mut reply = Reply::version_less(SSH_FXP_VERSION);
+            reply.u32(VERSION);
+            reply.bytes(POSIX_RENAME.as_bytes());
+            reply.bytes(b"1");
+            return Ok(reply.finish());
+        }
+        let id = packet.u32()?;
+        match self.dispatch(kind, id, &mut packet).await {
+            Ok(reply) => Ok(reply),
+            Err(e) => Ok(status(id, e.code, &e.message)),
+        }
+    }
+
+    async fn dispatch(&mut self, kind: u8, id: u32, packet: &mut Reader) -> SftpResult<Bytes> {
+        match kind {
+            SSH_FXP_OPEN => {
+                let path = self.check(&packet.string()?)?;
+                let flags = packet.u32()?;
+                let attrs = packet.attrs()?;
+                let mut options = OpenOptions::new();
+                options
+                    .read(flags & SSH_FXF_READ != 0)
+                    .write(flags & (SSH_FXF_WRITE | SSH_FXF_APPEND) != 0)
+                    .append(flags & SSH_FXF_APPEND != 0)
+                    .truncate(flags & SSH_FXF_TRUNC != 0);
+                if flags & SSH_FXF_CREAT != 0 {
+                    if flags & SSH_FXF_EXCL != 0 {
+                        options.create_new(true);
+                    } else {
+                        options.create(true);
+                    }
+                }
+                #[cfg(unix)]
+                if let Some(permissions) = attrs.permissions {
+                    options.mode(permissions & 0o7777);
+                }
+                #[cfg(not(unix))]
+                let _ = attrs;
+                // The path was checked resolved, a symlink swapped in since is not followed
+                #[cfg(unix)]
+                options.custom_flags(libc::O_NOFOLLOW);
+                let file = options.open(&path).await?;
+                log::info!("SFTP open {}", path.display());
+                self.add_handle(id, Handle::File { file, path })
+            }
+            SSH_FXP_CLOSE => {
+                let name = packet.string()?;
+                if let Some(Handle::File { mut file, .. }) = self.handles.remove(&name) {
+                    file.flush().await?;
+                }
+                Ok(ok(id))
+            }
+            SSH_FXP_READ => {
+                let name = packet.string()?;
+                let offset = packet.u64()?;
+                let len = packet.u32()?.min(MAX_READ) as usize;
+                let (file, _) = self.file(&name)?;
+                file.seek(SeekFrom::Start(offset)).await?;
+                let mut data = vec![0u8; len];
+                let mut filled = 0;
+                while filled < len {
+                    match file.read(&mut data[filled..]).await? {
+                        0 => break,
+                        n => filled += n,
+                    }
+                }
+                if filled == 0 && len > 0 {
+                    return Ok(status(id, SSH_FX_EOF, "End of file"));
+                }
+                let mut reply = Reply::new(SSH_FXP_DATA, id);
+                reply.bytes(&data[..filled]);
+                Ok(reply.finish())
+            }
+            SSH_FXP_WRITE => {
+                let name = packet.string()?;
+                let offset = packet.u64()?;
+                let data = packet.bytes()?;
+                let (file, _) = self.file(&name)?;
+                // Appending files ignore the offset, like O_APPEND
+                file.seek(SeekFrom::Start(offset)).await?;
+                file.write_all(&data).await?;
+                Ok(ok(id))
+            }
+            SSH_FXP_LSTAT | SSH_FXP_STAT => {
+                let path = packet.string()?;
+                let meta = if kind == SSH_FXP_LSTAT {
+                    let path = self.check_entry(&path)?;
+                    blocking(move || fs::symlink_metadata(path)).await?
+                } else {
+                    let path = self.check(&path)?;
+                    blocking(move || fs::metadata(path)).await?
+                };
+                let mut reply = Reply::new(SSH_FXP_ATTRS, id);
+                reply.attrs(Some(&stat(&meta)));
+                Ok(reply.finish())
+            }
+            SSH_FXP_FSTAT => {
+                let name = packet.string()?;
+                let (file, _) = self.file(&name)?;
+                let meta = file.metadata().await?;
+                let mut reply = Reply::new(SSH_FXP_ATTRS, id);
+                reply.attrs(Some(&stat(&meta)));
+                Ok(reply.finish())
+            }
+            SSH_FXP_SETSTAT => {
+                let path = self.check(&packet.string()?)?;
+                let attrs = packet.attrs()?;
+                blocking(move || set_attrs(&path, &attrs)).await?;
+                Ok(ok(id))
+            }
+            SSH_FXP_FSETSTAT => {
+                let name = packet.string()?;
+                let attrs = packet.attrs()?;
+                let (file, path) = self.file(&name)?;
+                file.flush().await?;
+                let path = path.to_owned();
+                blocking(move || set_attrs(&path, &attrs)).await?;
+                Ok(ok(id))
+            }
+            SSH_FXP_OPENDIR => {
+                let path = self.check(&packet.string()?)?;
+                let entries = blocking(move || fs::read_dir(path)).await?;
+                self.add_handle(id, Handle::Dir { entries })
+            }
+            SSH_FXP_READDIR => {
+                let name = packet.string()?;
+                // The listing moves to the blocking thread and back into its handle
+                let mut entries = match self.handles.remove(&name) {
+                    Some(Handle::Dir { entries }) => entries,
+                    Some(handle) => {
+                        self.handles.insert(name, handle);
+                        return Err(SftpError::new(SSH_FX_FAILURE, "Not a directory handle"));
+                    }
+                    None => return Err(SftpError::new(SSH_FX_FAILURE, "Invalid handle")),
+                };
+                let (entries, names) = blocking(move || {
+                    let names = read_dir_batch(&mut entries);
+                    Ok((entries, names))
+                })
+                .await?;
+                self.handles.insert(name, Handle::Dir { entries });
+                let names = names?;
+                if names.is_empty() {
+                    return Ok(status(id, SSH_FX_EOF, "End of directory"));
+                }
+                let mut reply = Reply::new(SSH_FXP_NAME, id);
+                reply.u32(names.len() as u32);
+                for (name, stat) in &names {
+                    reply.bytes(name.as_bytes());
+                    reply.bytes(longname(name, stat).as_bytes());
+                    reply.attrs(Some(stat));
+                }
+                Ok(reply.finish())
+            }
+            SSH_FXP_REMOVE => {
+                let path = self.check_entry(&packet.string()?)?;
+                blocking(move || fs::remove_file(path)).await?;
+                Ok(ok(id))
+            }
+            SSH_FXP_MKDIR => {
+                let path = self.check_entry(&packet.string()?)?;
+                let attrs = packet.attrs()?;
+                blocking(move || {
+                    fs::create_dir(&path)?;
+                    if let Some(permissions) = attrs.permissions {
+                        set_permissions(&path, permissions)?;
+                    }
+                    Ok(())
+                })
+                .await?;
+                Ok(ok(id))
+            }
+            SSH_FXP_RMDIR => {
+                let path = self.check_entry(&packet.string()?)?;
+                self.not_root(&path)?;
+                blocking(move || fs::remove_dir(path)).await?;
+                Ok(ok(id))
+            }
+            SSH_FXP_REALPATH => {
+                let path = self.check(&packet.string()?)?;
+                let name = path.to_string_lossy();
+                let mut reply = Reply::new(SSH_FXP_NAME, id);
+                reply.u32(1);
+                reply.bytes(name.as_bytes());
+                reply.bytes(name.as_bytes());
+                reply.attrs(None);
+                Ok(reply.finish())
+            }
+            SSH_FXP_RENAME => {
+                let from = self.check_entry(&packet.string()?)?;
+                let to = self.check_entry(&packet.string()?)?;
+                self.not_root(&from)?;
+                // Version 3 never replaces an existing file, posix-rename does
+                blocking(move || {
+                    if fs::symlink_metadata(&to).is_ok() {
+                        return Err(io::Error::new(
+                            io::ErrorKind::AlreadyExists,
+                            "Target exists",
+                        ));
+                    }
+                    fs::rename(from, to)
+                })
+                .await?;
+                Ok(ok(id))
+            }
+            SSH_FXP_READLINK => {
+                let path = self.check_entry(&packet.string()?)?;
+                let target = blocking(move || fs::read_link(path)).await?;
+                let target = target.to_string_lossy();
+                let mut reply = Reply::new(SSH_FXP_NAME, id);
+                reply.u32(1);
+                reply.bytes(target.as_bytes());
+                reply.bytes(target.as_bytes());
+                reply.attrs(None);
+                Ok(reply.finish())
+            }
+            SSH_FXP_SYMLINK => {
+                // OpenSSH sends target first and link second, every client follows it
+                let target = packet.string()?;
+                let link = self.check_entry(&packet.string()?)?;
+                // A relative target is relative to the link's directory, and
+                // it has to stay inside the roots like everything else
+                let parent = link.parent().map(Path::to_owned).unwrap_or_default();
+                self.policy
+                    .check(&parent.join(&target))
+                    .map_err(SftpError::denied)?;
+                #[cfg(unix)]
+                {
+                    blocking(move || std::os::unix::fs::symlink(target, link)).await?;
+                    Ok(ok(id))
+                }
+                #[cfg(not(unix))]
+                {
+                    let _ = (target, link);
+                    Err(SftpError::new(SSH_FX_OP_UNSUPPORTED, "No symlinks here"))
+                }
+            }
+            SSH_FXP_EXTENDED => {
+                let request = packet.string()?;
+                if request != POSIX_RENAME {
+                    return Err(SftpError::new(
+                        SSH_FX_OP_UNSUPPORTED,
+                        "Unsupported extension",
+                    ));
+                }
+                let from = self.check_entry(&packet.string()?)?;
+                let to = self.check_entry(&packet.string()?)?;
+                self.not_root(&from)?;
+                blocking(move || fs::rename(from, to)).await?;
+                Ok(ok(id))
+            }
+            _ => Err(SftpError::new(SSH_FX_OP_UNSUPPORTED, "Unsupported request")),
+        }
+    }
+}
+
+// Splits the next whole packet off the stream, None until it's all there
+fn next_packet(buffer: &mut BytesMut) -> anyhow::Result<Option<Bytes>> {
+    if buffer.len() < 4 {
+        return Ok(None);
+    }
+    let len = u32::from_be_bytes(buffer[..4].try_into().unwrap()) as usize;
+    if len == 0 || len > MAX_PACKET {
+        return Err(anyhow::anyhow!("Invalid sftp packet length {}", len));
+    }
+    if buffer.len() < 4 + len {
+        return Ok(None);
+    }
+    buffer.advance(4);
+    Ok(Some(buffer.split_to(len).freeze()))
+}
+
+// One sftp session, requests are answered in order
+pub async fn handle_sftp(
+    tx: mpsc::Sender<Bytes>,       // From server to the client
+    mut rx: mpsc::Receiver<Bytes>, // From clients to server
+    mut done_rx: broadcast::Receiver<()>,
+    policy: FilePolicy,
+) {
+    let mut server = SftpServer::new(policy);
+    let mut buffer = BytesMut::new();
+
+    'session: loop {
+        let data = tokio::select! {
+            data = rx.recv() => data,
+            _ = done_rx.recv() => break,
+        };
+        let Some(data) = data else {
+            break;
+        };
+        if is_eof(&data) {
+            break;
+        }
+        buffer.extend_from_slice(&data);
+
+        loop {
+            let packet = match next_packet(&mut buffer) {
+                Ok(Some(packet)) => packet,
+                Ok(None) => break,
+                Err(e) => {
+                    log::error!("{}", e);
+                    break 'session;
+                }
+            };
+            let mut reply = match server.request(packet).await {
+                Ok(reply) => reply,
+                Err(e) => {
+                    log::error!("Malformed sftp request: {}", e.message);
+                    break 'session;
+                }
+            };
+            // Replies to big reads don't fit one message
+            while !reply.is_empty() {
+                let chunk = reply.split_to(reply.len().min(MAX_CHUNK));
+                if tx.send(chunk).await.is_err() {
+                    break 'session;
+                }
+            }
+        }
+    }
+
+    let _ = tx.send(Bytes::new()).await;
+    log::info!("handle_sftp exiting");
+}
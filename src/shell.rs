@@ -1,51 +1,371 @@
 use bytes::Bytes;
+use chrono::{DateTime, Utc};
 use portable_pty::{native_pty_system, CommandBuilder, PtySize};
 use serde::{Deserialize, Serialize};
 use std::thread;
 use tokio::sync::mpsc::Receiver;
+use ts_rs::TS;
+use uuid::Uuid;
 
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::sync::{broadcast, mpsc};
 
+use crate::asciicast::Recorder;
+use crate::screen::Screen;
+use crate::utils::to_json;
+
+pub const INITIAL_SIZE: PtySize = PtySize {
+    rows: 24,
+    cols: 80,
+    pixel_width: 0,
+    pixel_height: 0,
+};
+
+pub fn default_shell() -> &'static str {
+    if cfg!(target_os = "windows") {
+        "powershell"
+    } else {
+        "sh"
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, TS)]
+#[serde(rename_all = "lowercase")]
+#[ts(export)]
+pub enum Role {
+    Owner,
+    Writer,
+    Viewer,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct Participant {
+    pub id: String,
+    pub role: Role,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+#[ts(export)]
+pub enum SessionEvent {
+    // Sent only to the newly attached channel
+    Welcome {
+        me: String,
+        participants: Vec<Participant>,
+    },
+    Joined(Participant),
+    Left(Participant),
+    Role(Participant),
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize, TS)]
+pub struct SessionInfo {
+    pub id: String,
+    pub name: Option<String>,
+    pub variant: String,
+    pub shell: Option<String>,
+    pub created: String,
+    pub clients: u32,
+    pub last_activity: String,
+    pub rows: Option<u16>,
+    pub cols: Option<u16>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize, TS)]
+pub struct RenameSessionMsg {
+    pub session_id: String,
+    pub name: String,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize, TS)]
+pub struct KillSessionMsg {
+    pub session_id: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct SessionMeta {
+    pub name: Option<String>,
+    pub last_activity: DateTime<Utc>,
+}
+
 #[derive(Clone)]
 pub struct Session {
-    pub to_pty: mpsc::Sender<Bytes>,        // To send data to PTY
-    pub from_pty: broadcast::Sender<Bytes>, // To receive data from PTY
-    pub done_tx: broadcast::Sender<()>,           // To signal done
+    pub to_pty: mpsc::Sender<Bytes>,                // To send data to PTY
+    pub from_pty: broadcast::Sender<Bytes>,         // To receive data from PTY
+    pub done_tx: broadcast::Sender<()>,             // To signal done
+    pub participants: Arc<Mutex<Vec<Participant>>>, // In order of joining
+    pub screen: Option<Screen>,                     // Current screen of web_shell sessions
+    pub variant: String,
+    pub created: DateTime<Utc>,
+    pub meta: Arc<Mutex<SessionMeta>>,
+    pub attached: Arc<AtomicUsize>, // Data channels currently attached
+    pub detach_timeout: Duration,   // How long a shell outlives its last participant
+    pub creator: usize,             // Peer that opened the session
+    pub peers: Arc<Mutex<HashMap<String, usize>>>, // Participant id to the peer it joined from
+    // Output of single consumer sessions (port, udp), taken by their only channel instead of from_pty
+    pub direct: Arc<Mutex<Option<mpsc::Receiver<Bytes>>>>,
 }
 
 pub type SessionMap = Arc<Mutex<HashMap<String, Session>>>;
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Default, Clone, Serialize, Deserialize)]
 pub struct ShellMsg {
     pub resize: Option<PtySize>,
     pub input: Option<String>,
     pub output: Option<String>,
+    pub event: Option<SessionEvent>,
+    // Owner only, changes the role of another participant
+    pub set_role: Option<Participant>,
+}
+
+fn event_msg(event: SessionEvent) -> Bytes {
+    to_json(&ShellMsg {
+        event: Some(event),
+        ..Default::default()
+    })
+    .unwrap()
+    .into()
+}
+
+impl Session {
+    pub fn new(
+        to_pty: mpsc::Sender<Bytes>,
+        from_pty: broadcast::Sender<Bytes>,
+        done_tx: broadcast::Sender<()>,
+        variant: String,
+        screen: Option<Screen>,
+        detach_timeout: Duration,
+        direct: Option<mpsc::Receiver<Bytes>>,
+    ) -> Self {
+        let created = Utc::now();
+        Self {
+            to_pty,
+            from_pty,
+            done_tx,
+            participants: Default::default(),
+            screen,
+            variant,
+            created,
+            meta: Arc::new(Mutex::new(SessionMeta {
+                name: None,
+                last_activity: created,
+            })),
+            attached: Default::default(),
+            detach_timeout,
+            creator: 0,
+            peers: Default::default(),
+            direct: Arc::new(Mutex::new(direct)),
+        }
+    }
+
+    pub fn take_direct(&self) -> Option<mpsc::Receiver<Bytes>> {
+        self.direct.lock().unwrap().take()
+    }
+
+    pub fn touch(&self) {
+        self.meta.lock().unwrap().last_activity = Utc::now();
+    }
+
+    pub fn rename(&self, name: String) {
+        self.meta.lock().unwrap().name = Some(name);
+    }
+
+    pub fn info(&self, id: &str) -> SessionInfo {
+        let meta = self.meta.lock().unwrap().clone();
+        let size = self
+            .screen
+            .as_ref()
+            .map(|screen| screen.lock().unwrap().screen().size());
+        SessionInfo {
+            id: id.to_owned(),
+            name: meta.name,
+            variant: self.variant.clone(),
+            shell: (self.variant == "web_shell").then(|| default_shell().to_owned()),
+            created: self.created.to_rfc3339(),
+            clients: self.attached.load(Ordering::Relaxed) as u32,
+            last_activity: meta.last_activity.to_rfc3339(),
+            rows: size.map(|(rows, _)| rows),
+            cols: size.map(|(_, cols)| cols),
+        }
+    }
+
+    // Owners of shared sessions, the creator of everything else. Control
+    // sessions are never owned, they only end with their peer
+    pub fn owned_by(&self, peer: usize) -> bool {
+        if self.variant == "control" {
+            return false;
+        }
+        let participants = self.participants.lock().unwrap();
+        if participants.is_empty() {
+            return self.creator == peer;
+        }
+        let peers = self.peers.lock().unwrap();
+        participants
+            .iter()
+            .any(|p| p.role == Role::Owner && peers.get(&p.id) == Some(&peer))
+    }
+
+    // The creator and every peer with a participant in the session
+    pub fn joined_by(&self, peer: usize) -> bool {
+        self.creator == peer || self.peers.lock().unwrap().values().any(|p| *p == peer)
+    }
+
+    pub fn kill(&self) {
+        if self.done_tx.is_empty() {
+            let _ = self.done_tx.send(());
+        }
+    }
+
+    fn broadcast(&self, event: SessionEvent) {
+        let _ = self.from_pty.send(event_msg(event));
+    }
+
+    // The first participant owns the session, everyone after that only watches
+    pub fn join(&self, peer: usize) -> Participant {
+        let participant = {
+            let mut participants = self.participants.lock().unwrap();
+            let role = if participants.is_empty() {
+                Role::Owner
+            } else {
+                Role::Viewer
+            };
+            let participant = Participant {
+                id: Uuid::new_v4().to_string(),
+                role,
+            };
+            participants.push(participant.clone());
+            self.peers
+                .lock()
+                .unwrap()
+                .insert(participant.id.clone(), peer);
+            participant
+        };
+        log::info!("{} joined as {:?}", participant.id, participant.role);
+        self.broadcast(SessionEvent::Joined(participant.clone()));
+        participant
+    }
+
+    pub fn welcome(&self, id: &str) -> Bytes {
+        event_msg(SessionEvent::Welcome {
+            me: id.to_owned(),
+            participants: self.participants.lock().unwrap().clone(),
+        })
+    }
+
+    // Ends the session when the last participant leaves
+    pub fn leave(&self, id: &str) {
+        let (left, promoted, empty) = {
+            let mut participants = self.participants.lock().unwrap();
+            let Some(pos) = participants.iter().position(|p| p.id == id) else {
+                return;
+            };
+            let left = participants.remove(pos);
+            self.peers.lock().unwrap().remove(&left.id);
+            let mut promoted = None;
+            if left.role == Role::Owner {
+                if let Some(next) = participants.first_mut() {
+                    next.role = Role::Owner;
+                    promoted = Some(next.clone());
+                }
+            }
+            (left, promoted, participants.is_empty())
+        };
+        log::info!("{} left", left.id);
+        self.broadcast(SessionEvent::Left(left));
+        if let Some(promoted) = promoted {
+            self.broadcast(SessionEvent::Role(promoted));
+        }
+        if !empty {
+            return;
+        }
+        if self.detach_timeout.is_zero() {
+            self.kill();
+            return;
+        }
+        // Keep the shell around for a while so it can be reattached
+        let session = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(session.detach_timeout).await;
+            if session.participants.lock().unwrap().is_empty() {
+                log::info!("Detached session timed out");
+                session.kill();
+            }
+        });
+    }
+
+    fn role(&self, id: &str) -> Option<Role> {
+        let participants = self.participants.lock().unwrap();
+        participants.iter().find(|p| p.id == id).map(|p| p.role)
+    }
+
+    // Handing over ownership demotes the current owner to writer
+    fn set_role(&self, by: &str, target: Participant) {
+        if self.role(by) != Some(Role::Owner) || by == target.id {
+            log::warn!("{} is not allowed to change roles", by);
+            return;
+        }
+        let changed = {
+            let mut participants = self.participants.lock().unwrap();
+            if !participants.iter().any(|p| p.id == target.id) {
+                return;
+            }
+            let mut changed = vec![];
+            for p in participants.iter_mut() {
+                if p.id == target.id {
+                    p.role = target.role;
+                    changed.push(p.clone());
+                } else if p.id == by && target.role == Role::Owner {
+                    p.role = Role::Writer;
+                    changed.push(p.clone());
+                }
+            }
+            changed
+        };
+        for participant in changed {
+            self.broadcast(SessionEvent::Role(participant));
+        }
+    }
+
+    // Applies role changes and drops input/resize coming from viewers
+    pub fn filter_incoming(&self, id: &str, data: Bytes) -> Option<Bytes> {
+        let mut msg: ShellMsg = match serde_json::from_slice(&data) {
+            Ok(msg) => msg,
+            Err(e) => {
+                log::error!("Invalid shell message: {}", e);
+                return None;
+            }
+        };
+        if let Some(target) = msg.set_role.take() {
+            self.set_role(id, target);
+        }
+        if !matches!(self.role(id), Some(Role::Owner | Role::Writer)) {
+            msg.input = None;
+            msg.resize = None;
+        }
+        if msg.input.is_none() && msg.resize.is_none() {
+            return None;
+        }
+        to_json(&msg).ok().map(Bytes::from)
+    }
 }
 
 pub async fn handle_pty(
     tx: broadcast::Sender<Bytes>,  // From PTY to clients
     mut rx: mpsc::Receiver<Bytes>, // From clients to PTY
     mut done_rx: broadcast::Receiver<()>,
+    recorder: Option<Recorder>,
+    screen: Screen,
 ) {
     let pty_system = native_pty_system();
-    let pair = pty_system
-        .openpty(PtySize {
-            rows: 24,
-            cols: 80,
-            pixel_width: 0,
-            pixel_height: 0,
-        })
-        .unwrap();
+    let pair = pty_system.openpty(INITIAL_SIZE).unwrap();
 
     let mut child = pair
         .slave
-        .spawn_command(CommandBuilder::new(if cfg!(target_os = "windows") {
-            "powershell"
-        } else {
-            "sh"
-        }))
+        .spawn_command(CommandBuilder::new(default_shell()))
         .unwrap();
 
     let reader = pair.master.try_clone_reader().unwrap();
@@ -53,6 +373,9 @@ pub async fn handle_pty(
     let reader = Arc::new(Mutex::new(reader));
 
     // Asynchronously read from PTY and send to main thread
+    let reader_screen = screen.clone();
+    // Recorded straight from the reader, a lagging broadcast receiver can't lose any of it
+    let reader_recorder = recorder.clone();
     tokio::spawn(async move {
         let reader_clone = Arc::clone(&reader);
 
@@ -68,12 +391,17 @@ pub async fn handle_pty(
                 match read_result {
                     Ok(n) if n == 0 => continue,
                     Ok(n) => {
+                        // Held until the message is sent, so snapshots line up with the broadcast
+                        let mut screen = reader_screen.lock().unwrap();
+                        screen.process(&buf[..n]);
                         let msg = String::from_utf8_lossy(&buf[..n]).to_string();
+                        if let Some(recorder) = &reader_recorder {
+                            recorder.output(&msg);
+                        }
 
                         let json = serde_json::to_string(&ShellMsg {
-                            resize: None,
-                            input: None,
                             output: Some(msg),
+                            ..Default::default()
                         })
                         .unwrap();
 
@@ -103,17 +431,25 @@ pub async fn handle_pty(
             let msg: ShellMsg =
                 serde_json::from_str(String::from_utf8_lossy(sus).to_string().as_str()).unwrap();
             if let Some(msg) = msg.input {
+                if let Some(recorder) = &recorder {
+                    recorder.input(&msg);
+                }
                 writer.write_all(msg.as_bytes()).unwrap();
                 writer.flush().unwrap();
             };
 
             if let Some(size) = msg.resize {
+                if let Some(recorder) = &recorder {
+                    recorder.resize(size);
+                }
+                screen.lock().unwrap().set_size(size.rows, size.cols);
                 pair.master.resize(size).unwrap();
             };
         }
     });
 
     // Wait for the child process to exit
+    let mut killer = child.clone_killer();
     thread::spawn(move || {
         let _ = child.wait();
     });
@@ -127,6 +463,11 @@ pub async fn handle_pty(
         // }
     };
 
+    // The shell doesn't end on its own when the session is killed
+    if let Err(e) = killer.kill() {
+        log::debug!("Failed to kill shell: {}", e);
+    }
+
     // loop {
     //     tokio::time::sleep(Duration::from_millis(1000)).await;
     //     dbg!("pending");
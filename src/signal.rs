@@ -1,5 +1,7 @@
+use crate::input::forget_session;
 use crate::peer::{Peer, PeerMap};
 use crate::recording::add_video;
+use crate::settings::Settings;
 use crate::shell::SessionMap;
 use crate::state::State;
 use anyhow::{anyhow, Ok, Result};
@@ -247,6 +249,14 @@ impl State<WsSignaling> {
                     .create_peer_connection(from.ok_or(anyhow!("no from"))?)
                     .await?;
 
+                let keyboards = self.keyboards.clone();
+                let pc = peer_connection.clone();
+                let mut cleanup_rx = done_rx.resubscribe();
+                tokio::spawn(async move {
+                    let _ = cleanup_rx.recv().await;
+                    forget_session(&keyboards, &pc);
+                });
+
                 // Register data channel creation handling
                 let self_ref = self.clone();
                 let pc = peer_connection.clone();
@@ -315,7 +325,12 @@ impl State<WsSignaling> {
         Ok(())
     }
 
-    pub async fn new(my_name: String, peer_type: String, url: String) -> Result<Self> {
+    pub async fn new(
+        my_name: String,
+        peer_type: String,
+        url: String,
+        settings: Settings,
+    ) -> Result<Self> {
         let signaling = Arc::new(WsSignaling::new(url.as_str()).await?);
 
         // Connect to the signaling server
@@ -377,6 +392,9 @@ impl State<WsSignaling> {
             signaling,
             peer_map,
             display_manager,
+            keyboards: Default::default(),
+            videos: Default::default(),
+            settings: Arc::new(settings),
         })
     }
 
@@ -420,8 +438,13 @@ impl State<WsSignaling> {
     }
 }
 
-pub async fn connect(my_name: String, peer_type: String, url: String) -> Result<()> {
-    let state = Arc::new(State::new(my_name, peer_type, url).await?);
+pub async fn connect(
+    my_name: String,
+    peer_type: String,
+    url: String,
+    settings: Settings,
+) -> Result<()> {
+    let state = Arc::new(State::new(my_name, peer_type, url, settings).await?);
     state.signal_loop().await;
 
     Result::Ok(())
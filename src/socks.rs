@@ -0,0 +1,135 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use anyhow::{anyhow, Result};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::policy::Target;
+
+const SOCKS_VERSION: u8 = 5;
+const NO_AUTH: u8 = 0;
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+const CMD_CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+const REPLY_SUCCEEDED: u8 = 0;
+const REPLY_COMMAND_NOT_SUPPORTED: u8 = 7;
+const REPLY_ADDRESS_NOT_SUPPORTED: u8 = 8;
+
+const MAX_HTTP_HEADER: usize = 8 * 1024;
+
+// Reads a SOCKS5 or HTTP CONNECT request and answers it, the stream is
+// left at the start of the tunneled bytes. The reply is sent before the
+// server dials, a refused target shows up as an immediately closed tunnel
+pub async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S) -> Result<Target> {
+    let first = stream.read_u8().await?;
+    if first == SOCKS_VERSION {
+        socks5(stream).await
+    } else {
+        http_connect(stream, first).await
+    }
+}
+
+async fn socks5<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S) -> Result<Target> {
+    let n_methods = stream.read_u8().await?;
+    let mut methods = vec![0u8; n_methods as usize];
+    stream.read_exact(&mut methods).await?;
+    if !methods.contains(&NO_AUTH) {
+        stream
+            .write_all(&[SOCKS_VERSION, NO_ACCEPTABLE_METHODS])
+            .await?;
+        return Err(anyhow!("SOCKS client requires authentication"));
+    }
+    stream.write_all(&[SOCKS_VERSION, NO_AUTH]).await?;
+
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header).await?;
+    let [version, cmd, _, atyp] = header;
+    if version != SOCKS_VERSION {
+        return Err(anyhow!("Unsupported SOCKS version {}", version));
+    }
+
+    let host = match atyp {
+        ATYP_IPV4 => {
+            let mut ip = [0u8; 4];
+            stream.read_exact(&mut ip).await?;
+            Ipv4Addr::from(ip).to_string()
+        }
+        ATYP_IPV6 => {
+            let mut ip = [0u8; 16];
+            stream.read_exact(&mut ip).await?;
+            Ipv6Addr::from(ip).to_string()
+        }
+        ATYP_DOMAIN => {
+            let len = stream.read_u8().await?;
+            let mut domain = vec![0u8; len as usize];
+            stream.read_exact(&mut domain).await?;
+            String::from_utf8(domain)?
+        }
+        _ => {
+            socks5_reply(stream, REPLY_ADDRESS_NOT_SUPPORTED).await?;
+            return Err(anyhow!("Unsupported SOCKS address type {}", atyp));
+        }
+    };
+    let port = stream.read_u16().await?;
+
+    if cmd != CMD_CONNECT {
+        socks5_reply(stream, REPLY_COMMAND_NOT_SUPPORTED).await?;
+        return Err(anyhow!("Unsupported SOCKS command {}", cmd));
+    }
+    socks5_reply(stream, REPLY_SUCCEEDED).await?;
+
+    Ok(Target { host, port })
+}
+
+// The bound address is not known on this side, 0.0.0.0:0 is what most proxies send
+async fn socks5_reply<S: AsyncWrite + Unpin>(stream: &mut S, reply: u8) -> Result<()> {
+    stream
+        .write_all(&[SOCKS_VERSION, reply, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+        .await?;
+    Ok(())
+}
+
+async fn http_connect<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    first: u8,
+) -> Result<Target> {
+    // Byte by byte so nothing after the header is swallowed
+    let mut header = vec![first];
+    while !header.ends_with(b"\r\n\r\n") {
+        if header.len() >= MAX_HTTP_HEADER {
+            return Err(anyhow!("HTTP proxy request header too large"));
+        }
+        header.push(stream.read_u8().await?);
+    }
+
+    let header = String::from_utf8_lossy(&header);
+    let request_line = header.lines().next().unwrap_or_default();
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(authority)) = (parts.next(), parts.next()) else {
+        stream
+            .write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n")
+            .await?;
+        return Err(anyhow!("Invalid HTTP proxy request {}", request_line));
+    };
+    if !method.eq_ignore_ascii_case("CONNECT") {
+        stream
+            .write_all(b"HTTP/1.1 405 Method Not Allowed\r\nAllow: CONNECT\r\n\r\n")
+            .await?;
+        return Err(anyhow!("Only CONNECT is supported, got {}", method));
+    }
+    let target = match authority.parse::<Target>() {
+        Ok(target) => target,
+        Err(e) => {
+            stream
+                .write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n")
+                .await?;
+            return Err(e);
+        }
+    };
+
+    stream
+        .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+        .await?;
+    Ok(target)
+}
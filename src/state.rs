@@ -3,7 +3,10 @@ use std::sync::Arc;
 use virtual_display::VirtualDisplayManager;
 use webrtc::{api::API, peer_connection::configuration::RTCConfiguration};
 
-use crate::{peer::PeerMap, shell::SessionMap, signal::Signaling};
+use crate::{
+    input::KeyboardMap, peer::PeerMap, recording::VideoMap, settings::Settings, shell::SessionMap,
+    signal::Signaling,
+};
 
 pub struct State<S: Signaling> {
     pub api: API,
@@ -13,4 +16,7 @@ pub struct State<S: Signaling> {
     pub signaling: Arc<S>,
     pub peer_map: PeerMap,
     pub display_manager: Arc<VirtualDisplayManager>,
+    pub keyboards: KeyboardMap,
+    pub videos: VideoMap,
+    pub settings: Arc<Settings>,
 }
@@ -0,0 +1,871 @@
+// One way directory sync, rsync style: the server sends block signatures of
+// the files it has, the client answers with which blocks to reuse and the
+// bytes in between. Frames are length prefixed json so signatures and deltas
+// of big files aren't bound by the channel's message size
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{broadcast, mpsc};
+use webrtc::peer_connection::RTCPeerConnection;
+
+use crate::compression::Compression;
+use crate::file::FileChannel;
+use crate::policy::FilePolicy;
+use crate::port::{is_eof, MAX_CHUNK};
+
+const MAX_FRAME: usize = 64 * 1024 * 1024;
+const MIN_BLOCK: usize = 700;
+const MAX_BLOCK: usize = 128 * 1024;
+// Literal bytes per Ops frame before it's sent
+const OPS_BATCH: usize = 1024 * 1024;
+// Bytes of the block sha256 kept in a signature
+const STRONG_LEN: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SyncKind {
+    File,
+    Dir,
+}
+
+// `path` is relative to the synced root with `/` separators
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncEntry {
+    pub path: String,
+    pub kind: SyncKind,
+    pub size: u64,
+    pub mtime: u64,
+    pub mode: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockSig {
+    pub weak: u32,
+    pub strong: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum DeltaOp {
+    // `count` blocks of the server's copy starting at `index`
+    Copy { index: usize, count: usize },
+    Literal { data: String },
+}
+
+//   client: Start, Tree
+//   server: Plan, then Signature for every file in the plan's update list
+//   client: Delta, Ops..., DeltaDone for every Signature
+//   server: Finished
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum SyncMsg {
+    Start {
+        root: String,
+        delete: bool,
+        dry_run: bool,
+    },
+    Tree {
+        entries: Vec<SyncEntry>,
+    },
+    Plan {
+        update: Vec<String>,
+        delete: Vec<String>,
+    },
+    Signature {
+        path: String,
+        block_size: usize,
+        blocks: Vec<BlockSig>,
+    },
+    Delta {
+        path: String,
+    },
+    Ops {
+        ops: Vec<DeltaOp>,
+    },
+    DeltaDone {
+        sha256: String,
+    },
+    Finished,
+    Error {
+        message: String,
+    },
+}
+
+async fn send_msg(tx: &mpsc::Sender<Bytes>, msg: &SyncMsg) -> Result<()> {
+    let json = serde_json::to_vec(msg)?;
+    let mut frame = BytesMut::with_capacity(4 + json.len());
+    frame.put_u32(json.len() as u32);
+    frame.put_slice(&json);
+    let mut frame = frame.freeze();
+    while !frame.is_empty() {
+        tx.send(frame.split_to(frame.len().min(MAX_CHUNK))).await?;
+    }
+    Ok(())
+}
+
+// Reassembles frames from channel messages
+struct Frames {
+    rx: mpsc::Receiver<Bytes>,
+    buffer: BytesMut,
+}
+
+impl Frames {
+    fn new(rx: mpsc::Receiver<Bytes>) -> Self {
+        Self {
+            rx,
+            buffer: BytesMut::new(),
+        }
+    }
+
+    async fn recv(&mut self) -> Result<SyncMsg> {
+        loop {
+            if self.buffer.len() >= 4 {
+                let len = u32::from_be_bytes(self.buffer[..4].try_into().unwrap()) as usize;
+                if len > MAX_FRAME {
+                    return Err(anyhow!("Sync frame of {} bytes is too large", len));
+                }
+                if self.buffer.len() >= 4 + len {
+                    self.buffer.advance(4);
+                    let frame = self.buffer.split_to(len);
+                    return match serde_json::from_slice(&frame)? {
+                        SyncMsg::Error { message } => Err(anyhow!(message)),
+                        msg => Ok(msg),
+                    };
+                }
+            }
+            let data = self.rx.recv().await.ok_or(anyhow!("Sync channel closed"))?;
+            if is_eof(&data) {
+                return Err(anyhow!("Sync channel closed"));
+            }
+            self.buffer.extend_from_slice(&data);
+        }
+    }
+}
+
+fn unexpected(msg: SyncMsg) -> anyhow::Error {
+    anyhow!("Unexpected sync message {:?}", msg)
+}
+
+// rsync's rolling checksum, the window slides one byte at a time
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    fn new(block: &[u8]) -> Self {
+        let mut rolling = Self {
+            a: 0,
+            b: 0,
+            len: block.len() as u32,
+        };
+        for (i, &byte) in block.iter().enumerate() {
+            rolling.a = rolling.a.wrapping_add(byte as u32);
+            rolling.b = rolling
+                .b
+                .wrapping_add((block.len() - i) as u32 * byte as u32);
+        }
+        rolling
+    }
+
+    fn roll(&mut self, out: u8, next: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(next as u32);
+        self.b = self
+            .b
+            .wrapping_sub(self.len.wrapping_mul(out as u32))
+            .wrapping_add(self.a);
+    }
+
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+fn strong(block: &[u8]) -> String {
+    let hash = Sha256::digest(block);
+    hash[..STRONG_LEN]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+// Roughly the square root of the size like rsync, both sides derive it
+// from the server's copy
+fn block_size(len: usize) -> usize {
+    ((len as f64).sqrt() as usize).clamp(MIN_BLOCK, MAX_BLOCK)
+}
+
+// Only whole blocks, a short tail is always sent as literal bytes
+fn signature(basis: &[u8]) -> Vec<BlockSig> {
+    basis
+        .chunks_exact(block_size(basis.len()))
+        .map(|block| BlockSig {
+            weak: Rolling::new(block).digest(),
+            strong: strong(block),
+        })
+        .collect()
+}
+
+// The bytes `count` blocks from `index` cover, None when they aren't all in
+// the server's copy. Both come from the client, so nothing may overflow
+fn blocks(basis: &[u8], block_size: usize, index: usize, count: usize) -> Option<&[u8]> {
+    let from = index.checked_mul(block_size)?;
+    let to = index.checked_add(count)?.checked_mul(block_size)?;
+    basis.get(from..to)
+}
+
+// Walks `data` against the server's blocks, literal runs are handed to
+// `emit` at most `OPS_BATCH` bytes at a time along with their raw length
+fn delta(
+    data: &[u8],
+    block_size: usize,
+    blocks: &[BlockSig],
+    mut emit: impl FnMut(DeltaOp, usize),
+) {
+    let mut weak: HashMap<u32, Vec<usize>> = HashMap::new();
+    for (index, block) in blocks.iter().enumerate() {
+        weak.entry(block.weak).or_default().push(index);
+    }
+    let literal = |from: usize, to: usize, emit: &mut dyn FnMut(DeltaOp, usize)| {
+        for chunk in data[from..to].chunks(OPS_BATCH) {
+            let op = DeltaOp::Literal {
+                data: BASE64.encode(chunk),
+            };
+            emit(op, chunk.len());
+        }
+    };
+
+    let mut start = 0;
+    let mut i = 0;
+    let mut rolling =
+        (!blocks.is_empty() && data.len() >= block_size).then(|| Rolling::new(&data[..block_size]));
+    let mut pending: Option<(usize, usize)> = None;
+    while let Some(window) = rolling.as_mut() {
+        let found = weak.get(&window.digest()).and_then(|candidates| {
+            let strong = strong(&data[i..i + block_size]);
+            candidates
+                .iter()
+                .find(|&&index| blocks[index].strong == strong)
+                .copied()
+        });
+        if let Some(index) = found {
+            if start < i {
+                if let Some((index, count)) = pending.take() {
+                    emit(DeltaOp::Copy { index, count }, 0);
+                }
+                literal(start, i, &mut emit);
+            }
+            pending = match pending {
+                Some((first, count)) if first + count == index => Some((first, count + 1)),
+                Some((first, count)) => {
+                    let op = DeltaOp::Copy {
+                        index: first,
+                        count,
+                    };
+                    emit(op, 0);
+                    Some((index, 1))
+                }
+                None => Some((index, 1)),
+            };
+            i += block_size;
+            start = i;
+            rolling =
+                (i + block_size <= data.len()).then(|| Rolling::new(&data[i..i + block_size]));
+        } else if i + block_size < data.len() {
+            window.roll(data[i], data[i + block_size]);
+            i += 1;
+        } else {
+            break;
+        }
+    }
+    if let Some((index, count)) = pending {
+        emit(DeltaOp::Copy { index, count }, 0);
+    }
+    if start < data.len() {
+        literal(start, data.len(), &mut emit);
+    }
+}
+
+fn mtime(meta: &fs::Metadata) -> u64 {
+    meta.modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |since| since.as_secs())
+}
+
+#[cfg(unix)]
+fn mode(meta: &fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(meta.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn mode(_: &fs::Metadata) -> Option<u32> {
+    None
+}
+
+// Every file and directory below `root` keyed by relative path, parents sort
+// before their children. Symlinks and special files are skipped
+fn scan(root: &Path) -> Result<BTreeMap<String, SyncEntry>> {
+    let mut entries = BTreeMap::new();
+    let mut dirs = vec![(root.to_path_buf(), String::new())];
+    while let Some((dir, prefix)) = dirs.pop() {
+        for dir_entry in fs::read_dir(&dir)? {
+            let dir_entry = dir_entry?;
+            let meta = fs::symlink_metadata(dir_entry.path())?;
+            let name = dir_entry.file_name().to_string_lossy().into_owned();
+            let path = format!("{}{}", prefix, name);
+            let kind = if meta.is_dir() {
+                dirs.push((dir_entry.path(), format!("{}/", path)));
+                SyncKind::Dir
+            } else if meta.is_file() {
+                SyncKind::File
+            } else {
+                log::info!("Skipping {}, not a file or directory", path);
+                continue;
+            };
+            entries.insert(
+                path.clone(),
+                SyncEntry {
+                    path,
+                    kind,
+                    size: meta.len(),
+                    mtime: mtime(&meta),
+                    mode: mode(&meta),
+                },
+            );
+        }
+    }
+    Ok(entries)
+}
+
+// Client supplied paths may only name something below the root
+fn relative(path: &str) -> Result<&Path> {
+    let relative = Path::new(path);
+    if path.is_empty()
+        || !relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+    {
+        return Err(anyhow!("Invalid sync path {}", path));
+    }
+    Ok(relative)
+}
+
+fn remove(path: &Path) -> Result<()> {
+    if fs::symlink_metadata(path)?.is_dir() {
+        fs::remove_dir_all(path)?;
+    } else {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+// What the server works out from both trees
+struct Plan {
+    update: Vec<String>,
+    dirs: Vec<String>,
+    // Server entries in the way of a client entry of another kind, removed even without delete
+    replace: Vec<String>,
+    delete: Vec<String>,
+}
+
+fn plan(
+    source: &BTreeMap<String, SyncEntry>,
+    dest: &BTreeMap<String, SyncEntry>,
+    delete: bool,
+) -> Plan {
+    let mut plan = Plan {
+        update: vec![],
+        dirs: vec![],
+        replace: vec![],
+        delete: vec![],
+    };
+    for (path, entry) in source {
+        let existing = dest.get(path);
+        if existing.is_some_and(|existing| existing.kind != entry.kind) {
+            plan.replace.push(path.clone());
+        }
+        let same_kind = existing.filter(|existing| existing.kind == entry.kind);
+        match entry.kind {
+            SyncKind::Dir if same_kind.is_none() => plan.dirs.push(path.clone()),
+            SyncKind::File
+                if !same_kind.is_some_and(|existing| {
+                    existing.size == entry.size && existing.mtime == entry.mtime
+                }) =>
+            {
+                plan.update.push(path.clone())
+            }
+            _ => (),
+        }
+    }
+    if delete {
+        // Children of a deleted or replaced directory go with it
+        for path in dest.keys() {
+            let parent_kept = path.rsplit_once('/').map_or(true, |(parent, _)| {
+                source
+                    .get(parent)
+                    .is_some_and(|entry| entry.kind == SyncKind::Dir)
+            });
+            if !source.contains_key(path) && parent_kept {
+                plan.delete.push(path.clone());
+            }
+        }
+    }
+    plan
+}
+
+// Writes one file from the server's old copy and the client's delta, it
+// replaces the old copy only once the hash matched
+async fn apply_delta(
+    frames: &mut Frames,
+    target: &Path,
+    entry: &SyncEntry,
+    policy: &FilePolicy,
+) -> Result<()> {
+    let target = policy.check_entry(target)?;
+    let basis = match fs::symlink_metadata(&target) {
+        Ok(meta) if meta.is_file() => tokio::fs::read(&target).await?,
+        _ => vec![],
+    };
+    let block_size = block_size(basis.len());
+    let name = target
+        .file_name()
+        .ok_or(anyhow!("{} has no file name", target.display()))?;
+    // A symlink planted at the temp name would redirect the write
+    let temp =
+        policy.check_entry(&target.with_file_name(format!(".{}.sync", name.to_string_lossy())))?;
+    if fs::symlink_metadata(&temp).is_ok_and(|meta| meta.is_symlink()) {
+        return Err(anyhow!("{} is a symlink", temp.display()));
+    }
+    let mut options = tokio::fs::OpenOptions::new();
+    options.create(true).truncate(true).write(true);
+    #[cfg(unix)]
+    options.custom_flags(libc::O_NOFOLLOW);
+    let mut file = options.open(&temp).await?;
+    let mut hasher = Sha256::new();
+    let expected = loop {
+        match frames.recv().await? {
+            SyncMsg::Ops { ops } => {
+                for op in ops {
+                    let data = match op {
+                        DeltaOp::Copy { index, count } => blocks(&basis, block_size, index, count)
+                            .ok_or(anyhow!("Blocks {}+{} out of range", index, count))?
+                            .to_vec(),
+                        DeltaOp::Literal { data } => BASE64.decode(data)?,
+                    };
+                    hasher.update(&data);
+                    file.write_all(&data).await?;
+                }
+            }
+            SyncMsg::DeltaDone { sha256 } => break sha256,
+            msg => return Err(unexpected(msg)),
+        }
+    };
+    file.flush().await?;
+
+    let sha256 = format!("{:x}", hasher.finalize());
+    if sha256 != expected {
+        fs::remove_file(&temp)?;
+        return Err(anyhow!("SHA-256 mismatch on {}", entry.path));
+    }
+    let file = file.into_std().await;
+    file.set_modified(UNIX_EPOCH + Duration::from_secs(entry.mtime))?;
+    // Permission bits only, no setuid, setgid or sticky from the client
+    #[cfg(unix)]
+    if let Some(mode) = entry.mode {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(mode & 0o777))?;
+    }
+    drop(file);
+    fs::rename(&temp, &target)?;
+    Ok(())
+}
+
+// Receiving end of `client sync`, the server's directory is made to look
+// like the client's
+pub async fn handle_sync(
+    tx: mpsc::Sender<Bytes>,   // From server to the client
+    rx: mpsc::Receiver<Bytes>, // From clients to server
+    mut done_rx: broadcast::Receiver<()>,
+    policy: FilePolicy,
+) {
+    let mut frames = Frames::new(rx);
+    tokio::select! {
+        res = serve_sync(&tx, &mut frames, &policy) => {
+            if let Err(e) = res {
+                log::error!("Sync failed: {}", e);
+                let message = e.to_string();
+                let _ = send_msg(&tx, &SyncMsg::Error { message }).await;
+            }
+        }
+        _ = done_rx.recv() => (),
+    }
+
+    log::info!("handle_sync exiting");
+}
+
+async fn serve_sync(
+    tx: &mpsc::Sender<Bytes>,
+    frames: &mut Frames,
+    policy: &FilePolicy,
+) -> Result<()> {
+    let (root, delete, dry_run) = match frames.recv().await? {
+        SyncMsg::Start {
+            root,
+            delete,
+            dry_run,
+        } => (root, delete, dry_run),
+        msg => return Err(unexpected(msg)),
+    };
+    let source: BTreeMap<String, SyncEntry> = match frames.recv().await? {
+        SyncMsg::Tree { entries } => entries
+            .into_iter()
+            .map(|entry| Ok((relative(&entry.path)?.to_string_lossy().into_owned(), entry)))
+            .collect::<Result<_>>()?,
+        msg => return Err(unexpected(msg)),
+    };
+
+    let root = policy.check(Path::new(&root))?;
+    let dest = if root.is_dir() {
+        scan(&root)?
+    } else {
+        BTreeMap::new()
+    };
+    let plan = plan(&source, &dest, delete);
+    log::info!(
+        "Sync to {}: {} to update, {} to delete",
+        root.display(),
+        plan.update.len(),
+        plan.delete.len()
+    );
+    send_msg(
+        tx,
+        &SyncMsg::Plan {
+            update: plan.update.clone(),
+            delete: plan.delete.clone(),
+        },
+    )
+    .await?;
+    if dry_run {
+        return send_msg(tx, &SyncMsg::Finished).await;
+    }
+
+    let target = |path: &str| -> Result<PathBuf> { Ok(root.join(relative(path)?)) };
+    for path in &plan.replace {
+        remove(&policy.check_entry(&target(path)?)?)?;
+    }
+    fs::create_dir_all(&root)?;
+    for path in &plan.dirs {
+        fs::create_dir(policy.check_entry(&target(path)?)?)?;
+    }
+
+    // Signatures go out while deltas come in, neither side waits on a full channel
+    let signatures = async {
+        for path in &plan.update {
+            let path_buf = target(path)?;
+            let basis = match fs::symlink_metadata(&path_buf) {
+                Ok(meta) if meta.is_file() => tokio::fs::read(&path_buf).await?,
+                _ => vec![],
+            };
+            let msg = SyncMsg::Signature {
+                path: path.clone(),
+                block_size: block_size(basis.len()),
+                blocks: signature(&basis),
+            };
+            send_msg(tx, &msg).await?;
+        }
+        Result::<()>::Ok(())
+    };
+    let deltas = async {
+        for path in &plan.update {
+            match frames.recv().await? {
+                SyncMsg::Delta { path: delta_path } if &delta_path == path => (),
+                msg => return Err(unexpected(msg)),
+            }
+            apply_delta(frames, &target(path)?, &source[path], policy).await?;
+        }
+        Result::<()>::Ok(())
+    };
+    tokio::try_join!(signatures, deltas)?;
+
+    for path in &plan.delete {
+        remove(&policy.check_entry(&target(path)?)?)?;
+    }
+    send_msg(tx, &SyncMsg::Finished).await
+}
+
+// Makes `remote` on the server match the local directory `local`
+pub async fn sync(
+    peer_connection: &Arc<RTCPeerConnection>,
+    local: &Path,
+    remote: &str,
+    delete: bool,
+    dry_run: bool,
+    compression: Option<Compression>,
+) -> Result<()> {
+    let source = scan(local)?;
+    let FileChannel { d, tx, rx } = FileChannel::open(peer_connection, "sync", compression).await?;
+    let mut frames = Frames::new(rx);
+
+    let start = SyncMsg::Start {
+        root: remote.into(),
+        delete,
+        dry_run,
+    };
+    send_msg(&tx, &start).await?;
+    let entries = source.values().cloned().collect();
+    send_msg(&tx, &SyncMsg::Tree { entries }).await?;
+
+    let (update, deleted) = match frames.recv().await? {
+        SyncMsg::Plan { update, delete } => (update, delete),
+        msg => return Err(unexpected(msg)),
+    };
+    for path in &update {
+        println!("update {}", path);
+    }
+    for path in &deleted {
+        println!("delete {}", path);
+    }
+
+    let mut total = 0;
+    let mut literal = 0;
+    if !dry_run {
+        for _ in &update {
+            let (path, block_size, blocks) = match frames.recv().await? {
+                SyncMsg::Signature {
+                    path,
+                    block_size,
+                    blocks,
+                } => (path, block_size, blocks),
+                msg => return Err(unexpected(msg)),
+            };
+            let data = tokio::fs::read(local.join(relative(&path)?)).await?;
+            total += data.len();
+
+            send_msg(&tx, &SyncMsg::Delta { path }).await?;
+            let mut ops = vec![];
+            let mut batch = 0;
+            let mut batches = vec![];
+            delta(&data, block_size, &blocks, |op, len| {
+                batch += len;
+                ops.push(op);
+                if batch >= OPS_BATCH {
+                    literal += batch;
+                    batch = 0;
+                    batches.push(std::mem::take(&mut ops));
+                }
+            });
+            literal += batch;
+            batches.push(ops);
+            for ops in batches {
+                send_msg(&tx, &SyncMsg::Ops { ops }).await?;
+            }
+            let sha256 = format!("{:x}", Sha256::digest(&data));
+            send_msg(&tx, &SyncMsg::DeltaDone { sha256 }).await?;
+        }
+    }
+
+    match frames.recv().await? {
+        SyncMsg::Finished => (),
+        msg => return Err(unexpected(msg)),
+    }
+    d.close().await?;
+
+    if dry_run {
+        println!("dry run, nothing changed");
+    } else {
+        println!(
+            "{} updated, {} deleted, {} of {} bytes sent as literal data",
+            update.len(),
+            deleted.len(),
+            literal,
+            total
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Blocks that differ from each other, so every one matches only itself
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state = 0x2545_f491_u32;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (state >> 16) as u8
+            })
+            .collect()
+    }
+
+    // Runs the client side delta and rebuilds the file like the server does
+    fn round_trip(basis: &[u8], data: &[u8]) -> Vec<DeltaOp> {
+        let block_size = block_size(basis.len());
+        let mut ops = vec![];
+        delta(data, block_size, &signature(basis), |op, _| ops.push(op));
+        let mut rebuilt = vec![];
+        for op in &ops {
+            match op {
+                DeltaOp::Copy { index, count } => {
+                    rebuilt.extend_from_slice(blocks(basis, block_size, *index, *count).unwrap())
+                }
+                DeltaOp::Literal { data } => rebuilt.extend(BASE64.decode(data).unwrap()),
+            }
+        }
+        assert_eq!(rebuilt, data);
+        ops
+    }
+
+    fn copy(op: &DeltaOp) -> Option<(usize, usize)> {
+        match op {
+            DeltaOp::Copy { index, count } => Some((*index, *count)),
+            DeltaOp::Literal { .. } => None,
+        }
+    }
+
+    fn literal_len(op: &DeltaOp) -> Option<usize> {
+        match op {
+            DeltaOp::Literal { data } => Some(BASE64.decode(data).unwrap().len()),
+            DeltaOp::Copy { .. } => None,
+        }
+    }
+
+    fn entry(path: &str, kind: SyncKind, mtime: u64) -> (String, SyncEntry) {
+        let entry = SyncEntry {
+            path: path.into(),
+            kind,
+            size: 1,
+            mtime,
+            mode: None,
+        };
+        (path.into(), entry)
+    }
+
+    #[test]
+    fn rolled_digest_matches_a_fresh_one() {
+        let data = noise(2 * MIN_BLOCK);
+        let mut rolling = Rolling::new(&data[..MIN_BLOCK]);
+        for window in data.windows(MIN_BLOCK + 1) {
+            rolling.roll(window[0], window[MIN_BLOCK]);
+            assert_eq!(rolling.digest(), Rolling::new(&window[1..]).digest());
+        }
+    }
+
+    #[test]
+    fn unchanged_file_is_one_copy() {
+        let basis = noise(10 * MIN_BLOCK);
+        let ops = round_trip(&basis, &basis);
+        assert_eq!(ops.len(), 1);
+        assert_eq!(copy(&ops[0]), Some((0, 10)));
+    }
+
+    #[test]
+    fn inserted_byte_is_found_by_rolling() {
+        let basis = noise(10 * MIN_BLOCK);
+        let mut data = vec![0x42];
+        data.extend_from_slice(&basis);
+        let ops = round_trip(&basis, &data);
+        assert_eq!(ops.len(), 2);
+        assert_eq!(literal_len(&ops[0]), Some(1));
+        assert_eq!(copy(&ops[1]), Some((0, 10)));
+    }
+
+    #[test]
+    fn changed_block_splits_the_copy_run() {
+        let basis = noise(10 * MIN_BLOCK);
+        let mut data = basis.clone();
+        data[5 * MIN_BLOCK + 3] ^= 0xff;
+        let ops = round_trip(&basis, &data);
+        assert_eq!(ops.len(), 3);
+        assert_eq!(copy(&ops[0]), Some((0, 5)));
+        assert_eq!(literal_len(&ops[1]), Some(MIN_BLOCK));
+        assert_eq!(copy(&ops[2]), Some((6, 4)));
+    }
+
+    #[test]
+    fn only_adjacent_blocks_coalesce() {
+        let basis = noise(10 * MIN_BLOCK);
+        let mut data = basis[3 * MIN_BLOCK..5 * MIN_BLOCK].to_vec();
+        data.extend_from_slice(&basis[MIN_BLOCK..2 * MIN_BLOCK]);
+        let ops = round_trip(&basis, &data);
+        let copies: Vec<_> = ops.iter().filter_map(copy).collect();
+        assert_eq!(copies, vec![(3, 2), (1, 1)]);
+        assert_eq!(ops.len(), 2);
+    }
+
+    #[test]
+    fn short_tail_and_empty_basis_go_as_literals() {
+        let basis = noise(10 * MIN_BLOCK + 300);
+        let ops = round_trip(&basis, &basis);
+        assert_eq!(copy(&ops[0]), Some((0, 10)));
+        assert_eq!(literal_len(&ops[1]), Some(300));
+
+        let data = noise(3 * MIN_BLOCK);
+        let ops = round_trip(&[], &data);
+        assert!(ops.iter().all(|op| copy(op).is_none()));
+    }
+
+    #[test]
+    fn out_of_range_blocks_are_rejected() {
+        let basis = noise(10 * MIN_BLOCK);
+        assert!(blocks(&basis, MIN_BLOCK, 9, 1).is_some());
+        assert!(blocks(&basis, MIN_BLOCK, 9, 2).is_none());
+        assert!(blocks(&basis, MIN_BLOCK, usize::MAX, 2).is_none());
+        assert!(blocks(&basis, MIN_BLOCK, usize::MAX / MIN_BLOCK, 1).is_none());
+    }
+
+    #[test]
+    fn kind_change_is_replaced_without_delete() {
+        let source = BTreeMap::from([
+            entry("a", SyncKind::Dir, 1),
+            entry("a/x", SyncKind::File, 1),
+            entry("b", SyncKind::File, 1),
+        ]);
+        let dest = BTreeMap::from([entry("a", SyncKind::File, 1), entry("b", SyncKind::Dir, 1)]);
+        let plan = plan(&source, &dest, false);
+        assert_eq!(plan.replace, vec!["a", "b"]);
+        assert_eq!(plan.dirs, vec!["a"]);
+        assert_eq!(plan.update, vec!["a/x", "b"]);
+        assert!(plan.delete.is_empty());
+    }
+
+    #[test]
+    fn unchanged_files_are_skipped() {
+        let source = BTreeMap::from([
+            entry("same", SyncKind::File, 1),
+            entry("newer", SyncKind::File, 2),
+        ]);
+        let dest = BTreeMap::from([
+            entry("same", SyncKind::File, 1),
+            entry("newer", SyncKind::File, 1),
+        ]);
+        let plan = plan(&source, &dest, false);
+        assert_eq!(plan.update, vec!["newer"]);
+        assert!(plan.replace.is_empty());
+    }
+
+    #[test]
+    fn delete_takes_directories_with_their_children() {
+        let source = BTreeMap::from([entry("keep", SyncKind::Dir, 1)]);
+        let dest = BTreeMap::from([
+            entry("keep", SyncKind::Dir, 1),
+            entry("keep/old", SyncKind::File, 1),
+            entry("gone", SyncKind::Dir, 1),
+            entry("gone/child", SyncKind::File, 1),
+        ]);
+        assert!(plan(&source, &dest, false).delete.is_empty());
+        let plan = plan(&source, &dest, true);
+        assert_eq!(plan.delete, vec!["gone", "keep/old"]);
+    }
+}
@@ -0,0 +1,87 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use bytes::Bytes;
+use tokio::net::UdpSocket;
+use tokio::sync::{broadcast, mpsc};
+use webrtc::data_channel::data_channel_init::RTCDataChannelInit;
+
+use crate::policy::{ForwardPolicy, Protocol, Target};
+
+pub const MAX_DATAGRAM: usize = 64 * 1024;
+
+// One datagram per message, a lost or late datagram is dropped instead of
+// holding back everything behind it
+pub fn channel_init() -> RTCDataChannelInit {
+    RTCDataChannelInit {
+        ordered: Some(false),
+        max_retransmits: Some(0),
+        ..Default::default()
+    }
+}
+
+// One udp channel is one flow, it ends after `idle_timeout` without traffic
+pub async fn handle_udp(
+    tx: mpsc::Sender<Bytes>,       // From server to the client
+    mut rx: mpsc::Receiver<Bytes>, // From clients to server
+    mut done_rx: broadcast::Receiver<()>,
+    target: Target,
+    policy: ForwardPolicy,
+    idle_timeout: Duration,
+) {
+    let addrs = match policy.resolve(&target, Protocol::Udp).await {
+        Ok(addrs) => addrs,
+        Err(e) => {
+            log::error!("Refusing udp channel: {}", e);
+            return;
+        }
+    };
+    let addr = addrs[0];
+    let bind: SocketAddr = if addr.is_ipv4() {
+        "0.0.0.0:0".parse().unwrap()
+    } else {
+        "[::]:0".parse().unwrap()
+    };
+
+    let socket = match UdpSocket::bind(bind).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            log::error!("UDP bind error: {}", e);
+            return;
+        }
+    };
+    if let Err(e) = socket.connect(addr).await {
+        log::error!("UDP connect error: {}", e);
+        return;
+    }
+    log::info!("Relaying udp to {}", target);
+
+    let mut buffer = vec![0u8; MAX_DATAGRAM];
+    loop {
+        tokio::select! {
+            msg = rx.recv() => {
+                let Some(msg) = msg else {
+                    break;
+                };
+                if let Err(e) = socket.send(&msg).await {
+                    log::error!("UDP send error: {}", e);
+                }
+            }
+            res = socket.recv(&mut buffer) => match res {
+                Ok(n) => {
+                    // A full queue just loses the datagram
+                    let _ = tx.try_send(Bytes::copy_from_slice(&buffer[..n]));
+                }
+                // Icmp errors of earlier datagrams show up here, the flow goes on
+                Err(e) => log::debug!("UDP recv error: {}", e),
+            },
+            _ = tokio::time::sleep(idle_timeout) => {
+                log::info!("UDP flow to {} idle", target);
+                break;
+            }
+            _ = done_rx.recv() => break,
+        }
+    }
+
+    log::info!("handle_udp exiting");
+}
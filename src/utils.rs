@@ -1,4 +1,7 @@
-use anyhow::Result;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::de::DeserializeOwned;
 use serde::Serialize;
 
 pub fn to_json<T>(input: T) -> Result<String>
@@ -8,3 +11,18 @@ where
     let res = serde_json::to_string(&input).unwrap();
     Ok(res)
 }
+
+// Shared by the server settings and the client config, no file means defaults
+pub fn load_config<T>(path: Option<&Path>) -> Result<T>
+where
+    T: DeserializeOwned + Default,
+{
+    let Some(path) = path else {
+        return Ok(T::default());
+    };
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read config {}", path.display()))?;
+    let config = serde_json::from_str(&text)
+        .with_context(|| format!("Failed to parse config {}", path.display()))?;
+    Ok(config)
+}
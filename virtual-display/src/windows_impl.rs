@@ -44,6 +44,40 @@ pub enum DisplayCommand {
     List,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DisplayMode {
+    pub width: u32,
+    pub height: u32,
+    pub refresh_rates: Vec<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DisplayInfo {
+    pub id: Id,
+    pub name: Option<String>,
+    pub enabled: bool,
+    pub modes: Vec<DisplayMode>,
+}
+
+impl From<&Monitor> for DisplayInfo {
+    fn from(monitor: &Monitor) -> Self {
+        Self {
+            id: monitor.id,
+            name: monitor.name.clone(),
+            enabled: monitor.enabled,
+            modes: monitor
+                .modes
+                .iter()
+                .map(|mode| DisplayMode {
+                    width: mode.width,
+                    height: mode.height,
+                    refresh_rates: mode.refresh_rates.clone(),
+                })
+                .collect(),
+        }
+    }
+}
+
 impl VirtualDisplayManager {
     pub async fn new() -> Result<Self> {
         let mut client = DriverClient::new().await?;
@@ -58,8 +92,10 @@ impl VirtualDisplayManager {
         })
     }
 
-    pub async fn handle_command(&self, command: DisplayCommand) -> Result<()> {
-        match command {
+    // The displays the command touched as they are afterwards, every display
+    // for List and none for Remove
+    pub async fn handle_command(&self, command: DisplayCommand) -> Result<Vec<DisplayInfo>> {
+        let id = match command {
             DisplayCommand::Create {
                 name,
                 width,
@@ -70,20 +106,29 @@ impl VirtualDisplayManager {
                 let height = height.unwrap_or(1080);
                 let refresh_rate = refresh_rate.unwrap_or(60);
                 self.create_display(name, width, height, refresh_rate)
-                    .await?;
-                Ok(())
+                    .await?
             }
             DisplayCommand::Update {
                 id,
                 width,
                 height,
                 refresh_rate,
-            } => self.update_display(id, width, height, refresh_rate).await,
-            DisplayCommand::Disable { id } => self.set_display_enabled(id, false).await,
-            DisplayCommand::Enable { id } => self.set_display_enabled(id, true).await,
-            DisplayCommand::Remove { id } => self.remove_display(id).await,
-            DisplayCommand::List => self.list_displays().await,
-        }
+            } => self.update_display(id, width, height, refresh_rate).await?,
+            DisplayCommand::Disable { id } => {
+                self.set_display_enabled(id, false).await?;
+                id
+            }
+            DisplayCommand::Enable { id } => {
+                self.set_display_enabled(id, true).await?;
+                id
+            }
+            DisplayCommand::Remove { id } => {
+                self.remove_display(id).await?;
+                return Ok(vec![]);
+            }
+            DisplayCommand::List => return self.list_displays().await,
+        };
+        Ok(vec![self.display(id).await?])
     }
 
     pub async fn set_display_enabled(&self, id: Id, enabled: bool) -> Result<()> {
@@ -148,14 +193,15 @@ impl VirtualDisplayManager {
         Ok(id)
     }
 
-    // updates and activates found display or creates new one
+    // updates and activates found display or creates new one, returns the id
+    // of whichever it was
     pub async fn update_display(
         &self,
         id: Id,
         width: Option<u32>,
         height: Option<u32>,
         refresh_rate: Option<u32>,
-    ) -> Result<()> {
+    ) -> Result<Id> {
         dbg!("huh??");
 
         let res = {
@@ -176,14 +222,17 @@ impl VirtualDisplayManager {
         };
         dbg!("huh2??");
 
-        if let None = res {
-            self.create_display(
-                None,
-                width.unwrap_or(1920),
-                height.unwrap_or(1080),
-                refresh_rate.unwrap_or(60),
-            )
-            .await?;
+        let id = match res {
+            Some(_) => id,
+            None => {
+                self.create_display(
+                    None,
+                    width.unwrap_or(1920),
+                    height.unwrap_or(1080),
+                    refresh_rate.unwrap_or(60),
+                )
+                .await?
+            }
         };
         dbg!("huh3??");
 
@@ -192,37 +241,20 @@ impl VirtualDisplayManager {
         client.notify().await?;
         dbg!("huh4??");
 
-        Ok(())
+        Ok(id)
     }
 
-    async fn list_displays(&self) -> Result<()> {
+    pub async fn list_displays(&self) -> Result<Vec<DisplayInfo>> {
         let client = self.client.lock().await;
-        let monitors = client.monitors();
+        Ok(client.monitors().iter().map(DisplayInfo::from).collect())
+    }
 
-        println!("Managed Displays:");
-        for monitor in monitors {
-            let mode = monitor
-                .modes
-                .first()
-                .map(|m| {
-                    format!(
-                        "{}x{}@{}Hz",
-                        m.width,
-                        m.height,
-                        m.refresh_rates.first().unwrap_or(&0)
-                    )
-                })
-                .unwrap_or_else(|| "No modes".to_string());
-
-            println!(
-                "[{}] {} - {} ({})",
-                if monitor.enabled { "✓" } else { " " },
-                monitor.id,
-                monitor.name.as_deref().unwrap_or("Unnamed"),
-                mode
-            );
-        }
-        Ok(())
+    pub async fn display(&self, id: Id) -> Result<DisplayInfo> {
+        self.list_displays()
+            .await?
+            .into_iter()
+            .find(|display| display.id == id)
+            .ok_or_else(|| anyhow!("Display {} not found", id))
     }
 
     pub async fn exit(&self) -> Result<()> {
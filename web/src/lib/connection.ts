@@ -102,7 +102,7 @@ export class ConnectionManager {
 
   async createControl() {
 
-    const controlChannel = this.createDataChannel({ variant: 'control', session_id: null });
+    const controlChannel = this.createDataChannel({ variant: 'control', session_id: null, compression: null, target: null, bind: null });
     this.controlChannel = controlChannel
 
     controlChannel.onclose = () => this.status.set('Control Channel has closed');
@@ -146,7 +146,7 @@ export class ConnectionManager {
   }
 
   async startWebShell(term: Terminal, session_id: string) {
-    const sendChannel = this.createDataChannel({ variant: 'web_shell', session_id });
+    const sendChannel = this.createDataChannel({ variant: 'web_shell', session_id, compression: null, target: null, bind: null });
     this.sendChannel = sendChannel
 
     // const enc = new TextDecoder("utf-8");